@@ -0,0 +1,177 @@
+use event::{Poll, Token};
+
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// Longest command line we accept from an admin client.
+static MAX_COMMAND_LEN: usize = 512;
+
+/// A local unix socket operators can connect to (with `socat` or `nc -U`) to
+/// run control bot commands without going through IRC. Every line sent is a
+/// command, and gets a single line in reply. Only the user we run as may
+/// connect.
+pub struct AdminSocket {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<AdminClient>,
+    /// Poll token of the listener, then of every client, for the current iteration
+    tokens: Vec<Token>
+}
+
+struct AdminClient {
+    stream: UnixStream,
+    buf: Vec<u8>
+}
+
+impl AdminSocket {
+    /// Listens on `path`, replacing a stale socket left behind by a previous run.
+    pub fn bind(path: &Path) -> Result<AdminSocket> {
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(Error::new(ErrorKind::AlreadyExists,
+                                      "Admin socket path exists and isn't a socket.",
+                                      Some(path.display().to_string())));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::new(ErrorKind::AddrInUse, "Admin socket already in use.",
+                                      Some(path.display().to_string())));
+            }
+            try!(fs::remove_file(path));
+        }
+        let listener = try!(UnixListener::bind(path));
+        try!(listener.set_nonblocking(true));
+        let admin = AdminSocket { path: path.to_path_buf(), listener: listener, clients: Vec::new(),
+                                  tokens: Vec::new() };
+        // Commands are as powerful as the control bot, e.g. REHASH FORCE
+        try!(fs::set_permissions(path, fs::Permissions::from_mode(0o600)));
+        Ok(admin)
+    }
+
+    /// Registers the listener and the clients with `poll`.
+    pub fn register(&mut self, poll: &mut Poll) {
+        self.tokens.clear();
+        self.tokens.push(poll.register(Some(self.listener.as_raw_fd())));
+        for client in self.clients.iter() {
+            self.tokens.push(poll.register(Some(client.stream.as_raw_fd())));
+        }
+    }
+
+    /// Accepts new clients and runs the commands of those that sent something,
+    /// replying with whatever `run` returns.
+    pub fn process<F>(&mut self, poll: &Poll, mut run: F) where F: FnMut(&str) -> String {
+        if self.tokens.is_empty() {
+            return;
+        }
+
+        let mut closed = Vec::new();
+        for (i, client) in self.clients.iter_mut().enumerate() {
+            match self.tokens.get(i + 1) {
+                Some(&token) if poll.is_ready(token) => (),
+                _ => continue
+            }
+            if client.read_commands(&mut run).is_err() {
+                closed.push(i);
+            }
+        }
+        for &i in closed.iter().rev() {
+            self.clients.remove(i);
+        }
+
+        if poll.is_ready(self.tokens[0]) {
+            loop {
+                match self.listener.accept() {
+                    Ok((stream, _)) => {
+                        if stream.set_nonblocking(true).is_ok() {
+                            self.clients.push(AdminClient { stream: stream, buf: Vec::new() });
+                        }
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        warn!(Core, "Failed to accept admin client: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+        // Clients accepted or closed above weren't registered with this poll
+        self.tokens.clear();
+    }
+}
+
+impl Drop for AdminSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl AdminClient {
+    /// Reads what the client sent and runs every complete line. Fails once the
+    /// client is gone or misbehaving.
+    fn read_commands<F>(&mut self, run: &mut F) -> Result<()> where F: FnMut(&str) -> String {
+        let mut chunk = [0; 512];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(Error::new(ErrorKind::ConnectionAborted,
+                                               "Admin client left.", None)),
+                Ok(n) => self.buf.extend(chunk[..n].iter().cloned()),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e)
+            }
+        }
+
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let reply = {
+                let line = String::from_utf8_lossy(&self.buf[..pos]);
+                run(line.trim_right_matches('\r'))
+            };
+            self.buf.drain(..pos + 1);
+            try!(self.stream.write_all(format!("{}\n", reply).as_bytes()));
+        }
+        if self.buf.len() > MAX_COMMAND_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "Admin command too long.", None));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AdminSocket;
+    use event::Poll;
+
+    use std::env;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn commands() {
+        let path = env::temp_dir().join(format!("irctools-admin-test-{}.sock", ::std::process::id()));
+        let mut admin = AdminSocket::bind(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"LAG\r\n").unwrap();
+        let mut poll = Poll::new();
+
+        // First the connection is accepted, then the command is run
+        for _ in 0..2 {
+            poll.clear();
+            admin.register(&mut poll);
+            poll.wait(1000).unwrap();
+            admin.process(&poll, |cmd| format!("ran {}", cmd));
+        }
+        let mut reply = String::new();
+        BufReader::new(client).read_line(&mut reply).unwrap();
+        assert_eq!(reply, "ran LAG\n");
+
+        // Still in use, so a second instance can't steal it
+        assert!(AdminSocket::bind(&path).is_err());
+        drop(admin);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,129 @@
+use cmd::{IrcMsg, IrcMsgRef};
+use cmd::command::Command;
+use conf::{Config, Mask, OptionKind, OptionSpec};
+use irc::IrcStream;
+use network::irc_lower;
+use protocol::ServerProtocol;
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Settings of the control bot, under `options.control`:
+///
+/// * `admins`: masks of users who may run privileged commands without being
+///   IRC operators
+/// * `opers_only`: whether only opers and admins may talk to the bot at all
+pub static OPTIONS: [OptionSpec; 2] = [OptionSpec { name: "admins", kind: OptionKind::Masks },
+                                       OptionSpec { name: "opers_only", kind: OptionKind::Bool }];
+
+/// The control bot: the pseudo-client opers talk to in order to query services.
+pub struct ControlBot {
+    config: Rc<RefCell<Config>>
+}
+
+impl ControlBot {
+    pub fn new(conf: Rc<RefCell<Config>>) -> ControlBot {
+        ControlBot { config: conf }
+    }
+
+    /// Handles a message from the uplink. Returns the replies to send if it was
+    /// a command addressed to the bot.
+    pub fn handle<T: ServerProtocol>(&self, msg: &IrcMsgRef, ircstream: &IrcStream<T>) -> Vec<IrcMsg> {
+        let (target, text) = match Command::from_ref(msg) {
+            Ok(Command::Privmsg { target, text }) => (target, text),
+            _ => return Vec::new()
+        };
+        let sender = match msg.prefix().and_then(|p| p.nick()) {
+            Some(nick) => nick,
+            None => return Vec::new()
+        };
+
+        // Not kept borrowed: REHASH replaces the configuration
+        let (nick, trusted, opers_only) = {
+            let conf = self.config.borrow();
+            let options = conf.options("control");
+            (conf.get_cbot_nick().to_owned(), is_trusted(sender, ircstream, &options.get_masks("admins")),
+             options.get_bool("opers_only", false))
+        };
+        // Messages can be addressed to nick@server
+        let target = target.split('@').next().unwrap_or(target);
+        if irc_lower(target) != irc_lower(&nick[..]) {
+            return Vec::new();
+        }
+
+        info!(Bot, "{} ran {}", sender, text);
+        let reply = if (opers_only || is_privileged(text)) && !trusted {
+            warn!(Security, "{} tried to run {} without being an IRC operator or admin", sender, text);
+            "Permission denied".to_owned()
+        } else {
+            self.run_command(text, ircstream)
+        };
+
+        vec![IrcMsg::new(Some(nick), "NOTICE", vec![sender.to_owned(), reply])]
+    }
+
+    /// Runs a command, from IRC or from the admin socket, and returns the reply.
+    pub fn run_command<T: ServerProtocol>(&self, text: &str, ircstream: &IrcStream<T>) -> String {
+        let mut words = text.split(' ').filter(|w| w.len() > 0);
+        let command = words.next().unwrap_or("");
+        if command.eq_ignore_ascii_case("REHASH") {
+            let force = words.next().map_or(false, |w| w.eq_ignore_ascii_case("FORCE"));
+            return rehash(ircstream, force);
+        }
+        let conf = self.config.borrow();
+        match &command.to_ascii_uppercase()[..] {
+            "LAG" => match ircstream.lag() {
+                Some(lag) => format!("Lag to {}: {} ms", conf.get_uplink_name(), lag),
+                None => format!("Lag to {}: not measured yet", conf.get_uplink_name())
+            },
+            "HELP" => "Available commands: HELP, LAG, REHASH [FORCE]".to_owned(),
+            _ => format!("Unknown command: {}", command)
+        }
+    }
+}
+
+/// Commands only IRC operators may run from IRC.
+fn is_privileged(text: &str) -> bool {
+    text.split(' ').filter(|w| w.len() > 0).next().map_or(false, |c| c.eq_ignore_ascii_case("REHASH"))
+}
+
+/// Whether a user is an IRC operator, or matches one of the admin masks. Masks
+/// are matched against the real host: vhosts are too easy to come by.
+fn is_trusted<T: ServerProtocol>(nick: &str, ircstream: &IrcStream<T>, admins: &[Mask]) -> bool {
+    let network = ircstream.network();
+    let network = network.borrow();
+    network.user(nick).map_or(false, |u| {
+        u.umodes.contains('o') || admins.iter().any(|m| m.matches(&u.nick[..], &u.ident[..], &u.host[..]))
+    })
+}
+
+/// Reloads the configuration, and describes what changed.
+fn rehash<T: ServerProtocol>(ircstream: &IrcStream<T>, force: bool) -> String {
+    let changes = match ircstream.rehash(force) {
+        Ok(changes) => changes,
+        Err(e) => return format!("Rehash failed: {}", e)
+    };
+    let mut applied = Vec::new();
+    if !changes.joined.is_empty() {
+        applied.push(format!("joined {}", changes.joined.connect(", ")));
+    }
+    if !changes.parted.is_empty() {
+        applied.push(format!("parted {}", changes.parted.connect(", ")));
+    }
+    if changes.host.is_some() {
+        applied.push("host changed".to_owned());
+    }
+    if changes.gecos.is_some() {
+        applied.push("gecos changed".to_owned());
+    }
+    if !changes.relink.is_empty() {
+        applied.push(format!("relinking for {}", changes.relink.connect(", ")));
+    }
+    if applied.is_empty() {
+        "Configuration reloaded".to_owned()
+    } else {
+        format!("Configuration reloaded: {}", applied.connect("; "))
+    }
+}
@@ -0,0 +1,128 @@
+use log::Level;
+
+use getopts::Options;
+
+use std::borrow::ToOwned;
+
+/// Configuration file used when `--config` isn't given.
+static DEFAULT_CONFIG: &'static str = "tools.conf";
+
+/// What to do, according to the command line.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Run,
+    /// Validate the configuration and exit
+    CheckConfig,
+    Version,
+    Help
+}
+
+/// Command line arguments.
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub action: Action,
+    pub config: String,
+    /// Detach from the terminal (false unless `--daemon` is given)
+    pub daemon: bool,
+    pub pidfile: Option<String>,
+    /// Overrides the level of every logging output
+    pub log_level: Option<Level>,
+    /// Link and burst as usual, but never send enforcement actions
+    pub dry_run: bool
+}
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("c", "config", &format!("configuration file, JSON or TOML (default: {})", DEFAULT_CONFIG),
+                "FILE");
+    opts.optflag("", "check-config", "report every problem in the configuration and exit");
+    opts.optflag("f", "foreground", "stay in the foreground (the default)");
+    opts.optflag("d", "daemon", "detach from the terminal");
+    opts.optopt("p", "pidfile", "write the process ID to FILE", "FILE");
+    opts.optopt("l", "log-level", "log at LEVEL and above on every output: error, warn, info, debug or trace",
+                "LEVEL");
+    opts.optflag("n", "dry-run", "link and burst, but never send enforcement actions (kills, bans...)");
+    opts.optflag("V", "version", "print the version and exit");
+    opts.optflag("h", "help", "print this help and exit");
+    opts
+}
+
+/// Parses the arguments, without the program name.
+pub fn parse(args: &[String]) -> Result<Args, String> {
+    let matches = try!(options().parse(args).map_err(|e| e.to_string()));
+    if !matches.free.is_empty() {
+        return Err(format!("Unexpected argument: {}", matches.free[0]));
+    }
+    if matches.opt_present("foreground") && matches.opt_present("daemon") {
+        return Err("--foreground and --daemon are mutually exclusive".to_owned());
+    }
+    let log_level = match matches.opt_str("log-level") {
+        Some(level) => Some(try!(Level::parse(&level[..]).ok_or(format!("Unknown log level: {}", level)))),
+        None => None
+    };
+    let action = if matches.opt_present("help") {
+        Action::Help
+    } else if matches.opt_present("version") {
+        Action::Version
+    } else if matches.opt_present("check-config") {
+        Action::CheckConfig
+    } else {
+        Action::Run
+    };
+
+    Ok(Args { action: action,
+              config: matches.opt_str("config").unwrap_or(DEFAULT_CONFIG.to_owned()),
+              daemon: matches.opt_present("daemon"),
+              pidfile: matches.opt_str("pidfile"),
+              log_level: log_level,
+              dry_run: matches.opt_present("dry-run") })
+}
+
+pub fn usage(program: &str) -> String {
+    options().usage(&format!("Usage: {} [options]", program))
+}
+
+/// Optional features this binary was built with.
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "ssl") {
+        features.push("ssl");
+    }
+    features
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, parse};
+    use log::Level;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn defaults() {
+        let parsed = parse(&args(&[])).unwrap();
+        assert_eq!(parsed.action, Action::Run);
+        assert_eq!(parsed.config, "tools.conf");
+        assert!(!parsed.daemon && !parsed.dry_run);
+        assert_eq!(parsed.pidfile, None);
+    }
+
+    #[test]
+    fn options() {
+        let parsed = parse(&args(&["--config", "/etc/irctools.conf", "--daemon", "-p", "/run/irctools.pid",
+                                   "--log-level", "debug", "--dry-run"])).unwrap();
+        assert_eq!(parsed.config, "/etc/irctools.conf");
+        assert!(parsed.daemon && parsed.dry_run);
+        assert_eq!(parsed.pidfile, Some("/run/irctools.pid".to_string()));
+        assert_eq!(parsed.log_level, Some(Level::Debug));
+        assert_eq!(parse(&args(&["--check-config"])).unwrap().action, Action::CheckConfig);
+        assert_eq!(parse(&args(&["-V"])).unwrap().action, Action::Version);
+
+        assert!(parse(&args(&["--log-level", "loud"])).is_err());
+        assert!(parse(&args(&["--daemon", "--foreground"])).is_err());
+        assert!(parse(&args(&["tools.conf"])).is_err());
+        assert!(parse(&args(&["--bogus"])).is_err());
+    }
+}
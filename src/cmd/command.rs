@@ -0,0 +1,251 @@
+use cmd::IrcMsgRef;
+use protocol::{ProtoErrorKind, ProtocolError};
+
+use std::borrow::ToOwned;
+
+/// A typed view of an `IrcMsgRef`. Parameters borrow from the message they were
+/// converted from, and arity is checked once during the conversion so that
+/// protocol handlers don't have to index `params` by hand. Nothing is allocated.
+#[derive(Debug, PartialEq)]
+pub enum Command<'a> {
+    /// PASS :password
+    Pass { password: &'a str },
+    /// PROTOCTL token1 token2 ...
+    Protoctl { tokens: &'a [&'a str] },
+    /// SERVER name hopcount :description
+    Server { name: &'a str, hopcount: u32, description: &'a str },
+    /// PING origin [target]
+    Ping { origin: &'a str, target: Option<&'a str> },
+    /// PONG origin [target]
+    Pong { origin: &'a str, target: Option<&'a str> },
+    /// End Of Synch
+    Eos,
+    /// A server introducing a user. `umodes` and `vhost` are only present with NICKv2,
+    /// and `ip` only with NICKIP.
+    Nick { nick: &'a str, hopcount: u32, timestamp: i64, ident: &'a str, host: &'a str,
+           server: &'a str, servicestamp: &'a str, umodes: Option<&'a str>,
+           vhost: Option<&'a str>, ip: Option<&'a str>, gecos: &'a str },
+    /// A user changing nick.
+    NickChange { nick: &'a str, timestamp: Option<i64> },
+    /// SJOIN ts #chan [+modes [args...]] :members
+    SJoin { timestamp: i64, channel: &'a str, modes: Option<&'a str>,
+            mode_args: &'a [&'a str], members: List<'a> },
+    Join { channels: List<'a> },
+    Part { channels: List<'a>, reason: Option<&'a str> },
+    Kick { channel: &'a str, targets: List<'a>, reason: Option<&'a str> },
+    Mode { target: &'a str, modes: &'a str, args: &'a [&'a str] },
+    UMode2 { modes: &'a str },
+    Quit { reason: Option<&'a str> },
+    Kill { target: &'a str, reason: Option<&'a str> },
+    /// Servers send the setter and timestamp, users don't.
+    Topic { channel: &'a str, setter: Option<&'a str>, timestamp: Option<i64>, topic: &'a str },
+    SQuit { server: &'a str, reason: Option<&'a str> },
+    SetHost { host: &'a str },
+    ChgHost { nick: &'a str, host: &'a str },
+    Privmsg { target: &'a str, text: &'a str },
+    Notice { target: &'a str, text: &'a str },
+    Error { message: &'a str },
+    /// A numeric reply, such as 001 or 433.
+    Numeric { code: u16, params: &'a [&'a str] },
+    /// Anything we don't know about (yet).
+    Unknown { command: &'a str, params: &'a [&'a str] }
+}
+
+impl<'a> Command<'a> {
+    /// Converts a parsed message into a `Command`, checking that every required
+    /// parameter is present and well-formed.
+    pub fn from_ref(msg: &'a IrcMsgRef<'a>) -> Result<Command<'a>, ProtocolError> {
+        let (cmd, p) = (msg.command, msg.params());
+        let command = match cmd {
+            "PASS" => Command::Pass { password: try!(arg(p, 0, cmd)) },
+            "PROTOCTL" => {
+                try!(arg(p, 0, cmd));
+                Command::Protoctl { tokens: p }
+            }
+            "SERVER" => Command::Server { name: try!(arg(p, 0, cmd)),
+                                          hopcount: try!(number(try!(arg(p, 1, cmd)))),
+                                          description: try!(arg(p, 2, cmd)) },
+            "PING" => Command::Ping { origin: try!(arg(p, 0, cmd)), target: opt(p, 1) },
+            "PONG" => Command::Pong { origin: try!(arg(p, 0, cmd)), target: opt(p, 1) },
+            "EOS" => Command::Eos,
+            "NICK" if p.len() >= 8 => {
+                // The gecos is always last; NICKv2 adds umodes and vhost,
+                // NICKIP adds the IP address right before the gecos.
+                let (umodes, vhost, ip) = match p.len() {
+                    8 => (None, None, None),
+                    10 => (Some(p[7]), Some(p[8]), None),
+                    11 => (Some(p[7]), Some(p[8]), Some(p[9])),
+                    _ => return Err(ProtocolError::new(ProtoErrorKind::InvalidParameter,
+                                                       "Unexpected number of NICK parameters",
+                                                       Some(format!("{} parameters", p.len()))))
+                };
+                Command::Nick { nick: p[0], hopcount: try!(number(p[1])),
+                                timestamp: try!(number(p[2])), ident: p[3], host: p[4],
+                                server: p[5], servicestamp: p[6], umodes: umodes,
+                                vhost: vhost, ip: ip, gecos: p[p.len()-1] }
+            }
+            "NICK" => Command::NickChange {
+                nick: try!(arg(p, 0, cmd)),
+                timestamp: match opt(p, 1) {
+                    Some(ts) => Some(try!(number(ts))),
+                    None => None
+                }
+            },
+            "SJOIN" => {
+                try!(arg(p, 2, cmd));
+                Command::SJoin { timestamp: try!(number(p[0])),
+                                 channel: p[1],
+                                 modes: if p.len() > 3 { Some(p[2]) } else { None },
+                                 mode_args: if p.len() > 4 { &p[3..p.len()-1] } else { &[] },
+                                 members: List::new(p[p.len()-1], ' ') }
+            }
+            "JOIN" => Command::Join { channels: List::new(try!(arg(p, 0, cmd)), ',') },
+            "PART" => Command::Part { channels: List::new(try!(arg(p, 0, cmd)), ','),
+                                      reason: opt(p, 1) },
+            "KICK" => Command::Kick { channel: try!(arg(p, 0, cmd)),
+                                      targets: List::new(try!(arg(p, 1, cmd)), ','),
+                                      reason: opt(p, 2) },
+            "MODE" => Command::Mode { target: try!(arg(p, 0, cmd)),
+                                      modes: try!(arg(p, 1, cmd)),
+                                      args: &p[2..] },
+            "UMODE2" => Command::UMode2 { modes: try!(arg(p, 0, cmd)) },
+            "QUIT" => Command::Quit { reason: opt(p, 0) },
+            "KILL" => Command::Kill { target: try!(arg(p, 0, cmd)), reason: opt(p, 1) },
+            "TOPIC" => {
+                let channel = try!(arg(p, 0, cmd));
+                if p.len() >= 3 {
+                    Command::Topic { channel: channel, setter: Some(p[1]),
+                                     timestamp: Some(try!(number(p[2]))),
+                                     topic: opt(p, 3).unwrap_or("") }
+                } else {
+                    Command::Topic { channel: channel, setter: None, timestamp: None,
+                                     topic: opt(p, 1).unwrap_or("") }
+                }
+            }
+            "SQUIT" => Command::SQuit { server: try!(arg(p, 0, cmd)), reason: opt(p, 1) },
+            "SETHOST" => Command::SetHost { host: try!(arg(p, 0, cmd)) },
+            "CHGHOST" => Command::ChgHost { nick: try!(arg(p, 0, cmd)),
+                                            host: try!(arg(p, 1, cmd)) },
+            "PRIVMSG" => Command::Privmsg { target: try!(arg(p, 0, cmd)),
+                                            text: try!(arg(p, 1, cmd)) },
+            "NOTICE" => Command::Notice { target: try!(arg(p, 0, cmd)),
+                                          text: try!(arg(p, 1, cmd)) },
+            "ERROR" => Command::Error { message: opt(p, 0).unwrap_or("") },
+            _ if cmd.len() == 3 && cmd.chars().all(|c| c.is_digit(10)) =>
+                Command::Numeric { code: try!(number(cmd)), params: p },
+            _ => Command::Unknown { command: cmd, params: p }
+        };
+
+        Ok(command)
+    }
+}
+
+/// A list parameter, such as the members of an SJOIN or the channels of a JOIN.
+/// Items are split out as they are iterated; empty ones are skipped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct List<'a> {
+    raw: &'a str,
+    sep: char
+}
+
+impl<'a> List<'a> {
+    pub fn new(raw: &'a str, sep: char) -> List<'a> {
+        List { raw: raw, sep: sep }
+    }
+
+    /// The list as it was received.
+    pub fn as_str(&self) -> &'a str {
+        self.raw
+    }
+
+    pub fn iter(&self) -> ListIter<'a> {
+        ListIter { rest: self.raw, sep: self.sep }
+    }
+}
+
+pub struct ListIter<'a> {
+    rest: &'a str,
+    sep: char
+}
+
+impl<'a> Iterator for ListIter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        while self.rest.len() > 0 {
+            let (item, rest) = match self.rest.find(self.sep) {
+                Some(i) => (&self.rest[..i], &self.rest[i + self.sep.len_utf8()..]),
+                None => (self.rest, "")
+            };
+            self.rest = rest;
+            if item.len() > 0 {
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+fn arg<'a>(params: &[&'a str], index: usize, cmd: &str) -> Result<&'a str, ProtocolError> {
+    params.get(index).map(|p| *p).ok_or(
+        ProtocolError::new(ProtoErrorKind::MissingParameter,
+                           "Missing required parameter",
+                           Some(format!("{} expects at least {} parameters, got {}",
+                                        cmd, index + 1, params.len()))))
+}
+
+fn opt<'a>(params: &[&'a str], index: usize) -> Option<&'a str> {
+    params.get(index).map(|p| *p)
+}
+
+fn number<N: ::std::str::FromStr>(s: &str) -> Result<N, ProtocolError> {
+    s.parse().map_err(|_| ProtocolError::new(ProtoErrorKind::InvalidParameter,
+                                             "Expected a number",
+                                             Some(s.to_owned())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Command, List};
+    use cmd::IrcMsgRef;
+    use protocol::ProtoErrorKind;
+
+    fn error(line: &str) -> Option<ProtoErrorKind> {
+        let msg = IrcMsgRef::parse(line).unwrap();
+        Command::from_ref(&msg).err().map(|e| e.kind)
+    }
+
+    #[test]
+    fn typed_commands() {
+        let ping = IrcMsgRef::parse("PING :Ping.MindForge.org\r\n").unwrap();
+        assert_eq!(Command::from_ref(&ping).ok(),
+                   Some(Command::Ping { origin: "Ping.MindForge.org", target: None }));
+
+        let sjoin = IrcMsgRef::parse(
+            ":Ping.MindForge.org SJOIN 1424879430 #Services +ntl 10 :@Alice +Bob\r\n").unwrap();
+        assert_eq!(Command::from_ref(&sjoin).ok(),
+                   Some(Command::SJoin { timestamp: 1424879430, channel: "#Services",
+                                         modes: Some("+ntl"), mode_args: &["10"],
+                                         members: List::new("@Alice +Bob", ' ') }));
+
+        let numeric = IrcMsgRef::parse(":Ping.MindForge.org 433 * Alice :Nick in use\r\n").unwrap();
+        assert_eq!(Command::from_ref(&numeric).ok(),
+                   Some(Command::Numeric { code: 433, params: &["*", "Alice", "Nick in use"] }));
+    }
+
+    #[test]
+    fn invalid_commands() {
+        assert_eq!(error(":Bob KICK #chan\r\n"), Some(ProtoErrorKind::MissingParameter));
+        assert_eq!(error("NICK Alice 1 yesterday alice host server 0 :Alice\r\n"),
+                   Some(ProtoErrorKind::InvalidParameter));
+    }
+
+    #[test]
+    fn lists() {
+        let members: Vec<&str> = List::new(" @Alice  +Bob ", ' ').iter().collect();
+        assert_eq!(members, vec!["@Alice", "+Bob"]);
+        let chans: Vec<&str> = List::new("#a,,#b", ',').iter().collect();
+        assert_eq!(chans, vec!["#a", "#b"]);
+        assert_eq!(List::new("", ',').iter().next(), None);
+    }
+}
@@ -1,7 +1,32 @@
+pub mod command;
+pub mod prefix;
+
 use std::str::FromStr;
 use std::borrow::ToOwned;
+use std::fmt::{Display, Formatter};
+use std::fmt::Result as FmtResult;
+
+use cmd::prefix::Prefix;
+
+/// Maximum length of a line, including the CR LF terminator.
+pub static MAX_LINE_LEN: usize = 512;
+/// Maximum number of parameters in a message.
+pub const MAX_PARAMS: usize = 15;
+/// Maximum length of the IRCv3 tags section, including the leading `@` and the
+/// trailing space. It is not counted in `MAX_LINE_LEN`.
+pub static MAX_TAGS_LEN: usize = 8191;
+
+/// An IRCv3 message tag. `value` is unescaped; a tag sent as `key=` is the same
+/// as one with no value at all.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Tag {
+    pub key: String,
+    pub value: Option<String>
+}
 
+#[derive(Debug, PartialEq, Clone)]
 pub struct IrcMsg {
+    pub tags: Vec<Tag>,
     pub source: Option<String>,
     pub command: String,
     pub params: Vec<String>
@@ -10,79 +35,358 @@ pub struct IrcMsg {
 pub type IrcMessage = Result<IrcMsg, String>;
 
 impl IrcMsg {
-    fn new(src: Option<String>, cmd: &str, p: Vec<String>) -> IrcMsg {
-        IrcMsg { source: src, command: cmd.to_owned(), params: p }
+    pub fn new(src: Option<String>, cmd: &str, p: Vec<String>) -> IrcMsg {
+        IrcMsg { tags: Vec::new(), source: src, command: cmd.to_owned(), params: p }
+    }
+
+    /// The structured form of `source`.
+    pub fn prefix(&self) -> Option<Prefix> {
+        self.source.as_ref().map(|s| Prefix::parse(&s[..]))
+    }
+
+    /// Returns the value of tag `key`, if present. Tags without a value yield `Some("")`.
+    /// If a key is repeated, the last one wins.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter().rev().find(|t| &t.key[..] == key)
+            .map(|t| t.value.as_ref().map_or("", |v| &v[..]))
+    }
+
+    /// The tags section as sent on the wire (`@k=v;k2 `), or an empty string if there
+    /// are no tags.
+    fn tags_section(&self) -> String {
+        if self.tags.is_empty() {
+            return String::new();
+        }
+        let tags: Vec<String> = self.tags.iter().map(|t| match t.value {
+            Some(ref v) if v.len() > 0 => format!("{}={}", t.key, escape_tag_value(&v[..])),
+            _ => t.key.clone()
+        }).collect();
+        format!("@{} ", tags.connect(";"))
+    }
+
+    /// Serializes the message into a CR LF terminated line, ready to be sent.
+    /// Fails if the message can't be represented on the wire: CR, LF or NUL anywhere,
+    /// spaces in the prefix, the command or any parameter but the last, empty middle
+    /// parameters, too many parameters, or a line longer than 512 bytes.
+    pub fn to_wire(&self) -> Result<String, String> {
+        for tag in self.tags.iter() {
+            if !valid_tag_key(&tag.key[..]) {
+                return Err(format!("Invalid tag key: {:?}", tag.key));
+            }
+        }
+
+        if let Some(ref src) = self.source {
+            if src.len() == 0 || src.contains(' ') || has_forbidden(&src[..]) {
+                return Err(format!("Invalid prefix: {:?}", src));
+            }
+        }
+
+        if self.command.len() == 0 || !self.command.chars().all(|c| c.is_alphanumeric()) {
+            return Err(format!("Invalid command: {:?}", self.command));
+        }
+
+        if self.params.len() > MAX_PARAMS {
+            return Err(format!("Too many parameters: {} (max. {})", self.params.len(), MAX_PARAMS));
+        }
+
+        for (i, param) in self.params.iter().enumerate() {
+            if has_forbidden(&param[..]) {
+                return Err(format!("CR, LF or NUL in parameter {}: {:?}", i, param));
+            }
+            let last = i + 1 == self.params.len();
+            if !last && (param.len() == 0 || param.contains(' ') || param.starts_with(":")) {
+                return Err(format!("Invalid middle parameter {}: {:?}", i, param));
+            }
+        }
+
+        let tags_len = self.tags_section().len();
+        if tags_len > MAX_TAGS_LEN {
+            return Err(format!("Tags too long: {} bytes (max. {})", tags_len, MAX_TAGS_LEN));
+        }
+
+        let line = format!("{}\r\n", self);
+        if line.len() - tags_len > MAX_LINE_LEN {
+            return Err(format!("Line too long: {} bytes (max. {})", line.len() - tags_len,
+                               MAX_LINE_LEN));
+        }
+
+        Ok(line)
     }
 }
 
-impl FromStr for IrcMsg {
-    type Err = String;
+fn has_forbidden(s: &str) -> bool {
+    s.contains('\r') || s.contains('\n') || s.contains('\0')
+}
 
-    fn from_str(m: &str) -> IrcMessage {
-        let mut to_process = m.clone();
+fn valid_tag_key(key: &str) -> bool {
+    key.len() > 0 && key.chars().enumerate().all(
+        |(i, c)| c.is_alphanumeric() || c == '-' || c == '.' || c == '/' || (i == 0 && c == '+'))
+}
 
-        if let Some(end) = to_process.find("\r\n") {
-            to_process = &to_process[..end];
-        } else {
-            return Err("CR LF terminators not found.".to_string());
+/// Escapes a tag value as described in the IRCv3 message-tags specification.
+pub fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape_tag_value`. Unknown escapes drop the backslash, and a trailing
+/// lone backslash is removed.
+pub fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => ()
+        }
+    }
+    unescaped
+}
+
+/// Validates the tags section of a message, without the leading `@`.
+fn check_tags(section: &str) -> Result<(), String> {
+    if section.len() + 2 > MAX_TAGS_LEN {
+        return Err(format!("Tags too long: {} bytes (max. {})", section.len() + 2, MAX_TAGS_LEN));
+    }
+    for (key, _) in raw_tags(section) {
+        if !valid_tag_key(key) {
+            return Err(format!("Invalid tag key: {:?}", key));
+        }
+    }
+    Ok(())
+}
+
+/// Splits a tags section into `(key, escaped value)` pairs.
+fn raw_tags<'a>(section: &'a str) -> Vec<(&'a str, Option<&'a str>)> {
+    section.split(';').filter(|t| t.len() > 0).map(|tag| match tag.find('=') {
+        Some(eq) => (&tag[..eq], Some(&tag[eq+1..])),
+        None => (tag, None)
+    }).collect()
+}
+
+/// Writes the message in wire format, without the CR LF terminator and without
+/// any validation; use `to_wire()` for anything that goes to the network.
+impl Display for IrcMsg {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        try!(write!(f, "{}", self.tags_section()));
+        if let Some(ref src) = self.source {
+            try!(write!(f, ":{} ", src));
+        }
+        try!(write!(f, "{}", self.command));
+        for (i, param) in self.params.iter().enumerate() {
+            let last = i + 1 == self.params.len();
+            if last && (param.len() == 0 || param.contains(' ') || param.starts_with(":")) {
+                try!(write!(f, " :{}", param));
+            } else {
+                try!(write!(f, " {}", param));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A message that borrows everything from the line it was parsed from, to avoid
+/// allocating during large bursts. Tag values are kept escaped until the message
+/// is converted with `into_owned()`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IrcMsgRef<'a> {
+    /// The tags section, without the leading `@`.
+    pub tags: Option<&'a str>,
+    pub source: Option<&'a str>,
+    pub command: &'a str,
+    params: [&'a str; MAX_PARAMS],
+    nparams: usize
+}
+
+impl<'a> IrcMsgRef<'a> {
+    /// Parses a single line, as described in RFC 1459 / RFC 2812 section 2.3.1, with
+    /// IRCv3 message tags. The line must be terminated by LF (with or without CR);
+    /// anything after the first LF is ignored.
+    pub fn parse(m: &'a str) -> Result<IrcMsgRef<'a>, String> {
+        let mut line = match m.find('\n') {
+            Some(end) => &m[..end],
+            None => return Err("LF terminator not found.".to_string())
+        };
+        if line.ends_with("\r") {
+            line = &line[..line.len()-1];
+        }
+        if has_forbidden(line) {
+            return Err("CR or NUL in the middle of a message.".to_string());
         }
 
+        let mut to_process = line.trim_left_matches(' ');
         if to_process.len() == 0 {
             return Err("Empty message.".to_string());
         }
 
-        // We start with the last param because it's the easiest to identify
-        // and then we can remove it from to_process
-        let last_param = if let Some(beg) = to_process.find(" :") {
-            let l = &to_process[beg+2..];
-            to_process = &to_process[..beg];
-            l
+        let tags = if to_process.starts_with("@") {
+            let (t, rest) = split_token(&to_process[1..]);
+            if rest.len() == 0 {
+                return Err("Tags found, but there's no space separator.".to_string());
+            }
+            try!(check_tags(t));
+            to_process = rest;
+            Some(t)
         } else {
-            ""
+            None
         };
 
-        let pref = if m.starts_with(":") {
-            if let Some(end) = m.find(' ') {
-                let p = Some(format!("{}", &m[1..end]));
-                to_process = &to_process[end+1..];
-                p
-            } else {
+        let pref = if to_process.starts_with(":") {
+            let (p, rest) = split_token(&to_process[1..]);
+            if rest.len() == 0 {
                 return Err("Prefix found, but there's no space separator.".to_string());
             }
+            if p.len() == 0 {
+                return Err("Empty prefix.".to_string());
+            }
+            to_process = rest;
+            Some(p)
         } else {
             None
         };
 
-        let command = if let Some(end) = to_process.find(' ') {
-            let c = &to_process[..end];
-            to_process = &to_process[end+1..];
-            c
-        } else {
-            let c = &to_process[..];
-            to_process = "";
-            c
-        };
-
-        let mut params: Vec<_> = to_process.splitn(14, ' ').filter_map(
-            |s| { if s.len() == 0 { None } else { Some(s.to_owned()) } }).collect();
-
-        if last_param.len() > 0 {
-            params.push(last_param.to_owned());
+        let (command, mut to_process) = split_token(to_process);
+        if command.len() == 0 {
+            return Err("Empty command.".to_string());
+        }
+        if !command.chars().all(|c| c.is_alphanumeric()) {
+            return Err(format!("Invalid command: {:?}", command));
         }
 
-        if command.len() > 0 {
-            Ok(IrcMsg::new(pref, command, params))
-        } else {
-            Err("Empty command.".to_string())
+        // Up to 14 middle parameters; the trailing one starts with ':' and may contain
+        // spaces. If there are already 14 middle params, the colon is optional.
+        let mut params = [""; MAX_PARAMS];
+        let mut nparams = 0;
+        while to_process.len() > 0 {
+            if to_process.starts_with(":") {
+                params[nparams] = &to_process[1..];
+                nparams += 1;
+                break;
+            }
+            if nparams == MAX_PARAMS - 1 {
+                params[nparams] = to_process;
+                nparams += 1;
+                break;
+            }
+            let (param, rest) = split_token(to_process);
+            params[nparams] = param;
+            nparams += 1;
+            to_process = rest;
         }
 
+        Ok(IrcMsgRef { tags: tags, source: pref, command: command,
+                       params: params, nparams: nparams })
+    }
+
+    /// The structured form of `source`.
+    pub fn prefix(&self) -> Option<Prefix<'a>> {
+        self.source.map(Prefix::parse)
+    }
+
+    pub fn params(&self) -> &[&'a str] {
+        &self.params[..self.nparams]
+    }
+
+    /// Returns the escaped value of tag `key`, if present.
+    pub fn raw_tag(&self, key: &str) -> Option<&'a str> {
+        self.tags.and_then(|t| raw_tags(t).into_iter().rev().find(|&(k, _)| k == key))
+            .map(|(_, v)| v.unwrap_or(""))
+    }
+
+    pub fn into_owned(self) -> IrcMsg {
+        let tags = self.tags.map_or(Vec::new(), |t| raw_tags(t).into_iter().map(|(k, v)| {
+            Tag { key: k.to_owned(),
+                  value: v.and_then(|v| if v.len() > 0 { Some(unescape_tag_value(v)) } else { None }) }
+        }).collect());
+
+        IrcMsg { tags: tags,
+                 source: self.source.map(|s| s.to_owned()),
+                 command: self.command.to_owned(),
+                 params: self.params().iter().map(|p| p.to_string()).collect() }
+    }
+}
+
+impl FromStr for IrcMsg {
+    type Err = String;
+
+    fn from_str(m: &str) -> IrcMessage {
+        IrcMsgRef::parse(m).map(|msg| msg.into_owned())
+    }
+}
+
+/// Splits off the first space-delimited token. The remainder has its leading
+/// spaces removed, so that repeated separators are accepted.
+fn split_token(s: &str) -> (&str, &str) {
+    match s.find(' ') {
+        Some(end) => (&s[..end], s[end..].trim_left_matches(' ')),
+        None => (s, "")
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::IrcMsg;
+    use super::{IrcMsg, IrcMsgRef, Tag, MAX_PARAMS};
+    use super::{escape_tag_value, unescape_tag_value};
     use std::str::FromStr;
+    use quickcheck::{quickcheck, TestResult};
+
+    fn msg(src: Option<&str>, cmd: &str, params: &[&str]) -> IrcMsg {
+        IrcMsg::new(src.map(|s| s.to_string()), cmd, params.iter().map(|p| p.to_string()).collect())
+    }
+
+    #[test]
+    fn to_wire() {
+        let cases = [
+            (msg(None, "PING", &["Ping.MindForge.org"]), "PING Ping.MindForge.org\r\n"),
+            (msg(Some("Alice"), "PRIVMSG", &["#chan", "hello world"]), ":Alice PRIVMSG #chan :hello world\r\n"),
+            (msg(Some("Alice"), "PRIVMSG", &["#chan", ":-)"]), ":Alice PRIVMSG #chan ::-)\r\n"),
+            (msg(None, "TOPIC", &["#chan", ""]), "TOPIC #chan :\r\n"),
+            (msg(None, "EOS", &[]), "EOS\r\n"),
+            (msg(None, "433", &["*", "Alice", "Nickname is already in use"]), "433 * Alice :Nickname is already in use\r\n")];
+
+        for &(ref m, wire) in cases.iter() {
+            assert_eq!(m.to_wire(), Ok(wire.to_string()));
+            assert_eq!(IrcMsg::from_str(wire).as_ref(), Ok(m));
+        }
+    }
+
+    #[test]
+    fn to_wire_rejects_invalid() {
+        let long = (0..600).map(|_| "x").collect::<String>();
+        let invalid = [
+            msg(None, "PRIVMSG", &["#chan", "hi\r\nQUIT :injected"]),
+            msg(Some("bad prefix"), "PING", &["x"]),
+            msg(None, "PING PONG", &["x"]),
+            msg(None, "", &["x"]),
+            msg(None, "MODE", &["#chan", "", "x"]),
+            msg(None, "MODE", &["#chan", "+b x", "x"]),
+            msg(None, "MODE", &[":#chan", "x"]),
+            msg(None, "PRIVMSG", &["#chan", &long[..]]),
+            msg(None, "X", &["1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13",
+                             "14", "15", "16"])];
+
+        for m in invalid.iter() {
+            assert!(m.to_wire().is_err());
+        }
+    }
     #[test]
     fn ping() {
         let ping1 = ":services.MindForge.org PING services.MindForge.org :RustPower.MindForge.org\r\n";
@@ -136,4 +440,211 @@ mod test {
         assert!(msg.params[1] == "server2.example.com");
         assert!(msg.params[2] == "server3.example.com");
     }
+
+    #[test]
+    fn tags() {
+        let line = "@s2s-md/geoip=cc\\=PT;msgid=a1b2;time=2026-10-17T02:27:06.000Z;+draft/reply \
+                    :001ABCDEF PRIVMSG #chan :hi there\r\n";
+        let msg = IrcMsg::from_str(line).unwrap();
+        assert_eq!(msg.tag("msgid"), Some("a1b2"));
+        assert_eq!(msg.tag("time"), Some("2026-10-17T02:27:06.000Z"));
+        assert_eq!(msg.tag("+draft/reply"), Some(""));
+        assert_eq!(msg.tag("account"), None);
+        assert_eq!(msg.source, Some("001ABCDEF".to_string()));
+        assert_eq!(msg.params, vec!["#chan".to_string(), "hi there".to_string()]);
+
+        let mut tagged = IrcMsg::new(None, "PRIVMSG", vec!["#chan".to_string(), "hi".to_string()]);
+        tagged.tags = vec![Tag { key: "example.com/x".to_string(), value: Some("a; b\\c".to_string()) },
+                           Tag { key: "flag".to_string(), value: None }];
+        let wire = tagged.to_wire().unwrap();
+        assert_eq!(&wire[..], "@example.com/x=a\\:\\sb\\\\c;flag PRIVMSG #chan hi\r\n");
+        assert_eq!(IrcMsg::from_str(&wire[..]), Ok(tagged));
+
+        assert!(IrcMsg::from_str("@b@d=1 PING x\r\n").is_err());
+        assert!(IrcMsg::from_str("@time=now\r\n").is_err());
+    }
+
+    #[test]
+    fn tag_escapes() {
+        assert_eq!(&escape_tag_value("; \\\r\n")[..], "\\:\\s\\\\\\r\\n");
+        assert_eq!(&unescape_tag_value("\\:\\s\\\\\\r\\n")[..], "; \\\r\n");
+        assert_eq!(&unescape_tag_value("a\\b\\")[..], "ab");
+    }
+
+    #[test]
+    fn tags_length() {
+        let mut msg = IrcMsg::new(None, "PING", vec!["x".to_string()]);
+        msg.tags = vec![Tag { key: "big".to_string(),
+                              value: Some((0..1000).map(|_| "0123456789").collect()) }];
+        assert!(msg.to_wire().is_err());
+        msg.tags[0].value = Some((0..100).map(|_| "0123456789").collect());
+        assert!(msg.to_wire().is_ok());
+    }
+
+    #[test]
+    fn parser_edge_cases() {
+        let fifteen = ["1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13", "14",
+                       "15 and the rest"];
+        let cases = vec![
+            ("PING :\r\n", Some(msg(None, "PING", &[""]))),
+            ("PING a :\r\n", Some(msg(None, "PING", &["a", ""]))),
+            ("PING a\n", Some(msg(None, "PING", &["a"]))),
+            ("PING   a    b  \r\n", Some(msg(None, "PING", &["a", "b"]))),
+            ("  :src   PING   a :b  c \r\n", Some(msg(Some("src"), "PING", &["a", "b  c "]))),
+            ("PRIVMSG #chan ::)\r\n", Some(msg(None, "PRIVMSG", &["#chan", ":)"]))),
+            ("PRIVMSG #chan a:b :c :d\r\n", Some(msg(None, "PRIVMSG", &["#chan", "a:b", "c :d"]))),
+            (":weird :prefix PING x\r\n", None),
+            (":a:b PING x :y\r\n", Some(msg(Some("a:b"), "PING", &["x", "y"]))),
+            (":src :trailing only\r\n", None),
+            ("X 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 and the rest\r\n", Some(msg(None, "X", &fifteen))),
+            ("X 1 2 3 4 5 6 7 8 9 10 11 12 13 14 :15 and the rest\r\n", Some(msg(None, "X", &fifteen))),
+            ("PING a\r\nPING b\r\n", Some(msg(None, "PING", &["a"]))),
+            ("PING a", None),
+            ("PING a\rb\r\n", None),
+            ("\r\n", None),
+            ("   \r\n", None),
+            (":src\r\n", None),
+            (": PING\r\n", None),
+            (":src  \r\n", None),
+            ("PI.NG a\r\n", None)];
+
+        for &(input, ref expected) in cases.iter() {
+            assert_eq!(IrcMsg::from_str(input).ok(), *expected);
+        }
+    }
+
+    #[test]
+    fn parser_roundtrip() {
+        static COMMANDS: [&'static str; 5] = ["PRIVMSG", "NICK", "SJOIN", "001", "PING"];
+
+        fn clean(s: &str, space: bool) -> String {
+            s.chars().filter(|&c| c != '\r' && c != '\n' && c != '\0' && (space || c != ' ')).collect()
+        }
+
+        fn prop(source: Option<String>, cmd: u8, middle: Vec<String>, trailing: Option<String>) -> TestResult {
+            let source = source.map(|s| clean(&s[..], false)).and_then(
+                |s| if s.len() > 0 { Some(s) } else { None });
+            let mut params: Vec<String> = middle.iter()
+                .map(|p| clean(&p[..], false).trim_left_matches(':').to_string())
+                .filter(|p| p.len() > 0)
+                .take(MAX_PARAMS - 1)
+                .collect();
+            if let Some(t) = trailing {
+                params.push(clean(&t[..], true));
+            }
+            let msg = IrcMsg::new(source, COMMANDS[cmd as usize % COMMANDS.len()], params);
+
+            match msg.to_wire() {
+                Ok(wire) => TestResult::from_bool(IrcMsg::from_str(&wire[..]) == Ok(msg)),
+                Err(_) => TestResult::discard()
+            }
+        }
+
+        quickcheck(prop as fn(Option<String>, u8, Vec<String>, Option<String>) -> TestResult);
+    }
+
+    #[test]
+    fn parser_never_panics() {
+        fn prop(line: String) -> bool {
+            let _ = IrcMsg::from_str(&line[..]);
+            let _ = IrcMsg::from_str(&format!("{}\r\n", line)[..]);
+            true
+        }
+
+        quickcheck(prop as fn(String) -> bool);
+    }
+
+    #[test]
+    fn borrowed() {
+        let line = "@msgid=x\\sy :Ping.MindForge.org SJOIN 1424879430 #Services +nt :@Alice +Bob\r\n";
+        let msg = IrcMsgRef::parse(line).unwrap();
+        assert_eq!(msg.source, Some("Ping.MindForge.org"));
+        assert_eq!(msg.command, "SJOIN");
+        assert_eq!(msg.params(), &["1424879430", "#Services", "+nt", "@Alice +Bob"][..]);
+        assert_eq!(msg.raw_tag("msgid"), Some("x\\sy"));
+        assert_eq!(msg.into_owned(), IrcMsg::from_str(line).unwrap());
+        assert_eq!(msg.into_owned().tag("msgid"), Some("x y"));
+    }
+}
+
+#[cfg(test)]
+pub mod bench {
+    use super::{IrcMsg, IrcMsgRef};
+    use std::str::FromStr;
+    use test::Bencher;
+
+    /// An Unreal 3.2 burst from a small network: three servers, ~120 users and
+    /// ten channels.
+    static RECORDED: &'static str = include_str!("burst.txt");
+
+    /// How many times the users and channels of the recorded burst are repeated.
+    const COPIES: usize = 200;
+
+    /// A burst of realistic size, ~24000 NICK and 2000 SJOIN lines: the recorded
+    /// one, with its users and channels repeated under new names.
+    pub fn burst() -> String {
+        let lines: Vec<&str> = RECORDED.split('\n').map(|l| l.trim_right_matches('\r'))
+            .filter(|l| l.len() > 0).collect();
+        let kind = |line: &str| line.split(' ').nth(1).unwrap_or("").to_string();
+        let first = lines.iter().position(|l| kind(l) == "NICK").unwrap();
+        let last = lines.iter().rposition(|l| kind(l) == "TOPIC").unwrap();
+
+        let mut out: Vec<String> = lines[..first].iter().map(|l| l.to_string()).collect();
+        for command in ["NICK", "SJOIN"].iter() {
+            for copy in 0..COPIES {
+                let suffix = if copy == 0 { String::new() } else { format!("-{}", copy) };
+                for line in lines[first..last + 1].iter() {
+                    match &kind(line)[..] {
+                        "NICK" if *command == "NICK" => out.push(rename(line, 2, &suffix[..])),
+                        "SJOIN" if *command == "SJOIN" => {
+                            let members = line.find(" :").unwrap();
+                            let mut sjoin = rename(&line[..members], 3, &suffix[..]);
+                            sjoin.push_str(" :");
+                            let renamed: Vec<String> = line[members + 2..].split(' ').filter(|m| m.len() > 0).map(|m|
+                                match m.char_at(0) {
+                                    '&' | '"' | '\'' => m.to_string(),
+                                    _ => format!("{}{}", m, suffix)
+                                }).collect();
+                            sjoin.push_str(&renamed.connect(" ")[..]);
+                            out.push(sjoin);
+                        }
+                        "TOPIC" if *command == "SJOIN" => out.push(rename(line, 2, &suffix[..])),
+                        _ => ()
+                    }
+                }
+            }
+        }
+        out.extend(lines[last + 1..].iter().map(|l| l.to_string()));
+        out.push(String::new());
+        out.connect("\r\n")
+    }
+
+    /// Appends `suffix` to the `index`th word of `line`.
+    fn rename(line: &str, index: usize, suffix: &str) -> String {
+        let mut words: Vec<String> = line.split(' ').map(|w| w.to_string()).collect();
+        words[index].push_str(suffix);
+        words.connect(" ")
+    }
+
+    #[bench]
+    fn burst_borrowed(b: &mut Bencher) {
+        let burst = burst();
+        let lines: Vec<String> = burst.split('\n').filter(|l| l.len() > 0)
+            .map(|l| format!("{}\n", l)).collect();
+        b.bytes = burst.len() as u64;
+        b.iter(|| {
+            lines.iter().filter(|l| IrcMsgRef::parse(&l[..]).is_ok()).count()
+        });
+    }
+
+    #[bench]
+    fn burst_owned(b: &mut Bencher) {
+        let burst = burst();
+        let lines: Vec<String> = burst.split('\n').filter(|l| l.len() > 0)
+            .map(|l| format!("{}\n", l)).collect();
+        b.bytes = burst.len() as u64;
+        b.iter(|| {
+            lines.iter().filter(|l| IrcMsg::from_str(&l[..]).is_ok()).count()
+        });
+    }
 }
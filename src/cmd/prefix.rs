@@ -0,0 +1,110 @@
+use std::fmt::{Display, Formatter};
+use std::fmt::Result as FmtResult;
+
+/// The origin of a message, as found in its prefix.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Prefix<'a> {
+    /// A server name, such as `Ping.MindForge.org`
+    Server(&'a str),
+    /// A bare nick
+    Nick(&'a str),
+    /// `nick!user@host`, or one of its partial forms (`nick@host`, `nick!user`)
+    User { nick: &'a str, user: Option<&'a str>, host: Option<&'a str> },
+    /// Unreal server ID: a digit followed by two digits or uppercase letters
+    Sid(&'a str),
+    /// Unreal user ID: the SID of the user's server followed by six digits or
+    /// uppercase letters
+    Uid(&'a str)
+}
+
+impl<'a> Prefix<'a> {
+    /// Classifies a raw prefix. Nicks can't start with a digit or contain dots,
+    /// so the forms can't be confused with each other.
+    pub fn parse(prefix: &'a str) -> Prefix<'a> {
+        let (rest, host) = match prefix.find('@') {
+            Some(at) => (&prefix[..at], Some(&prefix[at+1..])),
+            None => (prefix, None)
+        };
+        let (nick, user) = match rest.find('!') {
+            Some(bang) => (&rest[..bang], Some(&rest[bang+1..])),
+            None => (rest, None)
+        };
+
+        if user.is_some() || host.is_some() {
+            Prefix::User { nick: nick, user: user, host: host }
+        } else if is_id(prefix, 3) {
+            Prefix::Sid(prefix)
+        } else if is_id(prefix, 9) {
+            Prefix::Uid(prefix)
+        } else if prefix.contains('.') {
+            Prefix::Server(prefix)
+        } else {
+            Prefix::Nick(prefix)
+        }
+    }
+
+    /// Whether the message originated from a server rather than from a user.
+    pub fn is_server(&self) -> bool {
+        match *self {
+            Prefix::Server(_) | Prefix::Sid(_) => true,
+            _ => false
+        }
+    }
+
+    /// The nick of a user prefix, if it carries one (UIDs don't).
+    pub fn nick(&self) -> Option<&'a str> {
+        match *self {
+            Prefix::Nick(nick) | Prefix::User { nick, .. } => Some(nick),
+            _ => None
+        }
+    }
+}
+
+fn is_id(s: &str, len: usize) -> bool {
+    s.len() == len && s.chars().next().map_or(false, |c| c.is_digit(10)) &&
+        s.chars().all(|c| c.is_digit(10) || (c >= 'A' && c <= 'Z'))
+}
+
+impl<'a> Display for Prefix<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            Prefix::Server(s) | Prefix::Nick(s) | Prefix::Sid(s) | Prefix::Uid(s) => write!(f, "{}", s),
+            Prefix::User { nick, user, host } => {
+                try!(write!(f, "{}", nick));
+                if let Some(user) = user {
+                    try!(write!(f, "!{}", user));
+                }
+                if let Some(host) = host {
+                    try!(write!(f, "@{}", host));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Prefix;
+
+    #[test]
+    fn parse() {
+        let cases = [
+            ("Ping.MindForge.org", Prefix::Server("Ping.MindForge.org")),
+            ("Alice", Prefix::Nick("Alice")),
+            ("Alice[away]", Prefix::Nick("Alice[away]")),
+            ("Alice!alice@host.example.com",
+             Prefix::User { nick: "Alice", user: Some("alice"), host: Some("host.example.com") }),
+            ("Alice@host.example.com", Prefix::User { nick: "Alice", user: None, host: Some("host.example.com") }),
+            ("Alice!alice", Prefix::User { nick: "Alice", user: Some("alice"), host: None }),
+            ("001", Prefix::Sid("001")),
+            ("0AB", Prefix::Sid("0AB")),
+            ("001ABCDEF", Prefix::Uid("001ABCDEF"))];
+
+        for &(raw, ref expected) in cases.iter() {
+            let prefix = Prefix::parse(raw);
+            assert_eq!(prefix, *expected);
+            assert_eq!(&prefix.to_string()[..], raw);
+        }
+    }
+}
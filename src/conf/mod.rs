@@ -1,111 +1,812 @@
+mod options;
+mod source;
+mod validate;
+
+pub use self::options::{Mask, ModuleOptions, OptionKind, OptionSpec};
+pub use self::validate::ConfigError;
+
 use std::borrow::{Borrow, ToOwned};
 use std::collections::HashMap;
-use std::fs::File;
 use std::io::{Error, ErrorKind};
 use std::io::Result;
-use std::io::Read;
-use std::error::Error as StdError;
-use std::path::Path;
-use rustc_serialize::json::decode;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use rustc_serialize::Decodable;
+use rustc_serialize::json;
+use log::{Category, Level};
+use network::irc_lower;
 
-/// Configuration data.
+/// A link block: an uplink we can connect to.
+#[derive(RustcDecodable, Clone, Default)]
+pub struct Link {
+    /// Server name the uplink is expected to introduce itself with
+    pub name: String,
+    /// Address to connect to: a hostname, an IP address, or the path of a unix socket
+    pub address: String,
+    pub port: Option<u16>,
+    /// Password we send
+    pub password: String,
+    /// Password we expect from the uplink
+    pub pass_receive: String,
+    pub use_ssl: bool,
+    pub tls: Option<TlsConfig>,
+    /// Local address to connect from
+    pub bind: Option<String>
+}
+
+/// A bot block: a client we introduce once the burst is over. The first one is
+/// the control bot, which takes commands and logs to channels; the others only
+/// sit in their channels.
+#[derive(RustcDecodable, Clone, Default, Debug, PartialEq)]
+pub struct Bot {
+    pub nick: String,
+    pub ident: String,
+    pub host: String,
+    pub gecos: String,
+    /// Channels joined once introduced
+    pub chans: Vec<String>
+}
+
+/// TLS settings of a link block.
+#[derive(RustcDecodable, Clone, Default, Debug)]
+pub struct TlsConfig {
+    /// Oldest protocol version we accept: "1.0", "1.1" or "1.2" (the default)
+    pub min_version: Option<String>,
+    /// Whether the uplink certificate must be signed by a trusted CA and match
+    /// `hostname`. Defaults to true unless `fingerprint` is set.
+    pub verify: Option<bool>,
+    /// PEM bundle of trusted CAs; the system ones are used if unset
+    pub ca_file: Option<String>,
+    /// SHA-256 fingerprint of the uplink certificate, in hex (colons allowed).
+    /// When set, only that certificate is accepted.
+    pub fingerprint: Option<String>,
+    /// Name sent with SNI and checked against the certificate. Defaults to the
+    /// link name.
+    pub hostname: Option<String>,
+    /// PEM client certificate and key we present, for certfp authentication
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>
+}
+
+/// A logging output: where messages go, and which ones.
+#[derive(RustcDecodable, Clone, Default, Debug)]
+pub struct LogConfig {
+    /// "stderr", "file", "syslog" or "channel"
+    pub sink: String,
+    /// Least important level logged: "error", "warn", "info" (the default),
+    /// "debug" or "trace" (raw link traffic)
+    pub level: Option<String>,
+    /// Categories logged: "core", "link", "protocol", "bot", "security". All of
+    /// them if unset.
+    pub categories: Option<Vec<String>>,
+    /// Log file, or syslog socket (defaults to /dev/log)
+    pub path: Option<String>,
+    /// Size in bytes beyond which the log file is rotated; never if unset
+    pub max_size: Option<u64>,
+    /// Rotated log files kept (5 by default)
+    pub keep: Option<u32>,
+    /// Channel the control bot logs to
+    pub channel: Option<String>
+}
+
+/// TLS protocol versions.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum TlsVersion {
+    Tlsv1,
+    Tlsv1_1,
+    Tlsv1_2
+}
+
+/// How the link blocks are tried when (re)connecting.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LinkOrder {
+    /// Always start with the first link block, and fall back to the next ones.
+    Priority,
+    /// Start with the link block after the one used last.
+    RoundRobin
+}
+
+/// What changed between the running configuration and a reloaded one, as far
+/// as the link is concerned. Logging, charsets, send queue and ping settings
+/// are picked up without further action; link blocks on the next connection.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigChanges {
+    /// Channels added to `cbot_chans`
+    pub joined: Vec<String>,
+    /// Channels removed from `cbot_chans`
+    pub parted: Vec<String>,
+    /// New `cbot_host`, if it changed
+    pub host: Option<String>,
+    /// New `cbot_gecos`, if it changed
+    pub gecos: Option<String>,
+    /// Settings that changed but can only be applied by relinking
+    pub relink: Vec<&'static str>
+}
+
+/// Configuration data, as found in the configuration file.
 #[derive(RustcDecodable, Default)]
-pub struct Config {
+struct ConfigData {
     servname: String,
     numeric: u16,
     description: String,
-    uplink: String,
-    uplinkname: String,
+    /// Single uplink configuration, used when there are no link blocks
+    uplink: Option<String>,
+    uplinkname: Option<String>,
     port: Option<u16>,
-    password: String,
-    pass_receive: String,
-    use_ssl: bool,
+    password: Option<String>,
+    pass_receive: Option<String>,
+    use_ssl: Option<bool>,
+    tls: Option<TlsConfig>,
+    bind: Option<String>,
+    links: Option<Vec<Link>>,
+    link_order: Option<String>,
     encoding: String,
-    cbot_nick: String,
-    cbot_ident: String,
-    cbot_host: String,
-    cbot_gecos: String,
-    cbot_chans: Vec<String>,
+    send_encoding: Option<String>,
+    target_encodings: Option<HashMap<String, String>>,
+    /// Single bot configuration, used when there are no bot blocks
+    cbot_nick: Option<String>,
+    cbot_ident: Option<String>,
+    cbot_host: Option<String>,
+    cbot_gecos: Option<String>,
+    cbot_chans: Option<Vec<String>>,
+    bots: Option<Vec<Bot>>,
+    reconnect_min: Option<u32>,
+    reconnect_max: Option<u32>,
+    ping_freq: Option<u32>,
+    ping_timeout: Option<u32>,
+    connect_timeout: Option<u32>,
+    record_file: Option<String>,
+    admin_socket: Option<String>,
+    sendq_max: Option<usize>,
+    target_rate_lines: Option<u32>,
+    target_rate_period: Option<u32>,
+    logging: Option<Vec<LogConfig>>,
     options: HashMap<String, String>
 }
 
+/// Configuration data.
+#[derive(Default)]
+pub struct Config {
+    data: ConfigData,
+    links: Vec<Link>,
+    /// The control bot first
+    bots: Vec<Bot>,
+    link_order: LinkOrder,
+    /// Index of the link block currently in use
+    active_link: Option<usize>,
+    /// File the configuration was loaded from, to reload it
+    path: Option<PathBuf>,
+    /// Set from the command line: enforcement actions are logged instead of sent
+    dry_run: bool,
+    /// Set from the command line: overrides the level of every logging output
+    log_level: Option<Level>
+}
+
+impl Default for LinkOrder {
+    fn default() -> LinkOrder {
+        LinkOrder::Priority
+    }
+}
+
 #[stable]
 impl Config {
-    /// Loads a JSON configuration from the desired path.
+    /// Loads a JSON or TOML configuration from the desired path.
     #[stable]
     pub fn load(path: &Path) -> Result<Config> {
-        let mut file = try!(File::open(path));
-        let mut data = String::new();
-        try!(file.read_to_string(&mut data));
-        decode(&data[..]).map_err(|e| Error::new(ErrorKind::InvalidInput,
-                                                 "Failed to decode configuration file.",
-                                                 Some(e.description().to_owned())))
+        Config::load_checked(path).map_err(|errors| invalid(&errors[..]))
+    }
+
+    /// Loads and validates a configuration, reporting every problem found.
+    pub fn load_checked(path: &Path) -> ::std::result::Result<Config, Vec<ConfigError>> {
+        let mut json = try!(source::read(path));
+        let errors = validate::check_json(&json);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        // Module options are nested objects in the file, but kept as dotted keys
+        if let json::Json::Object(ref mut object) = json {
+            let flat = try!(options::flatten(&object["options"]).map_err(|e| vec![e]));
+            object.insert("options".to_owned(), flat);
+        }
+        let data: ConfigData = try!(Decodable::decode(&mut json::Decoder::new(json))
+                                        .map_err(|e| vec![validate::decoder_error(e)]));
+        let mut config = try!(Config::from_data_checked(data));
+        config.path = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    /// Loads the configuration file again, keeping the runtime state: the link in
+    /// use and the command line overrides. Link changes apply on the next
+    /// connection, but the link in use can't be removed.
+    pub fn reloaded(&self) -> Result<Config> {
+        let path = try!(self.path.as_ref().ok_or(
+            Error::new(ErrorKind::InvalidInput, "The configuration wasn't loaded from a file.", None)));
+        let mut config = try!(Config::load(path));
+        if let Some(_) = self.active_link {
+            let name = &self.active_link().name;
+            match config.links.iter().position(|l| &l.name == name) {
+                Some(index) => config.active_link = Some(index),
+                None => return Err(Error::new(ErrorKind::InvalidInput,
+                                              "The link block in use was removed.",
+                                              Some(name.clone())))
+            }
+        }
+        config.dry_run = self.dry_run;
+        config.log_level = self.log_level;
+        Ok(config)
+    }
+
+    fn from_data(data: ConfigData) -> Result<Config> {
+        Config::from_data_checked(data).map_err(|errors| invalid(&errors[..]))
+    }
+
+    fn from_data_checked(mut data: ConfigData) -> ::std::result::Result<Config, Vec<ConfigError>> {
+        let legacy = data.links.is_none();
+        let links = match data.links.take() {
+            Some(links) => links,
+            None => match (data.uplink.take(), data.uplinkname.take()) {
+                (Some(addr), Some(name)) => vec![Link { name: name,
+                                                        address: addr,
+                                                        port: data.port,
+                                                        password: data.password.take().unwrap_or(String::new()),
+                                                        pass_receive: data.pass_receive.take().unwrap_or(String::new()),
+                                                        use_ssl: data.use_ssl.unwrap_or(false),
+                                                        tls: data.tls.take(),
+                                                        bind: data.bind.take() }],
+                _ => Vec::new()
+            }
+        };
+
+        let legacy_bot = data.bots.is_none();
+        let bots = match data.bots.take() {
+            Some(bots) => bots,
+            None => match data.cbot_nick.take() {
+                Some(nick) => vec![Bot { nick: nick,
+                                         ident: data.cbot_ident.take().unwrap_or(String::new()),
+                                         host: data.cbot_host.take().unwrap_or(String::new()),
+                                         gecos: data.cbot_gecos.take().unwrap_or(String::new()),
+                                         chans: data.cbot_chans.take().unwrap_or(Vec::new()) }],
+                None => Vec::new()
+            }
+        };
+
+        let mut errors = validate::check_data(&data, &links[..], legacy, &bots[..], legacy_bot);
+        errors.extend(options::check(&data.options, &options::MODULES).into_iter());
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let order = match data.link_order.as_ref().map(|o| &o[..]) {
+            Some("roundrobin") => LinkOrder::RoundRobin,
+            _ => LinkOrder::Priority
+        };
+
+        Ok(Config { data: data, links: links, bots: bots, link_order: order, active_link: None, path: None,
+                    dry_run: false, log_level: None })
+    }
+
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn set_log_level(&mut self, level: Option<Level>) {
+        self.log_level = level;
+    }
+
+    /// Level every logging output uses instead of its own, if set.
+    pub fn get_log_level(&self) -> Option<Level> {
+        self.log_level
+    }
+
+    /// Compares with a reloaded configuration. Only the control bot is updated
+    /// in place; changes to the other bots need a relink.
+    pub fn changes(&self, new: &Config) -> ConfigChanges {
+        let (old, data) = (&self.data, &new.data);
+        let (old_bot, new_bot) = (&self.bots[0], &new.bots[0]);
+        let mut changes = ConfigChanges::default();
+        let old_chans: Vec<String> = old_bot.chans.iter().map(|c| irc_lower(&c[..])).collect();
+        let new_chans: Vec<String> = new_bot.chans.iter().map(|c| irc_lower(&c[..])).collect();
+        changes.joined = new_bot.chans.iter().filter(|c| !old_chans.contains(&irc_lower(&c[..])))
+                                      .cloned().collect();
+        changes.parted = old_bot.chans.iter().filter(|c| !new_chans.contains(&irc_lower(&c[..])))
+                                      .cloned().collect();
+        if new_bot.host != old_bot.host {
+            changes.host = Some(new_bot.host.clone());
+        }
+        if new_bot.gecos != old_bot.gecos {
+            changes.gecos = Some(new_bot.gecos.clone());
+        }
+
+        if data.servname != old.servname {
+            changes.relink.push("servname");
+        }
+        if data.numeric != old.numeric {
+            changes.relink.push("numeric");
+        }
+        if data.description != old.description {
+            changes.relink.push("description");
+        }
+        if irc_lower(&new_bot.nick[..]) != irc_lower(&old_bot.nick[..]) {
+            changes.relink.push("cbot_nick");
+        }
+        if new_bot.ident != old_bot.ident {
+            changes.relink.push("cbot_ident");
+        }
+        if new.bots[1..] != self.bots[1..] {
+            changes.relink.push("bots");
+        }
+        changes
     }
 
     pub fn get_server_name(&self) -> &str {
-        &self.servname[..]
+        &self.data.servname[..]
     }
 
     pub fn get_numeric(&self) -> u16 {
-        self.numeric
+        self.data.numeric
     }
 
     pub fn get_description(&self) -> &str {
-        &self.description[..]
+        &self.data.description[..]
     }
 
-    pub fn get_uplink_addr(&self) -> &str {
-        &self.uplink[..]
+    pub fn get_links(&self) -> &[Link] {
+        &self.links[..]
     }
 
-    pub fn get_uplink_name(&self) -> &str {
-        &self.uplinkname[..]
+    /// Indexes of the link blocks, in the order they should be tried on the next
+    /// connection attempt.
+    pub fn link_candidates(&self) -> Vec<usize> {
+        let n = self.links.len();
+        let first = match (self.link_order, self.active_link) {
+            (LinkOrder::RoundRobin, Some(last)) => (last + 1) % n,
+            _ => 0
+        };
+        (0..n).map(|i| (first + i) % n).collect()
     }
 
-    #[cfg(not(feature = "ssl"))]
-    pub fn get_uplink_port(&self) -> u16 {
-        self.port.unwrap_or(6667)
+    /// Makes link block `index` the one used by the `get_uplink_*` accessors.
+    pub fn select_link(&mut self, index: usize) {
+        assert!(index < self.links.len());
+        self.active_link = Some(index);
+    }
+
+    /// The link block currently in use (the first one before any connection attempt).
+    pub fn active_link(&self) -> &Link {
+        &self.links[self.active_link.unwrap_or(0)]
+    }
+
+    pub fn get_uplink_addr(&self) -> &str {
+        &self.active_link().address[..]
+    }
+
+    pub fn get_uplink_name(&self) -> &str {
+        &self.active_link().name[..]
     }
 
-    #[cfg(feature = "ssl")]
     pub fn get_uplink_port(&self) -> u16 {
-        self.port.unwrap_or(6697)
+        let link = self.active_link();
+        link.port.unwrap_or(if link.use_ssl { 6697 } else { 6667 })
     }
 
     pub fn get_link_passwd(&self) -> &str {
-        &self.password[..]
+        &self.active_link().password[..]
     }
 
     pub fn get_passwd_receive(&self) -> &str {
-        &self.pass_receive[..]
+        &self.active_link().pass_receive[..]
     }
 
+    /// Charset of incoming lines that aren't valid UTF-8.
     pub fn get_encoding(&self) -> &str {
-        &self.encoding[..]
+        &self.data.encoding[..]
+    }
+
+    /// Charset of outgoing lines; defaults to `encoding`.
+    pub fn get_send_encoding(&self) -> &str {
+        self.data.send_encoding.as_ref().map(|e| &e[..]).unwrap_or(self.get_encoding())
+    }
+
+    /// Channels and nicks that want outgoing lines in a charset of their own.
+    pub fn get_target_encodings(&self) -> Vec<(&str, &str)> {
+        match self.data.target_encodings {
+            Some(ref targets) => targets.iter().map(|(t, e)| (&t[..], &e[..])).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Local address to connect to the active link from, if any.
+    pub fn get_bind_addr(&self) -> Option<IpAddr> {
+        self.active_link().bind.as_ref().and_then(|b| b.parse().ok())
     }
 
     pub fn use_ssl(&self) -> bool {
-        self.use_ssl
+        self.active_link().use_ssl
+    }
+
+    /// TLS settings of the active link block.
+    pub fn get_tls(&self) -> TlsConfig {
+        self.active_link().tls.clone().unwrap_or(Default::default())
+    }
+
+    /// Every bot we introduce, the control bot first.
+    pub fn get_bots(&self) -> &[Bot] {
+        &self.bots[..]
     }
 
     pub fn get_cbot_nick(&self) -> &str {
-        &self.cbot_nick[..]
+        &self.bots[0].nick[..]
     }
 
     pub fn get_cbot_ident(&self) -> &str {
-        &self.cbot_ident[..]
+        &self.bots[0].ident[..]
     }
 
     pub fn get_cbot_host(&self) -> &str {
-        &self.cbot_host[..]
+        &self.bots[0].host[..]
     }
 
     pub fn get_cbot_gecos(&self) -> &str {
-        &self.cbot_gecos[..]
+        &self.bots[0].gecos[..]
     }
 
     pub fn get_cbot_chans(&self) -> &[String] {
-        self.cbot_chans.borrow()
+        self.bots[0].chans.borrow()
+    }
+
+    /// Seconds to wait before the first reconnection attempt.
+    pub fn get_reconnect_min(&self) -> u32 {
+        self.data.reconnect_min.unwrap_or(5)
+    }
+
+    /// Upper bound, in seconds, for the delay between reconnection attempts.
+    pub fn get_reconnect_max(&self) -> u32 {
+        self.data.reconnect_max.unwrap_or(300)
+    }
+
+    /// Seconds between PINGs sent to the uplink.
+    pub fn get_ping_freq(&self) -> u32 {
+        self.data.ping_freq.unwrap_or(90)
+    }
+
+    /// Seconds we wait for the uplink to answer a PING before dropping the link.
+    pub fn get_ping_timeout(&self) -> u32 {
+        self.data.ping_timeout.unwrap_or(60)
+    }
+
+    /// Seconds we wait for each address of the uplink to accept the connection.
+    pub fn get_connect_timeout(&self) -> u32 {
+        self.data.connect_timeout.unwrap_or(30)
+    }
+
+    /// File the traffic of every link is appended to, for replaying it later,
+    /// with the PASS lines redacted.
+    pub fn get_record_file(&self) -> Option<&str> {
+        self.data.record_file.as_ref().map(|f| &f[..])
+    }
+
+    /// Path of the unix socket accepting control bot commands locally, if any.
+    pub fn get_admin_socket(&self) -> Option<&str> {
+        self.data.admin_socket.as_ref().map(|s| &s[..])
+    }
+
+    /// Bytes that can wait in the send queue before non-urgent messages are refused.
+    pub fn get_sendq_max(&self) -> usize {
+        self.data.sendq_max.unwrap_or(1048576)
+    }
+
+    /// Per-target flood control for PRIVMSG and NOTICE: at most that many lines
+    /// every that many seconds (the period defaults to 1). Disabled unless
+    /// `target_rate_lines` is set.
+    pub fn get_target_rate(&self) -> Option<(u32, u64)> {
+        self.data.target_rate_lines.map(
+            |lines| (lines, self.data.target_rate_period.unwrap_or(1) as u64 * 1000))
+    }
+
+    /// Logging outputs; by default, informational messages go to stderr.
+    pub fn get_logging(&self) -> Vec<LogConfig> {
+        match self.data.logging {
+            Some(ref logging) => logging.clone(),
+            None => vec![LogConfig { sink: "stderr".to_owned(), ..Default::default() }]
+        }
+    }
+
+    /// The options of a module, from its `options.<module>` namespace.
+    pub fn options<'a>(&'a self, module: &'a str) -> ModuleOptions<'a> {
+        ModuleOptions::new(module, &self.data.options)
+    }
+}
+
+impl TlsConfig {
+    pub fn min_version(&self) -> ::std::result::Result<TlsVersion, String> {
+        match self.min_version.as_ref().map(|v| &v[..]) {
+            Some("1.0") => Ok(TlsVersion::Tlsv1),
+            Some("1.1") => Ok(TlsVersion::Tlsv1_1),
+            None | Some("1.2") => Ok(TlsVersion::Tlsv1_2),
+            Some(other) => Err(format!("Unknown min_version '{}', expected 1.0, 1.1 or 1.2", other))
+        }
+    }
+
+    /// The pinned certificate fingerprint, decoded.
+    pub fn fingerprint(&self) -> ::std::result::Result<Option<Vec<u8>>, String> {
+        let fp = match self.fingerprint {
+            Some(ref fp) => fp,
+            None => return Ok(None)
+        };
+        let hex: Vec<u8> = fp.bytes().filter(|&b| b != b':').collect();
+        if hex.len() != 64 {
+            return Err(format!("fingerprint must be a SHA-256 hash, got '{}'", fp));
+        }
+        let mut digest = Vec::with_capacity(32);
+        for pair in hex.chunks(2) {
+            match (hex_digit(pair[0]), hex_digit(pair[1])) {
+                (Some(hi), Some(lo)) => digest.push(hi << 4 | lo),
+                _ => return Err(format!("fingerprint is not hexadecimal: '{}'", fp))
+            }
+        }
+        Ok(Some(digest))
+    }
+
+    pub fn verify(&self) -> bool {
+        self.verify.unwrap_or(self.fingerprint.is_none())
+    }
+}
+
+impl LogConfig {
+    pub fn level(&self) -> ::std::result::Result<Level, String> {
+        match self.level {
+            Some(ref level) => Level::parse(&level[..]).ok_or(format!("unknown level '{}'", level)),
+            None => Ok(Level::Info)
+        }
+    }
+
+    /// The categories logged; empty for all of them.
+    pub fn categories(&self) -> ::std::result::Result<Vec<Category>, String> {
+        let mut categories = Vec::new();
+        for name in self.categories.iter().flat_map(|c| c.iter()) {
+            categories.push(try!(Category::parse(&name[..]).ok_or(format!("unknown category '{}'", name))));
+        }
+        Ok(categories)
+    }
+
+    fn validate(&self) -> ::std::result::Result<(), String> {
+        try!(self.level());
+        try!(self.categories());
+        match &self.sink[..] {
+            "stderr" | "syslog" => Ok(()),
+            "file" if self.path.is_none() => Err("a file sink needs a path".to_owned()),
+            "channel" if self.channel.is_none() => Err("a channel sink needs a channel".to_owned()),
+            "file" | "channel" => Ok(()),
+            other => Err(format!("unknown sink '{}', expected stderr, file, syslog or channel", other))
+        }
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'...b'9' => Some(c - b'0'),
+        b'a'...b'f' => Some(c - b'a' + 10),
+        b'A'...b'F' => Some(c - b'A' + 10),
+        _ => None
+    }
+}
+
+fn invalid(errors: &[ConfigError]) -> Error {
+    let detail: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    Error::new(ErrorKind::InvalidInput, "Invalid configuration.", Some(detail.connect("; ")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bot, Config, ConfigChanges, ConfigData, ConfigError, Link, LinkOrder, LogConfig, TlsConfig,
+                TlsVersion};
+    use log::{Category, Level};
+
+    use std::collections::HashMap;
+
+    fn link(name: &str) -> Link {
+        Link { name: name.to_string(), address: name.to_string(), ..Default::default() }
+    }
+
+    /// Valid settings, apart from the links
+    fn data() -> ConfigData {
+        ConfigData { servname: "RustPower.MindForge.org".to_string(), numeric: 201,
+                     description: "powered by Rust!".to_string(), encoding: "iso8859-15".to_string(),
+                     cbot_nick: Some("Tools".to_string()), cbot_ident: Some("tools".to_string()),
+                     cbot_host: Some("MindForge.org".to_string()), cbot_gecos: Some("MindForge Tools".to_string()),
+                     cbot_chans: Some(vec!["#Services".to_string()]), ..Default::default() }
+    }
+
+    fn bot(nick: &str, chans: &[&str]) -> Bot {
+        Bot { nick: nick.to_string(), ident: "tools".to_string(), host: "MindForge.org".to_string(),
+              gecos: "MindForge Tools".to_string(), chans: chans.iter().map(|c| c.to_string()).collect() }
+    }
+
+    /// The settings of `data()`, with bot blocks instead of the cbot_* settings
+    fn with_bots(bots: Vec<Bot>) -> ConfigData {
+        ConfigData { links: Some(vec![link("hub1")]), cbot_nick: None, cbot_ident: None, cbot_host: None,
+                     cbot_gecos: None, cbot_chans: None, bots: Some(bots), ..data() }
+    }
+
+    #[test]
+    fn legacy_uplink() {
+        let data = ConfigData { uplink: Some("37.187.102.70".to_string()),
+                                uplinkname: Some("Ping.MindForge.org".to_string()),
+                                use_ssl: Some(true),
+                                ..data() };
+        let conf = Config::from_data(data).ok().unwrap();
+        assert_eq!(conf.get_links().len(), 1);
+        assert_eq!(conf.get_uplink_name(), "Ping.MindForge.org");
+        assert_eq!(conf.get_uplink_port(), 6697);
+        assert!(Config::from_data(Default::default()).is_err());
+    }
+
+    #[test]
+    fn link_order() {
+        let data = ConfigData { links: Some(vec![link("hub1"), link("hub2"), link("hub3")]),
+                                link_order: Some("roundrobin".to_string()),
+                                ..data() };
+        let mut conf = Config::from_data(data).ok().unwrap();
+        assert_eq!(conf.link_order, LinkOrder::RoundRobin);
+        assert_eq!(conf.link_candidates(), vec![0, 1, 2]);
+        conf.select_link(1);
+        assert_eq!(conf.get_uplink_name(), "hub2");
+        assert_eq!(conf.link_candidates(), vec![2, 0, 1]);
+
+        conf.link_order = LinkOrder::Priority;
+        assert_eq!(conf.link_candidates(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn tls() {
+        let fp = "3A:7F:00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF:00:11:22:33:44:55:66:77:88:99:aa:bb:cc:dd";
+        let tls = TlsConfig { fingerprint: Some(fp.to_string()), ..Default::default() };
+        let digest = tls.fingerprint().ok().unwrap().unwrap();
+        assert_eq!(digest.len(), 32);
+        assert_eq!(&digest[..3], &[0x3a, 0x7f, 0x00][..]);
+        assert_eq!(digest[31], 0xdd);
+        assert!(!tls.verify());
+        assert_eq!(tls.min_version(), Ok(TlsVersion::Tlsv1_2));
+
+        let bad = TlsConfig { fingerprint: Some("3A:7F".to_string()),
+                              min_version: Some("1.3".to_string()), ..Default::default() };
+        assert!(bad.fingerprint().is_err());
+        assert!(bad.min_version().is_err());
+        assert!(TlsConfig::default().verify());
+
+        let data = ConfigData { links: Some(vec![Link { tls: Some(bad), ..link("hub1") }]),
+                                ..data() };
+        assert!(Config::from_data(data).is_err());
+    }
+
+    #[test]
+    fn bind_address() {
+        let v6 = ConfigData { links: Some(vec![Link { bind: Some("2001:db8::1".to_string()), ..link("hub1") }]),
+                              ..data() };
+        let conf = Config::from_data(v6).ok().unwrap();
+        assert_eq!(conf.get_bind_addr(), "2001:db8::1".parse().ok());
+
+        let bad = ConfigData { links: Some(vec![Link { bind: Some("hub1".to_string()), ..link("hub1") }]),
+                               ..data() };
+        assert!(Config::from_data(bad).is_err());
+    }
+
+    #[test]
+    fn logging() {
+        let conf = Config::from_data(ConfigData { links: Some(vec![link("hub1")]), ..data() }).ok().unwrap();
+        let logging = conf.get_logging();
+        assert_eq!(logging.len(), 1);
+        assert_eq!(logging[0].level(), Ok(Level::Info));
+
+        let output = LogConfig { sink: "channel".to_string(), channel: Some("#ServicesLog".to_string()),
+                                 level: Some("warn".to_string()),
+                                 categories: Some(vec!["security".to_string(), "link".to_string()]),
+                                 ..Default::default() };
+        assert_eq!(output.categories(), Ok(vec![Category::Security, Category::Link]));
+        let valid = ConfigData { links: Some(vec![link("hub1")]), logging: Some(vec![output]),
+                                 ..data() };
+        assert!(Config::from_data(valid).is_ok());
+
+        let bad = LogConfig { sink: "file".to_string(), ..Default::default() };
+        let invalid = ConfigData { links: Some(vec![link("hub1")]), logging: Some(vec![bad]),
+                                   ..data() };
+        assert!(Config::from_data(invalid).is_err());
+    }
+
+    #[test]
+    fn changes() {
+        let old = ConfigData { links: Some(vec![link("hub1")]), cbot_nick: Some("Tools".to_string()),
+                               cbot_host: Some("MindForge.org".to_string()),
+                               cbot_chans: Some(vec!["#Services".to_string(), "#TDebug".to_string()]),
+                               ..data() };
+        let new = ConfigData { links: Some(vec![link("hub1")]), cbot_nick: Some("TOOLS".to_string()),
+                               cbot_host: Some("services.MindForge.org".to_string()),
+                               cbot_chans: Some(vec!["#services".to_string(), "#ServicesLog".to_string()]),
+                               ..data() };
+        let old = Config::from_data(old).ok().unwrap();
+        let new = Config::from_data(new).ok().unwrap();
+        assert_eq!(old.changes(&new),
+                   ConfigChanges { joined: vec!["#ServicesLog".to_string()],
+                                   parted: vec!["#TDebug".to_string()],
+                                   host: Some("services.MindForge.org".to_string()),
+                                   gecos: None,
+                                   relink: vec![] });
+
+        let renamed = ConfigData { links: Some(vec![link("hub1")]), servname: "Other.MindForge.org".to_string(),
+                                   cbot_nick: Some("Tools".to_string()),
+                                   cbot_host: Some("MindForge.org".to_string()),
+                                   cbot_chans: Some(vec!["#Services".to_string(), "#TDebug".to_string()]),
+                                   ..data() };
+        let renamed = Config::from_data(renamed).ok().unwrap();
+        assert_eq!(old.changes(&renamed).relink, vec!["servname"]);
+
+        // Only the control bot is updated in place
+        let old = Config::from_data(with_bots(vec![bot("Tools", &["#Services"]), bot("Guard", &["#Help"])]))
+                      .ok().unwrap();
+        let new = Config::from_data(with_bots(vec![bot("Tools", &["#Services", "#TDebug"]),
+                                                   bot("Guard", &["#Help", "#Lobby"])])).ok().unwrap();
+        let changes = old.changes(&new);
+        assert_eq!(changes.joined, vec!["#TDebug".to_string()]);
+        assert_eq!(changes.relink, vec!["bots"]);
+    }
+
+    #[test]
+    fn bot_blocks() {
+        let conf = Config::from_data(with_bots(vec![bot("Tools", &["#Services"]), bot("Guard", &["#Help"])]))
+                       .ok().unwrap();
+        assert_eq!(conf.get_bots().len(), 2);
+        assert_eq!(conf.get_cbot_nick(), "Tools");
+        assert_eq!(conf.get_bots()[1].chans, vec!["#Help".to_string()]);
+
+        // The cbot_* settings make a single bot
+        let conf = Config::from_data(ConfigData { links: Some(vec![link("hub1")]), ..data() }).ok().unwrap();
+        assert_eq!(conf.get_bots(), &[bot("Tools", &["#Services"])][..]);
+
+        let invalid = with_bots(vec![bot("Tools", &["#Services"]), bot("TOOLS", &["Help"]),
+                                     Bot { ident: "".to_string(), ..bot("Guard", &[]) }]);
+        let keys: Vec<String> = Config::from_data_checked(invalid).err().unwrap().into_iter()
+                                                               .map(|e| e.key).collect();
+        assert_eq!(keys, vec!["bots[1].nick", "bots[1].chans[0]", "bots[2].ident"]);
+        let keys: Vec<String> = Config::from_data_checked(with_bots(vec![])).err().unwrap().into_iter()
+                                                                           .map(|e| e.key).collect();
+        assert_eq!(keys, vec!["bots"]);
+    }
+
+    #[test]
+    fn validation() {
+        let invalid = ConfigData { links: Some(vec![Link { port: Some(0), ..link("hub1") }]), numeric: 0,
+                                   cbot_chans: Some(vec!["#Services".to_string(), "Services".to_string()]),
+                                   encoding: "klingon".to_string(), cbot_nick: Some("1Tools".to_string()),
+                                   ..data() };
+        let keys: Vec<String> = Config::from_data_checked(invalid).err().unwrap().into_iter()
+                                                               .map(|e: ConfigError| e.key).collect();
+        assert_eq!(keys, vec!["numeric", "cbot_nick", "cbot_chans[1]", "encoding", "links[0].port"]);
+
+        // Settings of a single uplink are named as they appear in the file
+        let legacy = ConfigData { uplink: Some("37.187.102.70".to_string()),
+                                  uplinkname: Some("Ping.MindForge.org".to_string()),
+                                  bind: Some("nowhere".to_string()), ..data() };
+        let keys: Vec<String> = Config::from_data_checked(legacy).err().unwrap().into_iter()
+                                                                 .map(|e| e.key).collect();
+        assert_eq!(keys, vec!["bind"]);
+
+        // Option errors are named after the module namespace
+        let with_options = |options: HashMap<String, String>| ConfigData { links: Some(vec![link("hub1")]),
+                                                                           options: options, ..data() };
+        let mut options = HashMap::new();
+        options.insert("control.admins".to_string(), "*!*@staff.MindForge.org,a@b!c".to_string());
+        options.insert("control.opers_only".to_string(), "yes".to_string());
+        let keys: Vec<String> = Config::from_data_checked(with_options(options.clone())).err().unwrap().into_iter()
+                                                                                       .map(|e| e.key).collect();
+        assert_eq!(keys, vec!["options.control.admins"]);
+
+        options.insert("control.admins".to_string(), "*!*@staff.MindForge.org".to_string());
+        let conf = Config::from_data_checked(with_options(options)).ok().unwrap();
+        assert!(conf.options("control").get_bool("opers_only", false));
+        assert_eq!(conf.options("control").get_masks("admins").len(), 1);
     }
-    //pub fn get_option(&self) -> Option<
 }
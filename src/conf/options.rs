@@ -0,0 +1,322 @@
+use super::validate::ConfigError;
+use bot;
+use network::irc_lower;
+
+use rustc_serialize::json::{Json, Object};
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::collections::HashMap;
+
+/// The kinds of values module options hold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptionKind {
+    /// true/false, yes/no, on/off
+    Bool,
+    Int,
+    /// Seconds, or a number followed by s, m, h, d or w, possibly several: "1h30m"
+    Duration,
+    /// A JSON list, or a comma separated string
+    List,
+    /// A list of `nick!user@host` masks
+    Masks
+}
+
+/// An option a module reads from its namespace: `options.<module>.<name>`.
+pub struct OptionSpec {
+    pub name: &'static str,
+    pub kind: OptionKind
+}
+
+/// Every module with options, and what they are. Options outside of these are
+/// rejected when the configuration is loaded.
+pub static MODULES: [(&'static str, &'static [OptionSpec]); 1] = [
+    ("control", &bot::OPTIONS)
+];
+
+/// The options of one module, with typed accessors. Values were validated when
+/// the configuration was loaded; the defaults are used for missing ones.
+pub struct ModuleOptions<'a> {
+    module: &'a str,
+    options: &'a HashMap<String, String>
+}
+
+impl<'a> ModuleOptions<'a> {
+    pub fn new(module: &'a str, options: &'a HashMap<String, String>) -> ModuleOptions<'a> {
+        ModuleOptions { module: module, options: options }
+    }
+
+    pub fn get_str(&self, name: &str) -> Option<&'a str> {
+        self.options.get(&format!("{}.{}", self.module, name)).map(|v| &v[..])
+    }
+
+    pub fn get_bool(&self, name: &str, default: bool) -> bool {
+        self.get_str(name).and_then(|v| parse_bool(v).ok()).unwrap_or(default)
+    }
+
+    pub fn get_int(&self, name: &str, default: i64) -> i64 {
+        self.get_str(name).and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
+
+    /// A duration, in seconds.
+    pub fn get_duration(&self, name: &str, default: u64) -> u64 {
+        self.get_str(name).and_then(|v| parse_duration(v).ok()).unwrap_or(default)
+    }
+
+    pub fn get_list(&self, name: &str) -> Vec<String> {
+        self.get_str(name).map(parse_list).unwrap_or(Vec::new())
+    }
+
+    pub fn get_masks(&self, name: &str) -> Vec<Mask> {
+        self.get_list(name).iter().filter_map(|m| Mask::parse(&m[..]).ok()).collect()
+    }
+}
+
+/// A `nick!user@host` mask, with `*` and `?` wildcards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mask {
+    mask: String
+}
+
+impl Mask {
+    /// Parses a mask, completing partial ones as IRCds do: `Alice` stands for
+    /// `Alice!*@*`, and `*@host` for `*!*@host`.
+    pub fn parse(mask: &str) -> Result<Mask, String> {
+        if mask.is_empty() || mask.contains(' ') {
+            return Err(format!("'{}' is not a mask", mask));
+        }
+        let mask = match (mask.find('!'), mask.find('@')) {
+            (Some(bang), Some(at)) if bang < at => mask.to_owned(),
+            (None, Some(_)) => format!("*!{}", mask),
+            (None, None) => format!("{}!*@*", mask),
+            _ => return Err(format!("'{}' is not a nick!user@host mask", mask))
+        };
+        Ok(Mask { mask: irc_lower(&mask[..]) })
+    }
+
+    pub fn matches(&self, nick: &str, user: &str, host: &str) -> bool {
+        let target = irc_lower(&format!("{}!{}@{}", nick, user, host)[..]);
+        wildcard_match(self.mask.as_bytes(), target.as_bytes())
+    }
+}
+
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last star, if the text doesn't match past it
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+pub fn parse_bool(value: &str) -> Result<bool, String> {
+    match &value.to_ascii_lowercase()[..] {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(format!("expected true or false, got '{}'", value))
+    }
+}
+
+pub fn parse_duration(value: &str) -> Result<u64, String> {
+    let invalid = || format!("'{}' is not a duration, such as 90, 30s, 15m, 2h or 1d12h", value);
+    if value.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(secs) = value.parse() {
+        return Ok(secs);
+    }
+    let mut total = 0;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c >= '0' && c <= '9' {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(invalid())
+        };
+        let n: u64 = try!(number.parse().map_err(|_| invalid()));
+        total = try!(n.checked_mul(unit).and_then(|secs| secs.checked_add(total)).ok_or_else(invalid));
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
+pub fn parse_list(value: &str) -> Vec<String> {
+    value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()).map(|v| v.to_owned()).collect()
+}
+
+fn check_value(value: &str, kind: OptionKind) -> Result<(), String> {
+    match kind {
+        OptionKind::Bool => parse_bool(value).map(|_| ()),
+        OptionKind::Int => value.parse::<i64>().map(|_| ())
+                                .map_err(|_| format!("expected an integer, got '{}'", value)),
+        OptionKind::Duration => parse_duration(value).map(|_| ()),
+        OptionKind::List => Ok(()),
+        OptionKind::Masks => {
+            for mask in parse_list(value).iter() {
+                try!(Mask::parse(&mask[..]));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Checks every option against what the modules declared.
+pub fn check(options: &HashMap<String, String>, modules: &[(&str, &[OptionSpec])]) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    let mut keys: Vec<&String> = options.keys().collect();
+    keys.sort();
+    for key in keys.into_iter() {
+        let error_key = format!("options.{}", key);
+        let (module, name) = match key.find('.') {
+            Some(dot) => (&key[..dot], &key[dot + 1..]),
+            None => {
+                errors.push(ConfigError::new(&error_key[..], "options belong to a module, such as options.control"
+                                                             .to_owned()));
+                continue;
+            }
+        };
+        let specs = match modules.iter().find(|&&(m, _)| m == module) {
+            Some(&(_, specs)) => specs,
+            None => {
+                errors.push(ConfigError::new(&error_key[..], format!("unknown module '{}'", module)));
+                continue;
+            }
+        };
+        match specs.iter().find(|s| s.name == name) {
+            Some(spec) => if let Err(e) = check_value(&options[key][..], spec.kind) {
+                errors.push(ConfigError::new(&error_key[..], e));
+            },
+            None => errors.push(ConfigError::new(&error_key[..], "unknown option".to_owned()))
+        }
+    }
+    errors
+}
+
+/// Turns the `options` section into the flat map the options are kept in:
+/// `{"control": {"admins": ["a", "b"]}}` becomes `{"control.admins": "a,b"}`.
+pub fn flatten(options: &Json) -> Result<Json, ConfigError> {
+    let mut flat = Object::new();
+    try!(flatten_into(&mut flat, "", options));
+    Ok(Json::Object(flat))
+}
+
+fn flatten_into(flat: &mut Object, prefix: &str, value: &Json) -> Result<(), ConfigError> {
+    match *value {
+        Json::Object(ref object) => {
+            for (key, value) in object.iter() {
+                let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                try!(flatten_into(flat, &key[..], value));
+            }
+        }
+        Json::Array(ref items) => {
+            let mut values = Vec::new();
+            for item in items.iter() {
+                values.push(try!(scalar(prefix, item)));
+            }
+            flat.insert(prefix.to_owned(), Json::String(values.connect(",")));
+        }
+        Json::Null => (),
+        ref other => { flat.insert(prefix.to_owned(), Json::String(try!(scalar(prefix, other)))); }
+    }
+    Ok(())
+}
+
+fn scalar(key: &str, value: &Json) -> Result<String, ConfigError> {
+    match *value {
+        Json::String(ref s) => Ok(s.clone()),
+        Json::Boolean(b) => Ok(b.to_string()),
+        Json::I64(n) => Ok(n.to_string()),
+        Json::U64(n) => Ok(n.to_string()),
+        Json::F64(n) => Ok(n.to_string()),
+        _ => Err(ConfigError::new(&format!("options.{}", key)[..], format!("unexpected {}", value)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Mask, ModuleOptions, OptionKind, OptionSpec, check, flatten, parse_duration};
+
+    use rustc_serialize::json::Json;
+
+    use std::collections::HashMap;
+
+    static SPECS: [OptionSpec; 3] = [OptionSpec { name: "enabled", kind: OptionKind::Bool },
+                                     OptionSpec { name: "period", kind: OptionKind::Duration },
+                                     OptionSpec { name: "exempt", kind: OptionKind::Masks }];
+
+    fn options(json: &str) -> HashMap<String, String> {
+        match flatten(&Json::from_str(json).unwrap()).ok().unwrap() {
+            Json::Object(object) => object.into_iter().map(|(k, v)| (k, v.as_string().unwrap().to_string()))
+                                          .collect(),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("30m"), Ok(1800));
+        assert_eq!(parse_duration("1h30m"), Ok(5400));
+        assert_eq!(parse_duration("2d"), Ok(172800));
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("30x").is_err());
+        assert!(parse_duration("1h30").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+    }
+
+    #[test]
+    fn masks() {
+        let mask = Mask::parse("*!*@*.MindForge.org").unwrap();
+        assert!(mask.matches("Alice", "alice", "staff.mindforge.org"));
+        assert!(!mask.matches("Alice", "alice", "mindforge.org.evil.com"));
+        assert!(Mask::parse("alice@host?").unwrap().matches("Bob", "Alice", "host1"));
+        assert!(Mask::parse("Alice").unwrap().matches("ALICE", "x", "y"));
+        assert!(Mask::parse("a@b!c").is_err());
+    }
+
+    #[test]
+    fn typed_access() {
+        let opts = options(r#"{ "floodserv": { "enabled": true, "period": "30m", "lines": 5,
+                                               "exempt": ["*!*@staff.MindForge.org", "Alice"] } }"#);
+        let floodserv = ModuleOptions::new("floodserv", &opts);
+        assert!(floodserv.get_bool("enabled", false));
+        assert_eq!(floodserv.get_duration("period", 60), 1800);
+        assert_eq!(floodserv.get_int("lines", 10), 5);
+        assert_eq!(floodserv.get_int("missing", 10), 10);
+        assert_eq!(floodserv.get_masks("exempt").len(), 2);
+
+        let modules = [("floodserv", &SPECS[..])];
+        let keys: Vec<String> = check(&opts, &modules).into_iter().map(|e| e.key).collect();
+        assert_eq!(keys, vec!["options.floodserv.lines"]);
+
+        let bad = options(r#"{ "floodserv": { "enabled": "maybe", "period": "soon" }, "nickserv": { "x": 1 },
+                               "flat": "1" }"#);
+        let errors: Vec<String> = check(&bad, &modules).into_iter().map(|e| e.key).collect();
+        assert_eq!(errors, vec!["options.flat", "options.floodserv.enabled", "options.floodserv.period",
+                                "options.nickserv.x"]);
+    }
+}
@@ -0,0 +1,312 @@
+use super::validate::{self, ConfigError};
+
+use rustc_serialize::json::{Json, Object};
+use toml;
+
+use std::borrow::ToOwned;
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Settings that can be read from a file instead, through `<setting>_file`, so
+/// that they stay out of the configuration.
+static SECRET_FILES: [&'static str; 2] = ["password", "pass_receive"];
+
+/// Reads a configuration file as one JSON object, whatever its format: files
+/// ending in `.toml` are TOML, anything else is JSON.
+///
+/// On top of the settings, a file may have:
+///
+/// * `include`: other files (or just one), relative to the including file,
+///   whose settings are added. Lists, such as `links`, are concatenated with
+///   the including file's entries first; a setting can't be set twice.
+/// * `${NAME}` in any string of a TOML file, replaced with the environment
+///   variable NAME (`$${` stands for a literal `${`). JSON files are left as
+///   they are, so that existing configurations keep their meaning.
+/// * `password_file` and `pass_receive_file`, at the top level or in a link
+///   block: the secret is the contents of the file, without the final newline.
+pub fn read(path: &Path) -> Result<Json, Vec<ConfigError>> {
+    read_file(path, &mut Vec::new())
+}
+
+/// `including` holds the files being read, to catch include cycles.
+fn read_file(path: &Path, including: &mut Vec<PathBuf>) -> Result<Json, Vec<ConfigError>> {
+    let included = !including.is_empty();
+    let location = |e: ConfigError| if !included {
+        e
+    } else {
+        ConfigError::new(&e.key[..], format!("{}: {}", path.display(), e.message))
+    };
+
+    let mut text = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+                         .map_err(|e| vec![ConfigError::new("", format!("{}: {}", path.display(), e))]));
+    let canonical = try!(fs::canonicalize(path)
+                             .map_err(|e| vec![ConfigError::new("", format!("{}: {}", path.display(), e))]));
+    if including.contains(&canonical) {
+        return Err(vec![ConfigError::new("include", format!("{} is included in a loop", path.display()))]);
+    }
+
+    let is_toml = path.extension().and_then(|e| e.to_str()) == Some("toml");
+    let mut json = if is_toml {
+        try!(parse_toml(&text[..]).map_err(|errors| errors.into_iter().map(|e| location(e)).collect()))
+    } else {
+        try!(validate::parse(&text[..]).map_err(|e| vec![location(e)]))
+    };
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut errors = Vec::new();
+    resolve(&mut json, "", dir, is_toml, &mut errors);
+
+    let mut object = match json {
+        Json::Object(object) => object,
+        // Reported when checking the settings
+        other => return if errors.is_empty() { Ok(other) } else { Err(errors) }
+    };
+    let includes = match object.remove("include") {
+        None => Vec::new(),
+        Some(Json::String(file)) => vec![file],
+        Some(Json::Array(ref files)) if files.iter().all(|f| f.is_string()) =>
+            files.iter().filter_map(|f| f.as_string()).map(|f| f.to_owned()).collect(),
+        Some(other) => {
+            errors.push(location(ConfigError::new("include", format!("expected a file or a list of files, got {}",
+                                                                      other))));
+            Vec::new()
+        }
+    };
+
+    including.push(canonical);
+    for file in includes.iter() {
+        let file = dir.join(file);
+        match read_file(&file, including) {
+            Ok(Json::Object(included)) => merge(&mut object, included, "", &file, &mut errors),
+            Ok(_) => errors.push(ConfigError::new("include", format!("{} must hold settings, as an object",
+                                                                     file.display()))),
+            Err(e) => errors.extend(e.into_iter())
+        }
+    }
+    including.pop();
+
+    if errors.is_empty() {
+        Ok(Json::Object(object))
+    } else {
+        Err(errors)
+    }
+}
+
+fn parse_toml(text: &str) -> Result<Json, Vec<ConfigError>> {
+    let mut parser = toml::Parser::new(text);
+    match parser.parse() {
+        Some(table) => Ok(toml_to_json(toml::Value::Table(table))),
+        None => Err(parser.errors.iter().map(|e| {
+            let (line, col) = parser.to_linecol(e.lo);
+            ConfigError::new("", format!("line {}, column {}: {}", line + 1, col + 1, e.desc))
+        }).collect())
+    }
+}
+
+fn toml_to_json(value: toml::Value) -> Json {
+    match value {
+        toml::Value::String(s) | toml::Value::Datetime(s) => Json::String(s),
+        toml::Value::Integer(n) if n >= 0 => Json::U64(n as u64),
+        toml::Value::Integer(n) => Json::I64(n),
+        toml::Value::Float(n) => Json::F64(n),
+        toml::Value::Boolean(b) => Json::Boolean(b),
+        toml::Value::Array(items) => Json::Array(items.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Json::Object(table.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect())
+    }
+}
+
+fn child_key(key: &str, child: &str) -> String {
+    if key.is_empty() { child.to_owned() } else { format!("{}.{}", key, child) }
+}
+
+/// Adds the settings of an included file.
+fn merge(into: &mut Object, from: Object, key: &str, file: &Path, errors: &mut Vec<ConfigError>) {
+    for (name, value) in from.into_iter() {
+        let full_key = child_key(key, &name[..]);
+        let merged = match (into.remove(&name), value) {
+            (None, value) => value,
+            (Some(Json::Array(mut items)), Json::Array(more)) => {
+                items.extend(more.into_iter());
+                Json::Array(items)
+            }
+            (Some(Json::Object(mut object)), Json::Object(more)) => {
+                merge(&mut object, more, &full_key[..], file, errors);
+                Json::Object(object)
+            }
+            (Some(old), _) => {
+                errors.push(ConfigError::new(&full_key[..], format!("set again in {}", file.display())));
+                old
+            }
+        };
+        into.insert(name, merged);
+    }
+}
+
+/// Reads secret files, relative to `dir`, and substitutes environment variables
+/// if `substitute` is set.
+fn resolve(value: &mut Json, key: &str, dir: &Path, substitute: bool, errors: &mut Vec<ConfigError>) {
+    match *value {
+        Json::String(ref mut s) if substitute => match substitute_env(&s[..]) {
+            Ok(substituted) => *s = substituted,
+            Err(e) => errors.push(ConfigError::new(key, e))
+        },
+        Json::Array(ref mut items) => for (i, item) in items.iter_mut().enumerate() {
+            resolve(item, &format!("{}[{}]", key, i), dir, substitute, errors);
+        },
+        Json::Object(ref mut object) => {
+            for (name, value) in object.iter_mut() {
+                resolve(value, &child_key(key, &name[..])[..], dir, substitute, errors);
+            }
+            for &secret in SECRET_FILES.iter() {
+                let file_setting = format!("{}_file", secret);
+                let file = match object.remove(&file_setting) {
+                    Some(file) => file,
+                    None => continue
+                };
+                let file_key = child_key(key, &file_setting[..]);
+                if object.contains_key(secret) {
+                    errors.push(ConfigError::new(&file_key[..], format!("{} is set as well", secret)));
+                    continue;
+                }
+                match file.as_string().map(|f| read_secret(&dir.join(f))) {
+                    Some(Ok(value)) => { object.insert(secret.to_owned(), Json::String(value)); }
+                    Some(Err(e)) => errors.push(ConfigError::new(&file_key[..], e)),
+                    None => errors.push(ConfigError::new(&file_key[..], format!("expected a file, got {}", file)))
+                }
+            }
+        }
+        _ => ()
+    }
+}
+
+/// Replaces `${NAME}` with environment variables. Values are left out of the
+/// errors, as they may be secrets.
+fn substitute_env(value: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with("$") {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        let end = try!(rest[start..].find('}').map(|end| start + end)
+                                   .ok_or("unterminated ${ (write $${ for a literal ${)".to_owned()));
+        let name = &rest[start + 2..end];
+        match env::var(name) {
+            Ok(var) => result.push_str(&var[..]),
+            Err(_) => return Err(format!("environment variable {} is not set", name))
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn read_secret(path: &Path) -> Result<String, String> {
+    let mut secret = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut secret))
+                         .map_err(|e| format!("{}: {}", path.display(), e)));
+    let secret = secret.trim_right_matches(|c| c == '\n' || c == '\r');
+    if secret.is_empty() {
+        return Err(format!("{} is empty", path.display()));
+    }
+    Ok(secret.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read, substitute_env};
+
+    use rustc_serialize::json::Json;
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn write(dir: &PathBuf, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn env_substitution() {
+        env::set_var("IRCTOOLS_TEST_PASSWORD", "s3cret");
+        assert_eq!(substitute_env("pass ${IRCTOOLS_TEST_PASSWORD}!"), Ok("pass s3cret!".to_string()));
+        assert_eq!(substitute_env("$${IRCTOOLS_TEST_PASSWORD}"), Ok("${IRCTOOLS_TEST_PASSWORD}".to_string()));
+        assert!(substitute_env("${IRCTOOLS_TEST_UNSET}").is_err());
+        assert!(substitute_env("${IRCTOOLS_TEST_PASSWORD").is_err());
+    }
+
+    #[test]
+    fn includes_and_secrets() {
+        let dir = env::temp_dir().join(format!("irctools-conf-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        env::set_var("IRCTOOLS_TEST_HUB", "hub.MindForge.org");
+        write(&dir, "link.pass", "rustp0w3r!\n");
+        write(&dir, "links.toml", r#"
+            # Backup uplink
+            [[links]]
+            name = "Backup.MindForge.org"
+            address = "10.0.0.2"
+            password_file = "link.pass"
+            pass_receive_file = "link.pass"
+        "#);
+        let main = write(&dir, "tools.toml", r#"
+            include = "links.toml"
+            servname = "RustPower.MindForge.org"
+
+            [[links]]
+            name = "${IRCTOOLS_TEST_HUB}"
+            address = "10.0.0.1"
+            port = 6697
+
+            [options.control]
+            admins = ["*!*@staff.MindForge.org"]
+        "#);
+
+        let json = read(&main).ok().unwrap();
+        assert!(json.find("include").is_none());
+        let links = json.find("links").unwrap().as_array().unwrap();
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].find("name").unwrap().as_string(), Some("hub.MindForge.org"));
+        assert_eq!(links[0].find("port"), Some(&Json::U64(6697)));
+        assert_eq!(links[1].find("password").unwrap().as_string(), Some("rustp0w3r!"));
+        assert!(links[1].find("password_file").is_none());
+        assert!(json.find_path(&["options", "control", "admins"]).unwrap().is_array());
+
+        // Settings can't be set twice, files can't include themselves
+        write(&dir, "again.toml", "servname = \"Other.MindForge.org\"\n");
+        write(&dir, "loop.toml", "include = \"loop.toml\"\n");
+        let main = write(&dir, "tools.toml", "include = [\"again.toml\", \"loop.toml\"]\n\
+                                              servname = \"RustPower.MindForge.org\"\n");
+        let keys: Vec<String> = read(&main).err().unwrap().into_iter().map(|e| e.key).collect();
+        assert_eq!(keys, vec!["servname", "include"]);
+
+        // JSON files are not substituted, but get secrets
+        let main = write(&dir, "tools.conf", r#"{
+            "servname": "RustPower.MindForge.org",
+            "password": "pa${ss",
+            "links": [{ "name": "${IRCTOOLS_TEST_HUB}", "password_file": "link.pass" }]
+        }"#);
+        let json = read(&main).ok().unwrap();
+        assert_eq!(json.find("password").unwrap().as_string(), Some("pa${ss"));
+        let links = json.find("links").unwrap().as_array().unwrap();
+        assert_eq!(links[0].find("name").unwrap().as_string(), Some("${IRCTOOLS_TEST_HUB}"));
+        assert_eq!(links[0].find("password").unwrap().as_string(), Some("rustp0w3r!"));
+
+        // Syntax errors are located
+        let main = write(&dir, "tools.toml", "servname = \"RustPower.MindForge.org\"\nnumeric = \n");
+        let errors = read(&main).err().unwrap();
+        assert!(errors[0].message.starts_with("line 2"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
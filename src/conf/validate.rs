@@ -0,0 +1,512 @@
+use super::{Bot, ConfigData, Link};
+use network::irc_lower;
+
+use encoding::label::encoding_from_whatwg_label;
+use rustc_serialize::json::{self, DecoderError, Json, Object, ParserError};
+
+use std::borrow::ToOwned;
+use std::fmt::{self, Display, Formatter};
+use std::net::IpAddr;
+
+/// Longest nick Unreal accepts by default (NICKLEN).
+static MAX_NICK_LEN: usize = 30;
+/// Longest ident (USERLEN).
+static MAX_IDENT_LEN: usize = 10;
+/// Longest hostname (HOSTLEN).
+static MAX_HOST_LEN: usize = 63;
+
+/// A problem found in the configuration.
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    /// The offending setting, such as `numeric` or `links[1].tls`; empty when
+    /// the problem isn't about one setting (unreadable file, syntax error...)
+    pub key: String,
+    pub message: String
+}
+
+impl ConfigError {
+    pub fn new(key: &str, message: String) -> ConfigError {
+        ConfigError { key: key.to_owned(), message: message }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.key, self.message)
+        }
+    }
+}
+
+/// What a top level setting holds.
+#[derive(Clone, Copy)]
+enum Kind {
+    Str,
+    /// Non-negative integer, up to the given value
+    Uint(u64),
+    Bool,
+    Array,
+    Object
+}
+
+static U16: Kind = Kind::Uint(0xffff);
+static U32: Kind = Kind::Uint(0xffffffff);
+static U64: Kind = Kind::Uint(0xffffffffffffffff);
+static USIZE: Kind = Kind::Uint(0xffffffff);
+
+/// Every top level setting, what it holds, and whether it is required. The
+/// cbot_* settings aren't when there are bot blocks.
+static SETTINGS: [(&'static str, Kind, bool); 34] = [
+    ("servname", Kind::Str, true),
+    ("numeric", U16, true),
+    ("description", Kind::Str, true),
+    ("uplink", Kind::Str, false),
+    ("uplinkname", Kind::Str, false),
+    ("port", U16, false),
+    ("password", Kind::Str, false),
+    ("pass_receive", Kind::Str, false),
+    ("use_ssl", Kind::Bool, false),
+    ("tls", Kind::Object, false),
+    ("bind", Kind::Str, false),
+    ("links", Kind::Array, false),
+    ("link_order", Kind::Str, false),
+    ("encoding", Kind::Str, true),
+    ("send_encoding", Kind::Str, false),
+    ("target_encodings", Kind::Object, false),
+    ("cbot_nick", Kind::Str, true),
+    ("cbot_ident", Kind::Str, true),
+    ("cbot_host", Kind::Str, true),
+    ("cbot_gecos", Kind::Str, true),
+    ("cbot_chans", Kind::Array, true),
+    ("bots", Kind::Array, false),
+    ("reconnect_min", U32, false),
+    ("reconnect_max", U32, false),
+    ("ping_freq", U32, false),
+    ("ping_timeout", U32, false),
+    ("connect_timeout", U32, false),
+    ("record_file", Kind::Str, false),
+    ("admin_socket", Kind::Str, false),
+    ("sendq_max", USIZE, false),
+    ("target_rate_lines", U32, false),
+    ("target_rate_period", U32, false),
+    ("logging", Kind::Array, false),
+    ("options", Kind::Object, true)
+];
+
+/// Every setting of a link block.
+static LINK_SETTINGS: [(&'static str, Kind, bool); 8] = [
+    ("name", Kind::Str, true),
+    ("address", Kind::Str, true),
+    ("port", U16, false),
+    ("password", Kind::Str, true),
+    ("pass_receive", Kind::Str, true),
+    ("use_ssl", Kind::Bool, true),
+    ("tls", Kind::Object, false),
+    ("bind", Kind::Str, false)
+];
+
+/// Every setting of a bot block.
+static BOT_SETTINGS: [(&'static str, Kind, bool); 5] = [
+    ("nick", Kind::Str, true),
+    ("ident", Kind::Str, true),
+    ("host", Kind::Str, true),
+    ("gecos", Kind::Str, true),
+    ("chans", Kind::Array, true)
+];
+
+/// Every setting of a `tls` block.
+static TLS_SETTINGS: [(&'static str, Kind, bool); 7] = [
+    ("min_version", Kind::Str, false),
+    ("verify", Kind::Bool, false),
+    ("ca_file", Kind::Str, false),
+    ("fingerprint", Kind::Str, false),
+    ("hostname", Kind::Str, false),
+    ("cert_file", Kind::Str, false),
+    ("key_file", Kind::Str, false)
+];
+
+/// Every setting of a logging output.
+static LOGGING_SETTINGS: [(&'static str, Kind, bool); 7] = [
+    ("sink", Kind::Str, true),
+    ("level", Kind::Str, false),
+    ("categories", Kind::Array, false),
+    ("path", Kind::Str, false),
+    ("max_size", U64, false),
+    ("keep", U32, false),
+    ("channel", Kind::Str, false)
+];
+
+/// Parses the file, reporting syntax errors with their position.
+pub fn parse(text: &str) -> Result<Json, ConfigError> {
+    Json::from_str(text).map_err(|e| match e {
+        ParserError::SyntaxError(code, line, col) =>
+            ConfigError::new("", format!("line {}, column {}: {}", line, col, json::error_str(code))),
+        ParserError::IoError(e) => ConfigError::new("", format!("{}", e))
+    })
+}
+
+/// Checks the settings that are there, and that nothing is missing, before
+/// decoding; the decoder itself stops at the first problem without saying where.
+/// Link blocks, bot blocks, `tls` blocks and logging outputs are checked the
+/// same way.
+pub fn check_json(json: &Json) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    let object = match json.as_object() {
+        Some(object) => object,
+        None => return vec![ConfigError::new("", "the configuration must be a JSON object".to_owned())]
+    };
+    let has_bots = object.contains_key("bots");
+    let settings: Vec<(&'static str, Kind, bool)> = SETTINGS.iter().map(
+        |&(key, kind, required)| (key, kind, required && !(has_bots && key.starts_with("cbot_")))).collect();
+    check_object(object, &settings[..], "", &mut errors);
+    if has_bots {
+        for &(key, _, _) in SETTINGS.iter().filter(|&&(key, _, _)| key.starts_with("cbot_")) {
+            if object.contains_key(key) {
+                errors.push(ConfigError::new(key, "can't be set along with bots".to_owned()));
+            }
+        }
+    }
+
+    if let Some(&Json::Object(ref tls)) = object.get("tls") {
+        check_object(tls, &TLS_SETTINGS, "tls", &mut errors);
+    }
+    if let Some(&Json::Array(ref links)) = object.get("links") {
+        for (i, link) in links.iter().enumerate() {
+            let key = format!("links[{}]", i);
+            if let Some(link) = check_block(link, &key[..], &mut errors) {
+                check_object(link, &LINK_SETTINGS, &key[..], &mut errors);
+                if let Some(&Json::Object(ref tls)) = link.get("tls") {
+                    check_object(tls, &TLS_SETTINGS, &format!("{}.tls", key)[..], &mut errors);
+                }
+            }
+        }
+    }
+    if let Some(&Json::Array(ref outputs)) = object.get("logging") {
+        for (i, output) in outputs.iter().enumerate() {
+            let key = format!("logging[{}]", i);
+            if let Some(output) = check_block(output, &key[..], &mut errors) {
+                check_object(output, &LOGGING_SETTINGS, &key[..], &mut errors);
+                if let Some(&Json::Array(ref categories)) = output.get("categories") {
+                    check_items(categories, Kind::Str, &format!("{}.categories", key)[..], &mut errors);
+                }
+            }
+        }
+    }
+    if let Some(&Json::Array(ref bots)) = object.get("bots") {
+        for (i, bot) in bots.iter().enumerate() {
+            let key = format!("bots[{}]", i);
+            if let Some(bot) = check_block(bot, &key[..], &mut errors) {
+                check_object(bot, &BOT_SETTINGS, &key[..], &mut errors);
+                if let Some(&Json::Array(ref chans)) = bot.get("chans") {
+                    check_items(chans, Kind::Str, &format!("{}.chans", key)[..], &mut errors);
+                }
+            }
+        }
+    }
+    if let Some(&Json::Array(ref chans)) = object.get("cbot_chans") {
+        check_items(chans, Kind::Str, "cbot_chans", &mut errors);
+    }
+    if let Some(&Json::Object(ref targets)) = object.get("target_encodings") {
+        for (target, label) in targets.iter() {
+            if let Err(e) = check_kind(label, Kind::Str) {
+                errors.push(ConfigError::new(&format!("target_encodings.{}", target)[..], e));
+            }
+        }
+    }
+    errors
+}
+
+/// Checks the settings of one object, `prefix` being where it is.
+fn check_object(object: &Object, settings: &[(&'static str, Kind, bool)], prefix: &str,
+                errors: &mut Vec<ConfigError>) {
+    let path = |key: &str| if prefix.is_empty() { key.to_owned() } else { format!("{}.{}", prefix, key) };
+    for &(key, kind, required) in settings.iter() {
+        match object.get(key) {
+            None | Some(&Json::Null) if required =>
+                errors.push(ConfigError::new(&path(key)[..], "missing".to_owned())),
+            None | Some(&Json::Null) => (),
+            Some(value) => if let Err(e) = check_kind(value, kind) {
+                errors.push(ConfigError::new(&path(key)[..], e));
+            }
+        }
+    }
+    for key in object.keys() {
+        if !settings.iter().any(|&(k, _, _)| k == &key[..]) {
+            errors.push(ConfigError::new(&path(&key[..])[..], "unknown setting".to_owned()));
+        }
+    }
+}
+
+/// An entry of a list of blocks, such as `links`, which must be an object.
+fn check_block<'a>(value: &'a Json, key: &str, errors: &mut Vec<ConfigError>) -> Option<&'a Object> {
+    match check_kind(value, Kind::Object) {
+        Ok(_) => value.as_object(),
+        Err(e) => { errors.push(ConfigError::new(key, e)); None }
+    }
+}
+
+fn check_items(items: &[Json], kind: Kind, key: &str, errors: &mut Vec<ConfigError>) {
+    for (i, item) in items.iter().enumerate() {
+        if let Err(e) = check_kind(item, kind) {
+            errors.push(ConfigError::new(&format!("{}[{}]", key, i)[..], e));
+        }
+    }
+}
+
+fn check_kind(value: &Json, kind: Kind) -> Result<(), String> {
+    let ok = match (kind, value) {
+        (Kind::Str, &Json::String(_)) | (Kind::Bool, &Json::Boolean(_)) |
+        (Kind::Array, &Json::Array(_)) | (Kind::Object, &Json::Object(_)) => true,
+        (Kind::Uint(max), &Json::U64(n)) if n > max =>
+            return Err(format!("{} is too large, the maximum is {}", n, max)),
+        (Kind::Uint(_), &Json::U64(_)) => true,
+        _ => false
+    };
+    if ok {
+        Ok(())
+    } else {
+        let expected = match kind {
+            Kind::Str => "a string",
+            Kind::Uint(_) => "a positive integer",
+            Kind::Bool => "true or false",
+            Kind::Array => "a list",
+            Kind::Object => "an object"
+        };
+        Err(format!("expected {}, got {}", expected, value))
+    }
+}
+
+/// Describes what the decoder didn't like. Everything it decodes was checked
+/// already, so this is a last resort.
+pub fn decoder_error(e: DecoderError) -> ConfigError {
+    match e {
+        DecoderError::MissingFieldError(field) => ConfigError::new(&field[..], "missing".to_owned()),
+        DecoderError::ExpectedError(expected, found) =>
+            ConfigError::new("", format!("expected {}, got {}", expected, found)),
+        e => ConfigError::new("", format!("{:?}", e))
+    }
+}
+
+/// Checks the values of the settings. `legacy` tells whether the link came from
+/// the top level settings rather than a `links` list, to name them properly;
+/// `legacy_bot` does the same for the bot and the cbot_* settings.
+pub fn check_data(data: &ConfigData, links: &[Link], legacy: bool, bots: &[Bot], legacy_bot: bool)
+                  -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    {
+        let mut error = |key: &str, message: String| errors.push(ConfigError::new(key, message));
+
+        if data.servname.is_empty() {
+            error("servname", "must not be empty".to_owned());
+        } else if !data.servname.contains('.') || !is_valid_host(&data.servname[..]) {
+            error("servname", format!("'{}' is not a valid server name", data.servname));
+        }
+        if data.numeric < 1 || data.numeric > 254 {
+            error("numeric", format!("{} is out of range, Unreal accepts 1 to 254", data.numeric));
+        }
+        if data.description.is_empty() {
+            error("description", "must not be empty".to_owned());
+        }
+
+        if bots.is_empty() {
+            error("bots", "no bot configured, either 'bots' or the cbot_* settings must be set".to_owned());
+        }
+        for (i, bot) in bots.iter().enumerate() {
+            let key = |field: &str| if legacy_bot {
+                format!("cbot_{}", field)
+            } else {
+                format!("bots[{}].{}", i, field)
+            };
+            if !is_valid_nick(&bot.nick[..]) {
+                error(&key("nick"), format!("'{}' is not a nick the IRCd accepts", bot.nick));
+            } else if bots[..i].iter().any(|b| irc_lower(&b.nick[..]) == irc_lower(&bot.nick[..])) {
+                error(&key("nick"), format!("'{}' is used by another bot", bot.nick));
+            }
+            if bot.ident.is_empty() || bot.ident.len() > MAX_IDENT_LEN ||
+               bot.ident.contains(|c: char| c == ' ' || c == '@' || c == '!') {
+                error(&key("ident"), format!("'{}' is not a valid ident", bot.ident));
+            }
+            if !is_valid_host(&bot.host[..]) {
+                error(&key("host"), format!("'{}' is not a valid hostname", bot.host));
+            }
+            for (j, chan) in bot.chans.iter().enumerate() {
+                if !is_valid_channel(&chan[..]) {
+                    error(&format!("{}[{}]", key("chans"), j), format!("'{}' is not a channel name", chan));
+                }
+            }
+        }
+
+        if encoding_from_whatwg_label(&data.encoding[..]).is_none() {
+            error("encoding", format!("unknown encoding '{}'", data.encoding));
+        }
+        if let Some(ref label) = data.send_encoding {
+            if encoding_from_whatwg_label(&label[..]).is_none() {
+                error("send_encoding", format!("unknown encoding '{}'", label));
+            }
+        }
+        for (target, label) in data.target_encodings.iter().flat_map(|t| t.iter()) {
+            if encoding_from_whatwg_label(&label[..]).is_none() {
+                error(&format!("target_encodings.{}", target), format!("unknown encoding '{}'", label));
+            }
+        }
+
+        if links.is_empty() {
+            error("links", "no uplink configured, either 'links' or 'uplink' and 'uplinkname' must be set"
+                           .to_owned());
+        }
+        for (i, link) in links.iter().enumerate() {
+            let key = |field: &str| if legacy {
+                match field {
+                    "name" => "uplinkname".to_owned(),
+                    "address" => "uplink".to_owned(),
+                    _ => field.to_owned()
+                }
+            } else {
+                format!("links[{}].{}", i, field)
+            };
+            if link.name.is_empty() {
+                error(&key("name"), "must not be empty".to_owned());
+            }
+            if link.address.is_empty() {
+                error(&key("address"), "must not be empty".to_owned());
+            }
+            if link.port == Some(0) {
+                error(&key("port"), "must not be 0".to_owned());
+            }
+            if let Some(ref bind) = link.bind {
+                if bind.parse::<IpAddr>().is_err() {
+                    error(&key("bind"), format!("'{}' is not an IP address", bind));
+                }
+            }
+            if let Some(ref tls) = link.tls {
+                if let Err(e) = tls.min_version() {
+                    error(&key("tls.min_version"), e);
+                }
+                if let Err(e) = tls.fingerprint() {
+                    error(&key("tls.fingerprint"), e);
+                }
+                if tls.cert_file.is_some() != tls.key_file.is_some() {
+                    error(&key("tls"), "cert_file and key_file must be set together".to_owned());
+                }
+            }
+        }
+
+        match data.link_order.as_ref().map(|o| &o[..]) {
+            None | Some("priority") | Some("roundrobin") => (),
+            Some(other) => error("link_order", format!("expected 'priority' or 'roundrobin', got '{}'", other))
+        }
+        if data.reconnect_min.unwrap_or(5) > data.reconnect_max.unwrap_or(300) {
+            error("reconnect_min", "must not be greater than reconnect_max".to_owned());
+        }
+        for &(key, value) in [("ping_freq", data.ping_freq), ("ping_timeout", data.ping_timeout),
+                              ("connect_timeout", data.connect_timeout),
+                              ("target_rate_lines", data.target_rate_lines),
+                              ("target_rate_period", data.target_rate_period)].iter() {
+            if value == Some(0) {
+                error(key, "must not be 0".to_owned());
+            }
+        }
+        for (i, output) in data.logging.iter().flat_map(|l| l.iter()).enumerate() {
+            if let Err(e) = output.validate() {
+                error(&format!("logging[{}]", i), e);
+            }
+        }
+    }
+    errors
+}
+
+/// RFC 1459 nicks, within Unreal's default length.
+pub fn is_valid_nick(nick: &str) -> bool {
+    let special = |c: char| "[]\\`_^{|}".contains(c);
+    match nick.chars().next() {
+        Some(c) if is_ascii_alpha(c) || special(c) => (),
+        _ => return false
+    }
+    nick.len() <= MAX_NICK_LEN &&
+        nick.chars().all(|c| is_ascii_alnum(c) || special(c) || c == '-')
+}
+
+fn is_ascii_alpha(c: char) -> bool {
+    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z')
+}
+
+fn is_ascii_alnum(c: char) -> bool {
+    is_ascii_alpha(c) || (c >= '0' && c <= '9')
+}
+
+fn is_valid_host(host: &str) -> bool {
+    !host.is_empty() && host.len() <= MAX_HOST_LEN &&
+        host.chars().all(|c| is_ascii_alnum(c) || c == '.' || c == '-' || c == ':') &&
+        !host.starts_with(|c: char| c == '.' || c == '-' || c == ':')
+}
+
+fn is_valid_channel(chan: &str) -> bool {
+    chan.starts_with('#') && chan.len() > 1 &&
+        !chan.contains(|c: char| c == ' ' || c == ',' || c == '\x07')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConfigError, check_json, is_valid_nick, parse};
+
+    #[test]
+    fn json_errors() {
+        let err = parse("{\n  \"servname\": \"a.b\",\n  \"numeric\" 201\n}").err().unwrap();
+        assert_eq!(err.key, "");
+        assert!(err.message.starts_with("line 3, column"));
+
+        let json = parse(r#"{ "servname": "a.b", "numeric": 300, "description": 5, "encoding": "utf-8",
+                              "cbot_nick": "T", "cbot_ident": "t", "cbot_host": "a.b", "cbot_gecos": "g",
+                              "cbot_chan": ["#x"], "options": {} }"#).ok().unwrap();
+        let errors: Vec<String> = check_json(&json).iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec!["description: expected a string, got 5".to_string(),
+                                "cbot_chans: missing".to_string(),
+                                "cbot_chan: unknown setting".to_string()]);
+        // Fits in a u16, Unreal's range is checked later
+        assert!(!errors.iter().any(|e| e.starts_with("numeric")));
+
+        // Blocks are checked with their path
+        let json = parse(r#"{ "servname": "a.b", "numeric": 201, "description": "d", "encoding": "utf-8",
+                              "cbot_nick": "T", "cbot_ident": "t", "cbot_host": "a.b", "cbot_gecos": "g",
+                              "cbot_chans": ["#x", 5], "options": {}, "tls": { "verify": "yes" },
+                              "links": [{ "name": "hub", "address": "10.0.0.1", "password": "p",
+                                          "pass_receive": "p", "use_ssl": true },
+                                        { "name": "hub2", "address": "10.0.0.2", "port": "6697",
+                                          "password": "p", "pass_receive": "p", "use_ssl": true,
+                                          "tls": { "fingerprints": "ab" } },
+                                        "hub3"],
+                              "logging": [{ "sink": "file", "keep": -1, "categories": ["link", 1] },
+                                          { "level": "info" }] }"#).ok().unwrap();
+        let errors: Vec<String> = check_json(&json).iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec!["tls.verify: expected true or false, got \"yes\"".to_string(),
+                                "links[1].port: expected a positive integer, got \"6697\"".to_string(),
+                                "links[1].tls.fingerprints: unknown setting".to_string(),
+                                "links[2]: expected an object, got \"hub3\"".to_string(),
+                                "logging[0].keep: expected a positive integer, got -1".to_string(),
+                                "logging[0].categories[1]: expected a string, got 1".to_string(),
+                                "logging[1].sink: missing".to_string(),
+                                "cbot_chans[1]: expected a string, got 5".to_string()]);
+
+        // Bot blocks replace the cbot_* settings
+        let json = parse(r#"{ "servname": "a.b", "numeric": 201, "description": "d", "encoding": "utf-8",
+                              "cbot_nick": "T", "options": {},
+                              "bots": [{ "nick": "T", "ident": "t", "host": "a.b", "gecos": "g", "chans": [] },
+                                       { "nick": "G", "ident": "g", "host": "a.b", "chans": ["#x"] }] }"#)
+                       .ok().unwrap();
+        let errors: Vec<String> = check_json(&json).iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec!["cbot_nick: can't be set along with bots".to_string(),
+                                "bots[1].gecos: missing".to_string()]);
+    }
+
+    #[test]
+    fn nicks() {
+        assert!(is_valid_nick("MFTooL[dev]"));
+        assert!(is_valid_nick("^Tool-2"));
+        assert!(!is_valid_nick("2Tool"));
+        assert!(!is_valid_nick("Tool!"));
+        assert!(!is_valid_nick(""));
+        assert_eq!(ConfigError::new("numeric", "out of range".to_string()).to_string(), "numeric: out of range");
+    }
+}
@@ -0,0 +1,117 @@
+use libc;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Detaches from the terminal: forks twice so that we are neither a process
+/// group leader nor able to get a controlling terminal back, and points the
+/// standard streams at /dev/null. The working directory is kept, so that
+/// relative paths in the configuration keep working on reload.
+pub fn daemonize() -> Result<()> {
+    unsafe {
+        try!(fork_and_leave());
+        if libc::setsid() == -1 {
+            return Err(Error::last_os_error());
+        }
+        try!(fork_and_leave());
+        libc::umask(0o027);
+
+        let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+        if null == -1 {
+            return Err(Error::last_os_error());
+        }
+        for fd in 0..3 {
+            if libc::dup2(null, fd) == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        if null > 2 {
+            libc::close(null);
+        }
+    }
+    Ok(())
+}
+
+/// Forks; the parent exits right away and the child goes on.
+unsafe fn fork_and_leave() -> Result<()> {
+    match libc::fork() {
+        -1 => Err(Error::last_os_error()),
+        0 => Ok(()),
+        _ => libc::_exit(0)
+    }
+}
+
+/// A file holding our process ID, removed when dropped. Refuses to replace the
+/// file of an instance that is still running, but takes over stale ones.
+pub struct PidFile {
+    path: PathBuf
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> Result<PidFile> {
+        if let Some(pid) = try!(read_pid(path)) {
+            if is_running(pid) {
+                return Err(Error::new(ErrorKind::AlreadyExists, "Already running.",
+                                      Some(format!("{} holds PID {}", path.display(), pid))));
+            }
+            try!(fs::remove_file(path));
+        }
+        // create_new so that two instances starting at once can't both succeed
+        let mut file = try!(OpenOptions::new().write(true).create_new(true).open(path));
+        try!(write!(file, "{}\n", process::id()));
+        Ok(PidFile { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The PID in an existing PID file. Garbage counts as a stale file.
+fn read_pid(path: &Path) -> Result<Option<i32>> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => try!(file.read_to_string(&mut contents)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    Ok(Some(contents.trim().parse().unwrap_or(0)))
+}
+
+fn is_running(pid: i32) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    // Signal 0 only checks that the process exists; EPERM means it does, as someone else
+    unsafe { libc::kill(pid, 0) == 0 || Error::last_os_error().raw_os_error() == Some(libc::EPERM) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PidFile;
+
+    use std::env;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::process;
+
+    #[test]
+    fn stale_detection() {
+        let path = env::temp_dir().join(format!("irctools-test-{}.pid", process::id()));
+        // Nothing runs with PID 0x7ffffffe
+        File::create(&path).unwrap().write_all(b"2147483646\n").unwrap();
+        let pidfile = PidFile::create(&path).unwrap();
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, format!("{}\n", process::id()));
+
+        // We are running, so a second instance is refused
+        assert!(PidFile::create(&path).is_err());
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
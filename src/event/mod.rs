@@ -0,0 +1,95 @@
+pub mod signal;
+
+use libc;
+
+use std::io::{Error, Result};
+use std::os::unix::io::RawFd;
+
+/// The set of file descriptors the main loop waits on, rebuilt on every
+/// iteration. Registering returns a token to check readiness with once `wait`
+/// returns.
+pub struct Poll {
+    fds: Vec<libc::pollfd>
+}
+
+/// Identifies a registered file descriptor. Sources without a file descriptor
+/// (in-memory transports) get a token that is always ready.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Token {
+    Fd(usize),
+    Always
+}
+
+impl Poll {
+    pub fn new() -> Poll {
+        Poll { fds: Vec::new() }
+    }
+
+    pub fn clear(&mut self) {
+        self.fds.clear();
+    }
+
+    /// Waits for `fd` to become readable.
+    pub fn register(&mut self, fd: Option<RawFd>) -> Token {
+        match fd {
+            Some(fd) => {
+                self.fds.push(libc::pollfd { fd: fd, events: libc::POLLIN, revents: 0 });
+                Token::Fd(self.fds.len() - 1)
+            }
+            None => Token::Always
+        }
+    }
+
+    /// Blocks until one of the registered file descriptors is readable, or for
+    /// `timeout_ms` milliseconds at most. Returns how many are ready.
+    pub fn wait(&mut self, timeout_ms: u64) -> Result<usize> {
+        let timeout = if timeout_ms > i32::max_value() as u64 { i32::max_value() } else { timeout_ms as i32 };
+        let ready = unsafe {
+            libc::poll(self.fds.as_mut_ptr(), self.fds.len() as libc::nfds_t, timeout)
+        };
+        if ready < 0 {
+            let err = Error::last_os_error();
+            // A signal interrupting us isn't an error, just a spurious wakeup
+            if err.raw_os_error() == Some(libc::EINTR) {
+                return Ok(0);
+            }
+            return Err(err);
+        }
+        Ok(ready as usize)
+    }
+
+    /// Whether the source behind `token` can be read from (or hung up, in which
+    /// case reading it reports the error).
+    pub fn is_ready(&self, token: Token) -> bool {
+        match token {
+            Token::Fd(index) => self.fds[index].revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0,
+            Token::Always => true
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Poll, Token};
+
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn readiness() {
+        let (mut a, b) = UnixStream::pair().unwrap();
+        let mut poll = Poll::new();
+        let token = poll.register(Some(b.as_raw_fd()));
+        let always = poll.register(None);
+        assert_eq!(always, Token::Always);
+
+        assert_eq!(poll.wait(0).unwrap(), 0);
+        assert!(!poll.is_ready(token));
+        assert!(poll.is_ready(always));
+
+        a.write_all(b"PING\r\n").unwrap();
+        assert_eq!(poll.wait(1000).unwrap(), 1);
+        assert!(poll.is_ready(token));
+    }
+}
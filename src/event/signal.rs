@@ -0,0 +1,112 @@
+use libc;
+
+use std::io::{Error, Result};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_ISIZE_INIT};
+
+static TERMINATE: AtomicBool = ATOMIC_BOOL_INIT;
+static RELOAD: AtomicBool = ATOMIC_BOOL_INIT;
+/// Both ends of the self-pipe, written to by the handlers
+static WAKEUP_READ: AtomicIsize = ATOMIC_ISIZE_INIT;
+static WAKEUP_WRITE: AtomicIsize = ATOMIC_ISIZE_INIT;
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    match signal {
+        libc::SIGTERM | libc::SIGINT => TERMINATE.store(true, Ordering::SeqCst),
+        libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        _ => return
+    }
+    // After the flag: whoever drains the pipe then sees it. A full pipe already
+    // wakes the main loop up, so failing to write is fine.
+    let fd = WAKEUP_WRITE.load(Ordering::SeqCst) as libc::c_int;
+    unsafe { libc::write(fd, b"!".as_ptr() as *const libc::c_void, 1); }
+}
+
+/// Catches SIGTERM and SIGINT (shut down) and SIGHUP (reload the configuration).
+/// The handlers raise flags for the main loop to check, and write to a pipe it
+/// polls (see `raw_fd`): a signal arriving just before `Poll::wait` still
+/// wakes it up right away.
+pub fn install() -> Result<()> {
+    unsafe {
+        let mut fds = [0; 2];
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(Error::last_os_error());
+        }
+        for &fd in fds.iter() {
+            if libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) != 0 ||
+               libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        WAKEUP_READ.store(fds[0] as isize, Ordering::SeqCst);
+        WAKEUP_WRITE.store(fds[1] as isize, Ordering::SeqCst);
+
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_signal as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        for &signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP].iter() {
+            if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read end of the self-pipe, for the main loop to poll along with the rest.
+pub fn raw_fd() -> Option<RawFd> {
+    match WAKEUP_READ.load(Ordering::SeqCst) {
+        0 => None,
+        fd => Some(fd as RawFd)
+    }
+}
+
+/// Empties the self-pipe. Call it before checking the flags, so that a signal
+/// coming in between leaves a byte behind rather than going unnoticed.
+pub fn clear_wakeups() {
+    let fd = match raw_fd() {
+        Some(fd) => fd,
+        None => return
+    };
+    let mut buf = [0u8; 64];
+    while unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+}
+
+/// Whether a shutdown was requested since the last call.
+pub fn take_terminate() -> bool {
+    TERMINATE.swap(false, Ordering::SeqCst)
+}
+
+/// Whether a reload was requested since the last call.
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{clear_wakeups, install, raw_fd, take_reload, take_terminate};
+    use event::Poll;
+    use libc;
+
+    #[test]
+    fn flags() {
+        install().unwrap();
+        assert!(!take_reload());
+        unsafe { libc::raise(libc::SIGHUP); }
+        assert!(take_reload());
+        assert!(!take_reload());
+        assert!(!take_terminate());
+
+        // A signal arriving before the wait still wakes it up
+        let mut poll = Poll::new();
+        let token = poll.register(raw_fd());
+        unsafe { libc::raise(libc::SIGHUP); }
+        assert_eq!(poll.wait(10000).unwrap(), 1);
+        assert!(poll.is_ready(token));
+        clear_wakeups();
+        assert!(take_reload());
+        assert_eq!(poll.wait(0).unwrap(), 0);
+    }
+}
@@ -0,0 +1,42 @@
+use std::cmp;
+
+/// Exponential backoff between reconnection attempts: the delay starts at `min`
+/// seconds and doubles after every failed attempt, up to `max` seconds.
+pub struct Backoff {
+    min: u32,
+    max: u32,
+    current: u32
+}
+
+impl Backoff {
+    pub fn new(min: u32, max: u32) -> Backoff {
+        let min = cmp::max(min, 1);
+        Backoff { min: min, max: cmp::max(min, max), current: min }
+    }
+
+    /// Returns how long to wait before the next attempt, in seconds.
+    pub fn next_delay(&mut self) -> u32 {
+        let delay = self.current;
+        self.current = cmp::min(self.current.saturating_mul(2), self.max);
+        delay
+    }
+
+    /// Starts over from the minimum delay. Called once a link is established.
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Backoff;
+
+    #[test]
+    fn doubles_up_to_max() {
+        let mut backoff = Backoff::new(5, 60);
+        let delays: Vec<u32> = (0..6).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays, vec![5, 10, 20, 40, 60, 60]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), 5);
+    }
+}
@@ -0,0 +1,135 @@
+use conf::Config;
+use network::irc_lower;
+
+use encoding::{DecoderTrap, EncoderTrap, Encoding, EncodingRef};
+use encoding::label::encoding_from_whatwg_label;
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::str;
+
+/// Character sets used on the link. IRC has no way to tell what encoding a
+/// line is in, so incoming lines are taken as UTF-8 when they are valid UTF-8,
+/// and as the configured legacy charset otherwise. Outgoing lines use the send
+/// encoding, unless their target (a channel or nick) has one of its own.
+pub struct Charsets {
+    fallback: EncodingRef,
+    send: EncodingRef,
+    targets: HashMap<String, EncodingRef>
+}
+
+impl Charsets {
+    pub fn from_config(conf: &Config) -> Result<Charsets> {
+        let mut targets = HashMap::new();
+        for &(target, label) in conf.get_target_encodings().iter() {
+            targets.insert(irc_lower(target), try!(lookup(label)));
+        }
+        Ok(Charsets { fallback: try!(lookup(conf.get_encoding())),
+                      send: try!(lookup(conf.get_send_encoding())),
+                      targets: targets })
+    }
+
+    /// Decodes a raw line into `out`. Returns whether it was valid UTF-8.
+    pub fn decode(&self, raw: &[u8], out: &mut String) -> bool {
+        out.clear();
+        match str::from_utf8(raw) {
+            Ok(line) => { out.push_str(line); true }
+            Err(_) => {
+                // With DecoderTrap::Replace, decoding can't fail
+                let _ = self.fallback.decode_to(raw, DecoderTrap::Replace, out);
+                false
+            }
+        }
+    }
+
+    /// Encodes an outgoing line for `target`.
+    pub fn encode(&self, line: &str, target: Option<&str>) -> Vec<u8> {
+        let encoding = target.and_then(|t| self.targets.get(&irc_lower(t)))
+                             .map(|e| *e).unwrap_or(self.send);
+        // Characters the charset lacks become '?'
+        encoding.encode(line, EncoderTrap::Replace).unwrap_or(line.as_bytes().to_vec())
+    }
+}
+
+fn lookup(label: &str) -> Result<EncodingRef> {
+    encoding_from_whatwg_label(label).ok_or(
+        Error::new(ErrorKind::InvalidInput, "Unknown encoding.", Some(label.to_string())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Charsets;
+    use encoding::all::{ISO_8859_15, UTF_8, WINDOWS_1251};
+    use std::collections::HashMap;
+
+    fn charsets() -> Charsets {
+        let mut targets = HashMap::new();
+        targets.insert("#russian".to_string(), WINDOWS_1251 as ::encoding::EncodingRef);
+        Charsets { fallback: ISO_8859_15, send: UTF_8, targets: targets }
+    }
+
+    #[test]
+    fn hybrid_decoding() {
+        let charsets = charsets();
+        let mut line = String::new();
+        assert!(charsets.decode(b"NICK Ren\xc3\xa9\r\n", &mut line));
+        assert_eq!(line, "NICK René\r\n");
+        assert!(!charsets.decode(b"NICK Ren\xe9\r\n", &mut line));
+        assert_eq!(line, "NICK René\r\n");
+        assert!(!charsets.decode(b"PRIVMSG #chan :\xa4\r\n", &mut line));
+        assert_eq!(line, "PRIVMSG #chan :€\r\n");
+    }
+
+    #[test]
+    fn per_target_encoding() {
+        let charsets = charsets();
+        assert_eq!(charsets.encode("é", None), b"\xc3\xa9".to_vec());
+        assert_eq!(charsets.encode("Привет", Some("#Russian")), b"\xcf\xf0\xe8\xe2\xe5\xf2".to_vec());
+        assert_eq!(charsets.encode("é", Some("#Russian")), b"?".to_vec());
+    }
+}
+
+#[cfg(test)]
+mod bench {
+    use super::{Charsets, lookup};
+    use cmd::bench::burst;
+    use std::collections::HashMap;
+    use test::Bencher;
+
+    fn lines(burst: &str) -> Vec<&[u8]> {
+        burst.as_bytes().split(|&b| b == b'\n').filter(|l| l.len() > 0).collect()
+    }
+
+    /// What read_line used to do: resolve the charset label for every line.
+    #[bench]
+    fn burst_lookup_per_line(b: &mut Bencher) {
+        let burst = burst();
+        let lines = lines(&burst[..]);
+        let mut out = String::new();
+        b.bytes = burst.len() as u64;
+        b.iter(|| {
+            for line in lines.iter() {
+                let charsets = Charsets { fallback: lookup("iso8859-15").unwrap(),
+                                          send: lookup("iso8859-15").unwrap(),
+                                          targets: HashMap::new() };
+                charsets.decode(line, &mut out);
+            }
+        });
+    }
+
+    #[bench]
+    fn burst_cached(b: &mut Bencher) {
+        let burst = burst();
+        let lines = lines(&burst[..]);
+        let mut out = String::new();
+        let charsets = Charsets { fallback: lookup("iso8859-15").unwrap(),
+                                  send: lookup("iso8859-15").unwrap(),
+                                  targets: HashMap::new() };
+        b.bytes = burst.len() as u64;
+        b.iter(|| {
+            for line in lines.iter() {
+                charsets.decode(line, &mut out);
+            }
+        });
+    }
+}
@@ -1,39 +1,90 @@
 
-use std::net::TcpStream;
-use std::io::{Read, Write};
-use std::io::Result;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
 
-#[cfg(feature = "ssl")] use openssl::ssl::SslStream;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
-pub enum NetStream {
-    PlainNetStream(TcpStream),
-
-    #[cfg(feature = "ssl")]
-    SecureNetStream(SslStream<TcpStream>)
+/// Resolves an uplink address: a hostname, or an IPv4 or IPv6 literal (with or
+/// without brackets). Addresses are returned in resolver order.
+pub fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let host = host.trim_left_matches('[').trim_right_matches(']');
+    let addrs: Vec<SocketAddr> = try!((host, port).to_socket_addrs()).collect();
+    if addrs.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, "Uplink address didn't resolve.",
+                              Some(host.to_string())));
+    }
+    Ok(addrs)
 }
 
-impl Read for NetStream {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        match self {
-            &mut NetStream::PlainNetStream(ref mut stream) => stream.read(buf),
-            #[cfg(feature = "ssl")]
-            &mut NetStream::SecureNetStream(ref mut stream) => stream.read(buf),
+/// Connects to each address of `host` in turn until one accepts, optionally
+/// from a local `bind` address. When binding, addresses of the other family
+/// are skipped.
+pub fn open_socket(host: &str, port: u16, bind: Option<IpAddr>, timeout: Duration)
+                   -> Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = try!(resolve(host, port)).into_iter()
+        .filter(|a| bind.map_or(true, |b| same_family(a, &b)))
+        .collect();
+    let mut last_err = Error::new(ErrorKind::NotFound,
+                                  "No uplink address of the same family as the bind address.",
+                                  Some(format!("{} (bind {})", host,
+                                               bind.map_or(String::new(), |b| b.to_string()))));
+    for addr in addrs.iter() {
+        match connect_addr(addr, bind, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                warn!(Link, "Failed to connect to {}: {}", addr, e);
+                last_err = e;
+            }
         }
     }
+    Err(last_err)
 }
-impl Write for NetStream {
-    fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        match self {
-            &mut NetStream::PlainNetStream(ref mut stream) => stream.write(buf),
-            #[cfg(feature = "ssl")]
-            &mut NetStream::SecureNetStream(ref mut stream) => stream.write(buf),
-        }
+
+fn connect_addr(addr: &SocketAddr, bind: Option<IpAddr>, timeout: Duration) -> Result<TcpStream> {
+    let domain = match *addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6()
+    };
+    let socket = try!(Socket::new(domain, Type::stream(), Some(Protocol::tcp())));
+    if let Some(ip) = bind {
+        try!(socket.bind(&SockAddr::from(SocketAddr::new(ip, 0))));
     }
-    fn flush(&mut self) -> Result<()> {
-        match self {
-            &mut NetStream::PlainNetStream(ref mut stream) => stream.flush(),
-            #[cfg(feature = "ssl")]
-            &mut NetStream::SecureNetStream(ref mut stream) => stream.flush(),
-        }
+    try!(socket.connect_timeout(&SockAddr::from(*addr), timeout));
+    Ok(socket.into_tcp_stream())
+}
+
+fn same_family(addr: &SocketAddr, ip: &IpAddr) -> bool {
+    match (*addr, *ip) {
+        (SocketAddr::V4(_), IpAddr::V4(_)) | (SocketAddr::V6(_), IpAddr::V6(_)) => true,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{open_socket, resolve};
+
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::Duration;
+
+    #[test]
+    fn literals() {
+        let v6: SocketAddr = "[2001:db8::1]:6697".parse().unwrap();
+        assert_eq!(resolve("2001:db8::1", 6697).ok(), Some(vec![v6]));
+        assert_eq!(resolve("[2001:db8::1]", 6697).ok(), Some(vec![v6]));
+        let v4: SocketAddr = "127.0.0.1:6667".parse().unwrap();
+        assert_eq!(resolve("127.0.0.1", 6667).ok(), Some(vec![v4]));
+    }
+
+    #[test]
+    fn bind_family() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let timeout = Duration::from_secs(5);
+
+        let stream = open_socket("127.0.0.1", port, "127.0.0.1".parse().ok(), timeout).unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), "127.0.0.1".parse().unwrap());
+        assert!(open_socket("127.0.0.1", port, "::1".parse().ok(), timeout).is_err());
     }
 }
@@ -1,178 +1,552 @@
 
 mod conn;
+mod transport;
+mod record;
+mod backoff;
+mod watchdog;
+mod sendq;
+mod charset;
+#[cfg(feature = "ssl")]
+mod tls;
+
+pub use self::backoff::Backoff;
+pub use self::transport::{Transport, Duplex, duplex};
+use self::transport::Unlinked;
+pub use self::record::{Recorder, Replayer};
+pub use self::sendq::Priority;
+use self::sendq::SendQueue;
+use self::charset::Charsets;
+use self::watchdog::{Watchdog, WatchdogAction};
 
-use irc::conn::NetStream;
-use cmd::{IrcMsg, IrcMessage};
+use cmd::{IrcMsg, IrcMsgRef, MAX_LINE_LEN, MAX_TAGS_LEN};
 use protocol::ServerProtocol;
 use protocol::ProtoErrorKind;
-use conf::Config;
-
-use encoding::{DecoderTrap, EncoderTrap, Encoding};
-use encoding::label::encoding_from_whatwg_label;
-
-#[cfg(feature = "ssl")] use openssl::ssl::{SslStream, SslMethod, SslContext};
-#[cfg(feature = "ssl")] use openssl::ssl::error::SslError;
-#[cfg(feature = "ssl")] use std::result::Result as StdResult;
+use conf::{Config, ConfigChanges};
+use network::Network;
+use sched::{self, Scheduler};
+use log::{self, Logger};
 
-use std::io::{BufStream, BufRead, Result, Write};
+use std::io::{Read, Result, Write};
 use std::io::Error as IoError;
 use std::io::ErrorKind;
 use std::net::TcpStream;
-use std::str::FromStr;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::fs::OpenOptions;
+use std::path::Path;
 use std::error::Error;
 use std::borrow::ToOwned;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::cmp;
+use std::mem;
+use std::time::Duration;
+
+use time;
+
+/// Read timeout on the uplink socket, in seconds. We only read once the socket
+/// is readable, so this is just a safety net.
+static READ_TIMEOUT: u64 = 5;
+
+/// How much we read from the uplink at once.
+static READ_CHUNK: usize = 4096;
+
+/// How many bytes of the send queue are written before flushing the socket.
+static FLUSH_BATCH: usize = 8192;
 
 pub struct IrcStream<T: ServerProtocol> {
-    stream: Rc<RefCell<BufStream<NetStream>>>,
+    stream: Rc<RefCell<Box<Transport>>>,
     protocol_handler: RefCell<T>,
-    config: Rc<RefCell<Config>>
-}
-
-pub struct IrcStreamIterator<'a, T: 'a + ServerProtocol> {
-    ircstream: &'a IrcStream<T>
+    config: Rc<RefCell<Config>>,
+    /// Raw bytes read from the uplink that don't make a whole line yet
+    read_buf: RefCell<Vec<u8>>,
+    /// Encoded lines waiting to be written in one go
+    write_buf: RefCell<Vec<u8>>,
+    /// Raw bytes of the last line read
+    raw_line: RefCell<Vec<u8>>,
+    /// Decoded line being parsed, reused across reads
+    line_buf: RefCell<String>,
+    watchdog: RefCell<Watchdog>,
+    sendq: RefCell<SendQueue>,
+    /// Resolved once, rather than for every line
+    charsets: RefCell<Charsets>,
+    /// Set when a forced rehash needs the link dropped, with the QUIT and SQUIT
+    /// to send first, made while we were still the server the network knows
+    relink: RefCell<Option<Vec<IrcMsg>>>,
+    scheduler: Rc<RefCell<Scheduler>>
 }
 
 impl<'a, T: 'a + ServerProtocol> IrcStream<T> {
-    pub fn new(conf: Rc<RefCell<Config>>, phandler: T) -> Result<IrcStream<T>> {
-        let ssl = conf.borrow().use_ssl();
-        if ssl {
-            IrcStream::new_ssl_stream(conf, phandler)
-        } else {
-            IrcStream::new_plain_stream(conf, phandler)
+    /// Starts without a link, so that the first one is established by
+    /// `reconnect`, with the same retries as any other. Fails if the
+    /// configuration can't be used, e.g. for unknown charsets.
+    pub fn new(conf: Rc<RefCell<Config>>, phandler: T, scheduler: Rc<RefCell<Scheduler>>)
+               -> Result<IrcStream<T>> {
+        IrcStream::with_transport(conf, phandler, scheduler, Box::new(Unlinked))
+    }
+
+    /// Uses an already established link, such as an in-memory pipe in tests or
+    /// a recorded session being replayed. Reconnecting goes through the link
+    /// blocks as usual.
+    pub fn with_transport(conf: Rc<RefCell<Config>>, phandler: T,
+                          scheduler: Rc<RefCell<Scheduler>>, socket: Box<Transport>)
+                          -> Result<IrcStream<T>> {
+        let charsets = try!(Charsets::from_config(&*conf.borrow()));
+        let watchdog = Watchdog::new(conf.borrow().get_ping_freq(), conf.borrow().get_ping_timeout(),
+                                     now_ms());
+        let sendq = SendQueue::new(conf.borrow().get_sendq_max(), conf.borrow().get_target_rate());
+
+        Ok(IrcStream {
+            stream: Rc::new(RefCell::new(socket)),
+            protocol_handler: RefCell::new(phandler),
+            config: conf,
+            read_buf: RefCell::new(Vec::with_capacity(READ_CHUNK)),
+            write_buf: RefCell::new(Vec::with_capacity(FLUSH_BATCH)),
+            raw_line: RefCell::new(Vec::with_capacity(MAX_LINE_LEN)),
+            line_buf: RefCell::new(String::with_capacity(MAX_LINE_LEN)),
+            watchdog: RefCell::new(watchdog),
+            sendq: RefCell::new(sendq),
+            charsets: RefCell::new(charsets),
+            relink: RefCell::new(None),
+            scheduler: scheduler })
+    }
+
+    /// Reloads the configuration file and applies what changed: logging, charsets,
+    /// send queue and ping settings, and the channels, host and gecos of the
+    /// control bot. Nothing changes if the new configuration is invalid, or if it
+    /// changes settings that need a relink (server name, bot nick...) unless
+    /// `force` is set; the link is dropped then, and the main loop relinks.
+    pub fn rehash(&self, force: bool) -> Result<ConfigChanges> {
+        let config = try!(self.config.borrow().reloaded());
+        let changes = self.config.borrow().changes(&config);
+        if !changes.relink.is_empty() && !force {
+            return Err(IoError::new(ErrorKind::InvalidInput, "Changes need a relink, not reloading.",
+                                    Some(changes.relink.connect(", "))));
+        }
+        let charsets = try!(Charsets::from_config(&config));
+        let logger = try!(Logger::from_config(&config));
+
+        if !changes.relink.is_empty() {
+            // Sent by run_timers, once whoever asked for the rehash got a reply
+            let farewell = self.protocol_handler.borrow().quit_msgs("Relinking to apply a new configuration");
+            *self.relink.borrow_mut() = Some(farewell);
         }
+        self.watchdog.borrow_mut().set_period(config.get_ping_freq(), config.get_ping_timeout());
+        self.sendq.borrow_mut().set_limits(config.get_sendq_max(), config.get_target_rate());
+        *self.config.borrow_mut() = config;
+        *self.charsets.borrow_mut() = charsets;
+        log::init(logger);
+        info!(Core, "Configuration reloaded");
+
+        if changes.relink.is_empty() {
+            let msgs = self.protocol_handler.borrow().rehash_msgs(&changes);
+            try!(self.send_msgs(&msgs[..]));
+        }
+        Ok(changes)
     }
 
-    pub fn iter(&'a self) -> IrcStreamIterator<'a, T> {
-        IrcStreamIterator::new(self)
+    /// Whether the link is being dropped on purpose, to apply a new configuration.
+    pub fn is_relinking(&self) -> bool {
+        self.relink.borrow().is_some()
     }
 
-    #[cfg(feature = "ssl")]
-    fn new_ssl_stream(conf: Rc<RefCell<Config>>, phandler: T) -> Result<IrcStream<T>> {
-        let socket = try!(TcpStream::connect(&format!("{}:{}",
-                                                      conf.borrow().get_uplink_addr(),
-                                                      conf.borrow().get_uplink_port())[..]));
-        let ssl_ctx = try!(ssl_to_io(SslContext::new(SslMethod::Tlsv1)));
-        let ssl_socket = try!(ssl_to_io(SslStream::new(&ssl_ctx, socket)));
+    /// Whether the uplink finished its burst and the control bot was introduced.
+    pub fn is_synced(&self) -> bool {
+        self.protocol_handler.borrow().is_synced()
+    }
 
-        Ok(IrcStream {
-            stream: Rc::new(RefCell::new(BufStream::new(NetStream::SecureNetStream(ssl_socket)))),
-            protocol_handler: RefCell::new(phandler),
-            config: conf })
+    /// The network state maintained by the protocol handler.
+    pub fn network(&self) -> Rc<RefCell<Network>> {
+        self.protocol_handler.borrow().network()
     }
 
-    #[cfg(not(feature = "ssl"))]
-    fn new_ssl_stream(conf: Rc<RefCell<Config>>, phandler: T) -> Result<IrcStream<T>> {
-        panic!("SSL support was not compiled, but use_ssl is set to 'yes'. Please recompile with ssl support by enabling the feature 'ssl'");
+    /// Leaves the network, telling it why. The link is unusable afterwards.
+    pub fn shutdown(&self, reason: &str) -> Result<()> {
+        let msgs = self.protocol_handler.borrow().quit_msgs(reason);
+        self.send_msgs(&msgs[..])
     }
 
-    fn new_plain_stream(conf: Rc<RefCell<Config>>, phandler: T) -> Result<IrcStream<T>> {
-        let socket = NetStream::PlainNetStream(try!(TcpStream::connect(
-            &format!("{}:{}",
-                     conf.borrow().get_uplink_addr(),
-                     conf.borrow().get_uplink_port())[..])));
+    /// Drops the current link, if any, and establishes a new one: the protocol
+    /// handler forgets everything it knew about the network and the old link,
+    /// and we introduce ourselves again. The control bot is reintroduced once
+    /// the new burst is over.
+    pub fn reconnect(&self) -> Result<()> {
+        let socket = try!(connect_any(&self.config));
+        *self.stream.borrow_mut() = socket;
+        self.read_buf.borrow_mut().clear();
+        self.write_buf.borrow_mut().clear();
+        self.sendq.borrow_mut().clear();
+        self.watchdog.borrow_mut().reset(now_ms());
+        *self.relink.borrow_mut() = None;
+        self.protocol_handler.borrow_mut().reset();
+        self.introduce()
+    }
 
-        Ok(IrcStream {
-            stream: Rc::new(RefCell::new(BufStream::new(socket))),
-            protocol_handler: RefCell::new(phandler),
-            config: conf })
+    /// Round trip time to the uplink, in milliseconds, if it was measured already.
+    pub fn lag(&self) -> Option<u64> {
+        self.watchdog.borrow().lag(now_ms())
     }
 
     pub fn introduce(&self) -> Result<()> {
-        let intro_msg = &self.protocol_handler.borrow().introduce_msg()[..];
-        self.send_msg(intro_msg)
+        let intro_msgs = self.protocol_handler.borrow().introduce_msg();
+        self.send_msgs(&intro_msgs[..])
     }
 
-    pub fn recv_msg(&self) -> Result<IrcMessage> {
-        let mut line = String::new();
-        self.read_line(&mut line).and_then(|_| {
-            let msg = IrcMsg::from_str(&line[..]);
-            if msg.is_err() {
-                return Ok(msg);
-            }
-            match self.protocol_handler.borrow_mut().handle(msg.as_ref().unwrap()) {
-                Ok(Some(reply)) => self.send_msg(&reply[..]).and_then(|_| Ok(msg)),
-                Err(e)  => {
-                    println!("{}", e);
-                    if e.kind == ProtoErrorKind::Fatal {
-                        Err(IoError::new(ErrorKind::InvalidInput, e.desc, e.detail))
-                    } else {
-                        Ok(msg)
-                    }
-                }
-                _ => Ok(msg)
-            }})
+    /// File descriptor of the uplink, for the main loop to poll.
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        self.stream.borrow().raw_fd()
     }
-        
-    pub fn send_msg(&self, msg: &str) -> Result<()> {
-        self.write_line(msg)
+
+    /// Timers shared by the protocol handler and the bots.
+    pub fn scheduler(&self) -> Rc<RefCell<Scheduler>> {
+        self.scheduler.clone()
+    }
+
+    /// Whether the transport holds data the file descriptor won't signal, so
+    /// that the main loop must read again without waiting.
+    pub fn has_pending(&self) -> bool {
+        self.stream.borrow().pending() > 0
     }
 
-    fn write_line(&self, msg: &str) -> Result<()> {
-        let borrowed = self.config.borrow();
-        let charset = borrowed.get_encoding();
-        let encoding = match encoding_from_whatwg_label(charset) {
-            Some(enc) => enc,
-            None => return Err(IoError::new(ErrorKind::InvalidInput, "Failed to find encoder.",
-                                            Some(format!("Invalid encoder: {}", charset))))
+    /// How long the main loop may sleep before `run_timers` has something to do,
+    /// in milliseconds.
+    pub fn next_timeout_ms(&self) -> u64 {
+        if self.has_pending() || self.is_relinking() {
+            return 0;
+        }
+        let now = now_ms();
+        let deadline = self.watchdog.borrow().next_deadline();
+        let timeout = if deadline > now { deadline - now } else { 0 };
+        let timeout = match self.scheduler.borrow_mut().next_timeout_ms() {
+            Some(t) => cmp::min(timeout, t),
+            None => timeout
+        };
+        // Retry soon when rate limiting is holding messages back
+        if self.sendq.borrow().is_empty() { timeout } else { cmp::min(timeout, 100) }
+    }
+
+    /// Reads whatever the uplink sent. Only call this once the uplink is readable,
+    /// then take the messages out with `next_msg`.
+    pub fn read_available(&self) -> Result<()> {
+        let mut chunk = [0; READ_CHUNK];
+        let n = match self.stream.borrow_mut().read(&mut chunk) {
+            Ok(0) => return Err(IoError::new(ErrorKind::ConnectionAborted,
+                                             "Connection closed by peer.", None)),
+            Ok(n) => n,
+            Err(ref e) if is_timeout(e) => return Ok(()),
+            Err(e) => return Err(e)
         };
+        let mut buf = self.read_buf.borrow_mut();
+        buf.extend(chunk[..n].iter().cloned());
+        if buf.len() > MAX_TAGS_LEN + MAX_LINE_LEN && !buf.contains(&b'\n') {
+            return Err(IoError::new(ErrorKind::InvalidInput, "Line too long.",
+                                    Some(format!("{} bytes without a line feed", buf.len()))));
+        }
+        Ok(())
+    }
+
+    /// Raw bytes of the line behind the message `next_msg` handled last, for
+    /// hashing or matching independently of how it was decoded.
+    pub fn raw_line(&self) -> Vec<u8> {
+        self.raw_line.borrow().clone()
+    }
+
+    /// Handles the next complete line read from the uplink, if there is one,
+    /// then passes the message to `f` for the caller to act on as well. The
+    /// message borrows from the line, so that bursts don't allocate for every
+    /// user; callers that keep it convert it with `into_owned`. Returns whether
+    /// there was a line.
+    pub fn next_msg<F: FnMut(&IrcMsgRef)>(&self, mut f: F) -> Result<bool> {
+        let mut line = self.line_buf.borrow_mut();
+        if !try!(self.read_line(&mut line)) {
+            return Ok(false);
+        }
+
+        let msg = try!(IrcMsgRef::parse(&line[..]).map_err(
+            |e| IoError::new(ErrorKind::InvalidInput, "Invalid IRC Message", Some(e))));
+        if msg.command == "PONG" {
+            self.watchdog.borrow_mut().on_pong(now_ms());
+        }
+        let handled = self.protocol_handler.borrow_mut().handle(&msg);
+        match handled {
+            Ok(replies) => match self.send_msgs(&replies[..]) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => warn!(Link, "{}", e),
+                res => try!(res)
+            },
+            Err(e) => {
+                if e.kind == ProtoErrorKind::Fatal {
+                    error!(Protocol, "{}", e);
+                    return Err(IoError::new(ErrorKind::InvalidInput, e.desc, e.detail));
+                }
+                warn!(Protocol, "{}", e);
+            }
+        }
+        f(&msg);
+        Ok(true)
+    }
+
+    /// Runs the scheduler timers that are due and sends what they produced, then
+    /// checks the watchdog.
+    pub fn run_timers(&self) -> Result<()> {
+        if let Some(ref mut farewell) = *self.relink.borrow_mut() {
+            let _ = self.send_msgs(&mem::replace(farewell, Vec::new())[..]);
+            return Err(IoError::new(ErrorKind::ConnectionAborted,
+                                    "Relinking to apply a new configuration.", None));
+        }
+        let msgs = sched::run_due(&self.scheduler);
+        match self.send_msgs(&msgs[..]) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => warn!(Link, "{}", e),
+            res => try!(res)
+        }
+        self.check_watchdog()
+    }
+
+    /// Sends a PING to the uplink when one is due, and gives up on the link if
+    /// the last one went unanswered for too long.
+    fn check_watchdog(&self) -> Result<()> {
+        let action = self.watchdog.borrow_mut().check(now_ms());
+        match action {
+            WatchdogAction::SendPing => {
+                let ping = self.protocol_handler.borrow().ping_msg();
+                self.send_msg(&ping)
+            }
+            WatchdogAction::Timeout => {
+                let conf = self.config.borrow();
+                Err(IoError::new(ErrorKind::TimedOut, "Ping timeout.",
+                                 Some(format!("No PONG from {} in {} seconds",
+                                              conf.get_uplink_name(), conf.get_ping_timeout()))))
+            }
+            // Also a chance to send what rate limiting held back
+            WatchdogAction::Nothing => self.flush_sendq()
+        }
+    }
 
-        let data = match encoding.encode(msg, EncoderTrap::Replace) {
-            Ok(data) => data,
-            Err(data) => return Err(IoError::new(ErrorKind::InvalidInput,
-                                                 "Failed to encode message.",
-                                                 Some(format!("Failed to encode {} as {}.",
-                                                              data, encoding.name()))))
+    /// Queues a message with the default priority for its command, and flushes the queue.
+    pub fn send_msg(&self, msg: &IrcMsg) -> Result<()> {
+        self.send_msg_prio(msg, Priority::of(msg))
+    }
+
+    /// Queues a message and flushes the queue. Fails with `WouldBlock` when the
+    /// send queue is full; the message is dropped then.
+    pub fn send_msg_prio(&self, msg: &IrcMsg, prio: Priority) -> Result<()> {
+        try!(self.queue_msg(msg, prio));
+        self.flush_sendq()
+    }
+
+    /// Queues several messages and writes them in as few flushes as possible.
+    /// Invalid messages are logged and skipped. Messages that don't fit in the
+    /// send queue are dropped, and reported after the others were sent.
+    pub fn send_msgs(&self, msgs: &[IrcMsg]) -> Result<()> {
+        let mut res = Ok(());
+        for msg in msgs.iter() {
+            match self.queue_msg(msg, Priority::of(msg)) {
+                Ok(_) => (),
+                Err(ref e) if e.kind() == ErrorKind::InvalidInput => warn!(Protocol, "{}", e),
+                Err(e) => res = Err(e)
+            }
+        }
+        self.flush_sendq().and(res)
+    }
+
+    /// Whether the send queue is filling up; bulk senders should hold back.
+    pub fn is_congested(&self) -> bool {
+        self.sendq.borrow().is_congested()
+    }
+
+    fn queue_msg(&self, msg: &IrcMsg, prio: Priority) -> Result<()> {
+        if self.config.borrow().is_dry_run() && is_enforcement(msg) {
+            info!(Protocol, "Dry run, not sending {} {}", msg.command, msg.params.connect(" "));
+            return Ok(());
+        }
+        let line = match msg.to_wire() {
+            Ok(line) => line,
+            Err(e) => return Err(IoError::new(ErrorKind::InvalidInput,
+                                              "Refusing to send invalid message.", Some(e)))
+        };
+        let target = match &msg.command[..] {
+            "PRIVMSG" | "NOTICE" => msg.params.get(0).map(|t| &t[..]),
+            _ => None
         };
+        let data = self.charsets.borrow().encode(&line[..], target);
+        self.sendq.borrow_mut().push(data, prio, target).map_err(
+            |e| IoError::new(ErrorKind::WouldBlock, "Send queue full.",
+                             Some(format!("Dropping {} message, {} bytes queued",
+                                          msg.command, e.queued))))
+    }
+
+    /// Writes whatever can be sent right now, flushing the socket after each batch.
+    /// Lines held back by rate limiting are sent on a later call.
+    pub fn flush_sendq(&self) -> Result<()> {
+        loop {
+            let batch = self.sendq.borrow_mut().next_batch(now_ms(), FLUSH_BATCH);
+            if batch.is_empty() {
+                return Ok(());
+            }
+            for line in batch.iter() {
+                try!(self.write_line(&line[..]));
+            }
+            let mut buf = self.write_buf.borrow_mut();
+            let res = self.stream.borrow_mut().write_all(&buf[..])
+                          .and_then(|_| self.stream.borrow_mut().flush());
+            buf.clear();
+            try!(res);
+        }
+    }
 
-        try!(self.stream.borrow_mut().write_all(&data));
-        self.stream.borrow_mut().flush().and_then(
-            |_| { print!("[RAW OUTPUT]: {}", msg); Ok::<(), _>(()) })
+    fn write_line(&self, data: &[u8]) -> Result<()> {
+        self.write_buf.borrow_mut().extend(data.iter().cloned());
+        log::traffic(false, &String::from_utf8_lossy(data));
+        Ok(())
     }
 
-    // TODO Proper logging
-    fn read_line(&self, buff: &mut String) -> Result<()> {
-        let borrowed = self.config.borrow();
-        let charset = borrowed.get_encoding();
-        let encoding = match encoding_from_whatwg_label(charset) {
-            Some(enc) => enc,
-            None => return Err(IoError::new(ErrorKind::InvalidInput, "Failed to find decoder.",
-                                            Some(format!("Invalid decoder: {}", charset))))
+    /// Takes the next complete line out of the read buffer. Returns false if
+    /// there is none yet.
+    fn read_line(&self, buff: &mut String) -> Result<bool> {
+        let mut buf = self.read_buf.borrow_mut();
+        let end = match buf.iter().position(|&b| b == b'\n') {
+            Some(pos) => pos + 1,
+            None => return Ok(false)
         };
+        let mut raw = self.raw_line.borrow_mut();
+        raw.clear();
+        raw.extend(buf.drain(..end));
+        self.charsets.borrow().decode(&raw[..], buff);
+        log::traffic(true, buff);
+        Ok(true)
+    }
+}
 
-        let mut buf = Vec::new();
-        self.stream.borrow_mut().read_until(b'\n', &mut buf).and_then(|_| {
-            match encoding.decode(&buf, DecoderTrap::Replace) {
-                Ok(data) => { *buff = data; print!("[RAW INPUT]: {}", buff); Ok::<(), _>(()) },
-                Err(e) => Err(IoError::new(ErrorKind::InvalidInput, "Failed to decode message.",
-                                           Some(format!("Failed to decode {} as {}.", e,
-                                                        encoding.name()))))
+/// Tries every link block, in the configured order, until one of them accepts
+/// our connection. The link that succeeded becomes the active one.
+fn connect_any(conf: &RefCell<Config>) -> Result<Box<Transport>> {
+    let mut last_err = None;
+    let candidates = conf.borrow().link_candidates();
+    for index in candidates {
+        conf.borrow_mut().select_link(index);
+        match connect(&*conf.borrow()) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => {
+                let conf = conf.borrow();
+                if e.kind() == ErrorKind::PermissionDenied {
+                    warn!(Security, "Rejected the certificate of {} ({}): {}", conf.get_uplink_name(),
+                          conf.get_uplink_addr(), e);
+                } else {
+                    warn!(Link, "Failed to connect to {} ({}): {}", conf.get_uplink_name(),
+                          conf.get_uplink_addr(), (&e as &Error).description());
+                }
+                last_err = Some(e);
             }
-        })
+        }
     }
+    Err(last_err.unwrap_or(IoError::new(ErrorKind::InvalidInput, "No uplinks configured.", None)))
 }
 
-impl<'a, T: 'a + ServerProtocol> IrcStreamIterator<'a, T> {
-    pub fn new(istream: &'a IrcStream<T>) -> IrcStreamIterator<T> {
-        IrcStreamIterator { ircstream: istream }
+fn connect(conf: &Config) -> Result<Box<Transport>> {
+    let socket = if conf.get_uplink_addr().starts_with("/") {
+        try!(connect_unix(conf))
+    } else if conf.use_ssl() {
+        try!(connect_ssl(conf))
+    } else {
+        Box::new(try!(open_socket(conf))) as Box<Transport>
+    };
+    match conf.get_record_file() {
+        Some(path) => {
+            let log = try!(OpenOptions::new().create(true).append(true).open(&Path::new(path)));
+            Ok(Box::new(Recorder::new(socket, Box::new(log))))
+        }
+        None => Ok(socket)
     }
 }
 
-impl<'a, T: ServerProtocol + 'a> Iterator for IrcStreamIterator<'a, T> {
-    type Item = Result<IrcMessage>;
+#[cfg(feature = "ssl")]
+fn connect_ssl(conf: &Config) -> Result<Box<Transport>> {
+    let socket = try!(open_socket(conf));
+    let ssl_socket = try!(tls::connect_tls(socket, &conf.get_tls(), conf.get_uplink_name()));
 
-    fn next(&mut self) -> Option<Result<IrcMessage>> {
-        Some(self.ircstream.recv_msg())
+    Ok(Box::new(ssl_socket))
+}
+
+#[cfg(not(feature = "ssl"))]
+fn connect_ssl(conf: &Config) -> Result<Box<Transport>> {
+    panic!("SSL support was not compiled, but use_ssl is set to 'yes'. Please recompile with ssl support by enabling the feature 'ssl'");
+}
+
+/// Links to an IRCd on the same host through its unix socket; `address` is the
+/// socket path then.
+fn connect_unix(conf: &Config) -> Result<Box<Transport>> {
+    if conf.use_ssl() {
+        return Err(IoError::new(ErrorKind::InvalidInput, "TLS isn't supported over unix sockets.",
+                                Some(conf.get_uplink_addr().to_owned())));
     }
+    let socket = try!(UnixStream::connect(conf.get_uplink_addr()));
+    try!(socket.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT))));
+    Ok(Box::new(socket))
 }
 
-/// Converts a Result<U, SslError> into a Result<U>.
-#[cfg(feature = "ssl")]
-fn ssl_to_io<U>(res: StdResult<U, SslError>) -> Result<U> {
-    match res {
-        Ok(x) => Ok(x),
-        Err(e) => Err(IoError::new(ErrorKind::Other, "An SSL error occurred.",
-                                   Some(e.description().to_owned()))),
+fn open_socket(conf: &Config) -> Result<TcpStream> {
+    let timeout = Duration::from_secs(conf.get_connect_timeout() as u64);
+    let socket = try!(conn::open_socket(conf.get_uplink_addr(), conf.get_uplink_port(),
+                                        conf.get_bind_addr(), timeout));
+    try!(socket.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT))));
+    Ok(socket)
+}
+
+/// Messages acting against users or channels, which a dry run doesn't send.
+/// Plain MODE only counts when it restricts a channel: our own user modes and
+/// the modes the bot sets on joining go through.
+fn is_enforcement(msg: &IrcMsg) -> bool {
+    match &msg.command[..] {
+        "KILL" | "SVSKILL" | "KICK" | "TKL" | "GLINE" | "SHUN" | "ZLINE" | "SVSMODE" | "SVS2MODE" |
+        "SVSNICK" | "SVSJOIN" | "SVSPART" => true,
+        "MODE" => match (msg.params.get(0), msg.params.get(1)) {
+            (Some(target), Some(modes)) => target.starts_with('#') && is_restrictive(&modes[..]),
+            _ => false
+        },
+        _ => false
+    }
+}
+
+/// Whether channel mode changes restrict someone: bans, demotions, or locking
+/// the channel.
+fn is_restrictive(modes: &str) -> bool {
+    let mut adding = true;
+    modes.chars().any(|c| match c {
+        '+' => { adding = true; false }
+        '-' => { adding = false; false }
+        'b' | 'k' | 'l' | 'i' | 'm' => adding,
+        'q' | 'a' | 'o' | 'h' | 'v' => !adding,
+        _ => false
+    })
+}
+
+fn is_timeout(e: &IoError) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+/// Milliseconds from a monotonic clock.
+fn now_ms() -> u64 {
+    time::precise_time_ns() / 1000000
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_enforcement;
+    use cmd::IrcMsg;
+
+    fn mode(target: &str, modes: &str) -> IrcMsg {
+        IrcMsg::new(Some("Tools".to_string()), "MODE", vec![target.to_string(), modes.to_string()])
+    }
+
+    #[test]
+    fn enforcement() {
+        assert!(is_enforcement(&IrcMsg::new(None, "KILL", vec!["Spammer".to_string()])));
+        assert!(is_enforcement(&IrcMsg::new(None, "SVSMODE", vec!["Spammer".to_string(), "-x".to_string()])));
+        assert!(is_enforcement(&mode("#Services", "+b")));
+        assert!(is_enforcement(&mode("#Services", "+nt-o")));
+        assert!(is_enforcement(&mode("#Services", "-v+k")));
+        assert!(!is_enforcement(&mode("#Services", "+o")));
+        assert!(!is_enforcement(&mode("#Services", "+nt-b")));
+        assert!(!is_enforcement(&mode("Tools", "+ioS")));
+        assert!(!is_enforcement(&IrcMsg::new(None, "PRIVMSG", vec!["#Services".to_string(), "hi".to_string()])));
     }
 }
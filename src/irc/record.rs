@@ -0,0 +1,335 @@
+use irc::transport::Transport;
+use log;
+
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+/// Wraps a transport and logs everything going through it, in the format
+/// `Replayer` reads: one chunk per line, `<` for received data and `>` for
+/// sent data, followed by a space and the escaped bytes. Link passwords are
+/// left out, as in the logs: PASS lines are recorded as `PASS <redacted>`.
+///
+/// Chunks only hold whole lines, however the data was read or written, so
+/// that a PASS line is always seen at once. A line the link dropped in the
+/// middle of is never recorded; it wasn't handled either.
+pub struct Recorder<T: Transport> {
+    inner: T,
+    log: Box<Write>,
+    /// Received bytes that don't make a whole line yet
+    received: Vec<u8>,
+    /// Sent bytes that don't make a whole line yet
+    sent: Vec<u8>
+}
+
+impl<T: Transport> Recorder<T> {
+    pub fn new(inner: T, log: Box<Write>) -> Recorder<T> {
+        Recorder { inner: inner, log: log, received: Vec::new(), sent: Vec::new() }
+    }
+
+    fn record(&mut self, direction: char, data: &[u8]) -> Result<()> {
+        let lines = {
+            let partial = if direction == '<' { &mut self.received } else { &mut self.sent };
+            partial.extend(data.iter().cloned());
+            let end = partial.iter().rposition(|&b| b == b'\n');
+            match end {
+                Some(pos) => partial.drain(..pos + 1).collect::<Vec<u8>>(),
+                None => return Ok(())
+            }
+        };
+        try!(write!(self.log, "{} {}\n", direction, escape(&redact(&lines[..])[..])));
+        self.log.flush()
+    }
+}
+
+/// Redacts the PASS lines of whole lines of data.
+fn redact(data: &[u8]) -> Vec<u8> {
+    let mut redacted = Vec::with_capacity(data.len());
+    for (i, line) in data.split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            redacted.push(b'\n');
+        }
+        let (text, cr) = match line.last() {
+            Some(&b'\r') => (&line[..line.len() - 1], true),
+            _ => (line, false)
+        };
+        match ::std::str::from_utf8(text).map(log::redact) {
+            Ok(Cow::Owned(hidden)) => redacted.extend(hidden.bytes()),
+            _ => redacted.extend(text.iter().cloned())
+        }
+        if cr {
+            redacted.push(b'\r');
+        }
+    }
+    redacted
+}
+
+impl<T: Transport> Read for Recorder<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = try!(self.inner.read(buf));
+        if n > 0 {
+            try!(self.record('<', &buf[..n]));
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Transport> Write for Recorder<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = try!(self.inner.write(buf));
+        try!(self.record('>', &buf[..n]));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for Recorder<T> {
+    fn peer(&self) -> String {
+        format!("{} (recorded)", self.inner.peer())
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.inner.raw_fd()
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending()
+    }
+}
+
+/// Plays back a recorded session: reads return what the uplink sent, and
+/// writes are checked against what we sent back then, so that a test fails
+/// as soon as our side of the conversation diverges.
+pub struct Replayer {
+    incoming: VecDeque<u8>,
+    expected: VecDeque<u8>,
+    name: String
+}
+
+impl Replayer {
+    pub fn open(path: &Path) -> Result<Replayer> {
+        let file = try!(File::open(path));
+        Replayer::from_reader(BufReader::new(file), path.display().to_string())
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R, name: String) -> Result<Replayer> {
+        let mut replayer = Replayer { incoming: VecDeque::new(), expected: VecDeque::new(),
+                                      name: name };
+        for (n, line) in reader.lines().enumerate() {
+            let line = try!(line);
+            if line.is_empty() {
+                continue;
+            }
+            let data = if line.is_char_boundary(2) { unescape(&line[2..]) } else { None };
+            match (&line[..cmp::min(2, line.len())], data) {
+                ("< ", Some(data)) => replayer.incoming.extend(data),
+                ("> ", Some(data)) => replayer.expected.extend(data),
+                _ => return Err(bad_recording(n + 1))
+            }
+        }
+        Ok(replayer)
+    }
+
+    /// Whether we sent everything we were expected to.
+    pub fn is_done(&self) -> bool {
+        self.expected.is_empty()
+    }
+}
+
+impl Read for Replayer {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = cmp::min(buf.len(), self.incoming.len());
+        for (dst, src) in buf.iter_mut().zip(self.incoming.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Replayer {
+    /// Our PASS is compared as it was recorded, redacted. The uplink's PASS
+    /// replays as `<redacted>`, which the configuration has to expect.
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        for (i, &b) in redact(buf).iter().enumerate() {
+            if self.expected.pop_front() != Some(b) {
+                return Err(Error::new(ErrorKind::InvalidData, "Output diverges from the recording.",
+                                      Some(format!("{} bytes into {:?}", i, escape(buf)))));
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Replayer {
+    fn peer(&self) -> String {
+        format!("replay of {}", self.name)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+fn bad_recording(line: usize) -> Error {
+    Error::new(ErrorKind::InvalidData, "Malformed recording.", Some(format!("line {}", line)))
+}
+
+/// Escapes CR, LF, backslashes and anything that isn't printable ASCII.
+fn escape(data: &[u8]) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    for &b in data.iter() {
+        match b {
+            b'\r' => escaped.push_str("\\r"),
+            b'\n' => escaped.push_str("\\n"),
+            b'\\' => escaped.push_str("\\\\"),
+            0x20...0x7e => escaped.push(b as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", b))
+        }
+    }
+    escaped
+}
+
+fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            data.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'r') => data.push(b'\r'),
+            Some(b'n') => data.push(b'\n'),
+            Some(b'\\') => data.push(b'\\'),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let hex = match String::from_utf8(hex) {
+                    Ok(hex) => hex,
+                    Err(_) => return None
+                };
+                match u8::from_str_radix(&hex[..], 16) {
+                    Ok(byte) if hex.len() == 2 => data.push(byte),
+                    _ => return None
+                }
+            }
+            _ => return None
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Recorder, Replayer};
+    use irc::transport::{Duplex, Transport, duplex};
+
+    use std::cell::RefCell;
+    use std::io::{Read, Result, Write};
+    use std::os::unix::io::RawFd;
+    use std::rc::Rc;
+
+    /// A log sink the test can look into after handing it to the recorder.
+    struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A pipe pretending to hold data that polling wouldn't report, as TLS does.
+    struct Buffered(Duplex);
+
+    impl Read for Buffered {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Buffered {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Buffered {
+        fn peer(&self) -> String {
+            self.0.peer()
+        }
+        fn raw_fd(&self) -> Option<RawFd> {
+            None
+        }
+        fn pending(&self) -> usize {
+            42
+        }
+    }
+
+    #[test]
+    fn pending_data() {
+        let (us, _uplink) = duplex();
+        let recorder = Recorder::new(Buffered(us), Box::new(SharedLog(Rc::new(RefCell::new(Vec::new())))));
+        assert_eq!(recorder.pending(), 42);
+        let boxed: Box<Transport> = Box::new(recorder);
+        assert_eq!(boxed.pending(), 42);
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let (us, mut uplink) = duplex();
+        let mut recorder = Recorder::new(us, Box::new(SharedLog(log.clone())));
+
+        uplink.write_all(b"PING :Ping.MindForge.org\r\n").unwrap();
+        let mut buf = [0; 64];
+        let n = recorder.read(&mut buf).unwrap();
+        assert_eq!(n, 26);
+        recorder.write_all(b"PONG :Ping.MindForge.org\xe9\r\n").unwrap();
+
+        let recording = log.borrow().clone();
+        assert_eq!(&recording[..],
+                   &b"< PING :Ping.MindForge.org\\r\\n\n> PONG :Ping.MindForge.org\\xe9\\r\\n\n"[..]);
+
+        let mut replayer = Replayer::from_reader(&recording[..], "test".to_string()).unwrap();
+        let n = replayer.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"PING :Ping.MindForge.org\r\n");
+        replayer.write_all(b"PONG :Ping.MindForge.org\xe9\r\n").unwrap();
+        assert!(replayer.is_done());
+
+        let mut replayer = Replayer::from_reader(&recording[..], "test".to_string()).unwrap();
+        assert!(replayer.write_all(b"PONG :elsewhere\r\n").is_err());
+
+        // Passwords stay out of recordings, even when they come in pieces
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let (us, mut uplink) = duplex();
+        let mut recorder = Recorder::new(us, Box::new(SharedLog(log.clone())));
+        recorder.write_all(b"PASS :rustp0").unwrap();
+        recorder.write_all(b"w3r!\r\nPROTOCTL NOQUIT\r\n").unwrap();
+        uplink.write_all(b"PASS :s3").unwrap();
+        recorder.read(&mut buf).unwrap();
+        uplink.write_all(b"cret\r\n").unwrap();
+        recorder.read(&mut buf).unwrap();
+        let recording = log.borrow().clone();
+        assert_eq!(&recording[..], &b"> PASS <redacted>\\r\\nPROTOCTL NOQUIT\\r\\n\n< PASS <redacted>\\r\\n\n"[..]);
+        let mut replayer = Replayer::from_reader(&recording[..], "test".to_string()).unwrap();
+        replayer.write_all(b"PASS :rustp0w3r!\r\nPROTOCTL NOQUIT\r\n").unwrap();
+        assert!(replayer.is_done());
+        assert!(Replayer::from_reader(&b"? junk\n"[..], "test".to_string()).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use cmd::IrcMsg;
+use network::irc_lower;
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+
+/// Outgoing messages are sent in order of priority, and in the order they
+/// were queued within the same priority.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Priority {
+    /// Keeps the link alive and the network consistent: PONG, KILL, SQUIT...
+    High,
+    Normal,
+    /// Mass messages, such as notices to channels
+    Bulk
+}
+
+impl Priority {
+    /// Guesses the priority of a message from its command.
+    pub fn of(msg: &IrcMsg) -> Priority {
+        match &msg.command[..] {
+            "PING" | "PONG" | "KILL" | "SQUIT" | "ERROR" | "PASS" | "PROTOCTL" | "SERVER" |
+            "EOS" => Priority::High,
+            "PRIVMSG" | "NOTICE" if msg.params.get(0).map_or(false, |t| is_channel(&t[..])) =>
+                Priority::Bulk,
+            _ => Priority::Normal
+        }
+    }
+}
+
+/// Returned when a message is refused because too much is queued already.
+#[derive(Debug, PartialEq)]
+pub struct SendQFull {
+    /// Bytes waiting to be sent
+    pub queued: usize
+}
+
+/// Per-target token bucket: `lines` messages every `period` milliseconds.
+struct Bucket {
+    tokens: u32,
+    refilled_at: u64
+}
+
+struct Entry {
+    line: Vec<u8>,
+    /// Lowercased target of PRIVMSGs and NOTICEs, for rate limiting
+    target: Option<String>
+}
+
+/// Buffered outgoing messages, with priority classes and optional rate
+/// limiting per target. All times are in milliseconds.
+pub struct SendQueue {
+    /// One queue per priority, highest first
+    queues: [VecDeque<Entry>; 3],
+    /// Bytes currently queued
+    queued: usize,
+    /// Beyond this many queued bytes, only high priority messages are accepted
+    max_bytes: usize,
+    /// Per-target rate limit, in lines per period; None disables it
+    rate: Option<(u32, u64)>,
+    buckets: HashMap<String, Bucket>
+}
+
+impl SendQueue {
+    pub fn new(max_bytes: usize, rate: Option<(u32, u64)>) -> SendQueue {
+        SendQueue { queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()], queued: 0,
+                    max_bytes: max_bytes, rate: rate, buckets: HashMap::new() }
+    }
+
+    /// Queues a line, already serialized and encoded. High priority lines are always
+    /// accepted so that a full queue can't cause a ping timeout.
+    pub fn push(&mut self, line: Vec<u8>, prio: Priority, target: Option<&str>)
+                -> Result<(), SendQFull> {
+        if prio != Priority::High && self.queued + line.len() > self.max_bytes {
+            return Err(SendQFull { queued: self.queued });
+        }
+        self.queued += line.len();
+        self.queues[prio as usize].push_back(Entry { line: line,
+                                                     target: target.map(irc_lower) });
+        Ok(())
+    }
+
+    /// Changes the limits, e.g. after a configuration reload. What is queued already stays.
+    pub fn set_limits(&mut self, max_bytes: usize, rate: Option<(u32, u64)>) {
+        if rate != self.rate {
+            self.buckets.clear();
+        }
+        self.max_bytes = max_bytes;
+        self.rate = rate;
+    }
+
+    /// Bytes waiting to be sent.
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued == 0
+    }
+
+    /// Whether more than half of the queue is used; callers producing bulk
+    /// traffic should hold back.
+    pub fn is_congested(&self) -> bool {
+        self.queued > self.max_bytes / 2
+    }
+
+    pub fn clear(&mut self) {
+        for q in self.queues.iter_mut() {
+            q.clear();
+        }
+        self.queued = 0;
+        self.buckets.clear();
+    }
+
+    /// Takes up to `max_bytes` worth of lines to write in one go (at least one line
+    /// if any can be sent). Lines for targets over their rate limit stay queued, in order.
+    pub fn next_batch(&mut self, now: u64, max_bytes: usize) -> Vec<Vec<u8>> {
+        let mut batch = Vec::new();
+        let mut size = 0;
+        for prio in 0..3 {
+            let mut deferred = VecDeque::new();
+            while let Some(entry) = self.queues[prio].pop_front() {
+                if size > 0 && size + entry.line.len() > max_bytes {
+                    self.queues[prio].push_front(entry);
+                    break;
+                }
+                let allowed = match entry.target {
+                    Some(ref target) => self.take_token(target, now),
+                    None => true
+                };
+                if allowed {
+                    size += entry.line.len();
+                    self.queued -= entry.line.len();
+                    batch.push(entry.line);
+                } else {
+                    deferred.push_back(entry);
+                }
+            }
+            // Deferred lines were queued before whatever is left
+            while let Some(entry) = deferred.pop_back() {
+                self.queues[prio].push_front(entry);
+            }
+        }
+        batch
+    }
+
+    fn take_token(&mut self, target: &str, now: u64) -> bool {
+        let (lines, period) = match self.rate {
+            Some(rate) => rate,
+            None => return true
+        };
+        let bucket = self.buckets.entry(target.to_owned())
+                                 .or_insert(Bucket { tokens: lines, refilled_at: now });
+        if period > 0 && now >= bucket.refilled_at + period {
+            let periods = (now - bucket.refilled_at) / period;
+            bucket.tokens = cmp::min(lines as u64, bucket.tokens as u64 + periods * lines as u64) as u32;
+            bucket.refilled_at += periods * period;
+        }
+        if bucket.tokens > 0 {
+            bucket.tokens -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn is_channel(target: &str) -> bool {
+    target.starts_with("#") || target.starts_with("&")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SendQueue, Priority, SendQFull};
+    use cmd::IrcMsg;
+
+    #[test]
+    fn priorities() {
+        let notice = IrcMsg::new(None, "NOTICE", vec!["#chan".to_string(), "hi".to_string()]);
+        let pong = IrcMsg::new(None, "PONG", vec!["Ping.MindForge.org".to_string()]);
+        assert_eq!(Priority::of(&notice), Priority::Bulk);
+        assert_eq!(Priority::of(&pong), Priority::High);
+
+        let mut sendq = SendQueue::new(20, None);
+        sendq.push(b"bulk1\r\n".to_vec(), Priority::Bulk, None).unwrap();
+        sendq.push(b"normal\r\n".to_vec(), Priority::Normal, None).unwrap();
+        assert_eq!(sendq.push(b"bulk2 is too long\r\n".to_vec(), Priority::Bulk, None),
+                   Err(SendQFull { queued: 15 }));
+        sendq.push(b"pong\r\n".to_vec(), Priority::High, None).unwrap();
+        assert!(sendq.is_congested());
+
+        assert_eq!(sendq.next_batch(0, 14), vec![b"pong\r\n".to_vec(), b"normal\r\n".to_vec()]);
+        assert_eq!(sendq.next_batch(0, 14), vec![b"bulk1\r\n".to_vec()]);
+        assert!(sendq.is_empty());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut sendq = SendQueue::new(1000, Some((2, 1000)));
+        for line in ["a1", "a2", "a3"].iter() {
+            sendq.push(line.as_bytes().to_vec(), Priority::Normal, Some("Alice")).unwrap();
+        }
+        sendq.push(b"b1".to_vec(), Priority::Normal, Some("Bob")).unwrap();
+
+        assert_eq!(sendq.next_batch(0, 512), vec![b"a1".to_vec(), b"a2".to_vec(), b"b1".to_vec()]);
+        assert_eq!(sendq.next_batch(500, 512), Vec::<Vec<u8>>::new());
+        assert_eq!(sendq.next_batch(1000, 512), vec![b"a3".to_vec()]);
+    }
+}
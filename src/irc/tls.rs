@@ -0,0 +1,294 @@
+use conf::{TlsConfig, TlsVersion};
+
+use openssl::crypto::hash::HashType;
+use openssl::nid::Nid;
+use openssl::ssl::{Ssl, SslContext, SslMethod, SslStream, SSL_VERIFY_NONE, SSL_VERIFY_PEER};
+use openssl::ssl::error::SslError;
+use openssl::x509::{X509, X509FileType};
+
+use libc::{c_int, c_uchar, c_void};
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::error::Error;
+use std::io::Error as IoError;
+use std::io::{ErrorKind, Result};
+use std::net::TcpStream;
+use std::path::Path;
+use std::ptr;
+use std::result::Result as StdResult;
+use std::slice;
+use std::str;
+
+/// Runs the TLS handshake on `socket` and checks the uplink certificate:
+/// against the pinned fingerprint if there is one, against the trusted CAs and
+/// `hostname` if verification is enabled. The client certificate, if any, is
+/// presented so that the uplink can match us by certfp.
+pub fn connect_tls(socket: TcpStream, tls: &TlsConfig, hostname: &str)
+                   -> Result<SslStream<TcpStream>> {
+    let min_version = try!(tls.min_version().map_err(invalid));
+    let pinned = try!(tls.fingerprint().map_err(invalid));
+    let hostname = tls.hostname.as_ref().map(|h| &h[..]).unwrap_or(hostname);
+
+    // Negotiates the best version both ends support; the minimum is enforced
+    // once the handshake is done.
+    let mut ctx = try!(ssl_to_io(SslContext::new(SslMethod::Sslv23)));
+    if tls.verify() {
+        ctx.set_verify(SSL_VERIFY_PEER, None);
+        match tls.ca_file {
+            Some(ref ca) => try!(err_to_io(ctx.set_CA_file(&Path::new(&ca[..])))),
+            None => try!(err_to_io(ctx.set_default_verify_paths()))
+        }
+    } else {
+        ctx.set_verify(SSL_VERIFY_NONE, None);
+    }
+    if let (&Some(ref cert), &Some(ref key)) = (&tls.cert_file, &tls.key_file) {
+        try!(err_to_io(ctx.set_certificate_file(&Path::new(&cert[..]), X509FileType::PEM)));
+        try!(err_to_io(ctx.set_private_key_file(&Path::new(&key[..]), X509FileType::PEM)));
+    }
+
+    let ssl = try!(ssl_to_io(Ssl::new(&ctx)));
+    try!(ssl_to_io(ssl.set_hostname(hostname)));
+    let stream = try!(ssl_to_io(SslStream::new_from(ssl, socket)));
+
+    let version = stream.get_ssl().version().to_owned();
+    match tls_version(&version[..]) {
+        Some(v) if v >= min_version => (),
+        _ => return Err(rejected(format!("Uplink negotiated {}, older than the configured minimum",
+                                         version)))
+    }
+
+    let cert = match stream.get_peer_certificate() {
+        Some(cert) => cert,
+        None => return Err(rejected("Uplink sent no certificate".to_owned()))
+    };
+    if let Some(pinned) = pinned {
+        let actual = cert.fingerprint(HashType::SHA256).unwrap_or(Vec::new());
+        if actual != pinned {
+            return Err(rejected(format!("Certificate fingerprint {} doesn't match the pinned one",
+                                        to_hex(&actual[..]))));
+        }
+    }
+    if tls.verify() && !matches_hostname(&cert, hostname) {
+        return Err(rejected(format!("Certificate isn't valid for {}", hostname)));
+    }
+
+    Ok(stream)
+}
+
+fn tls_version(name: &str) -> Option<TlsVersion> {
+    match name {
+        "TLSv1" => Some(TlsVersion::Tlsv1),
+        "TLSv1.1" => Some(TlsVersion::Tlsv1_1),
+        "TLSv1.2" => Some(TlsVersion::Tlsv1_2),
+        _ => None
+    }
+}
+
+/// Checks the certificate against `hostname`, allowing a wildcard for the
+/// leftmost label. As in RFC 6125, the DNS names of the subjectAltName are
+/// authoritative; the common name only counts for certificates without any.
+fn matches_hostname(cert: &X509, hostname: &str) -> bool {
+    match dns_alt_names(cert) {
+        Some(ref names) if !names.is_empty() => names.iter().any(|n| name_matches(&n[..], hostname)),
+        _ => match cert.subject_name().text_by_nid(Nid::CN) {
+            Some(cn) => name_matches(&cn[..], hostname),
+            None => false
+        }
+    }
+}
+
+/// What reading the subjectAltName takes from libcrypto; the openssl crate
+/// doesn't expose it.
+mod ffi {
+    use libc::{c_int, c_uchar, c_void};
+
+    pub static NID_SUBJECT_ALT_NAME: c_int = 85;
+    pub static GEN_DNS: c_int = 2;
+
+    /// GENERAL_NAME: a type, and a union of pointers
+    #[repr(C)]
+    pub struct GeneralName {
+        pub kind: c_int,
+        /// An ASN1_IA5STRING when `kind` is GEN_DNS
+        pub value: *mut c_void
+    }
+
+    extern "C" {
+        pub fn X509_get_ext_d2i(cert: *mut c_void, nid: c_int, crit: *mut c_int, idx: *mut c_int) -> *mut c_void;
+        pub fn sk_num(stack: *const c_void) -> c_int;
+        pub fn sk_value(stack: *const c_void, i: c_int) -> *mut c_void;
+        pub fn GENERAL_NAMES_free(names: *mut c_void);
+        pub fn ASN1_STRING_data(string: *mut c_void) -> *mut c_uchar;
+        pub fn ASN1_STRING_length(string: *mut c_void) -> c_int;
+    }
+}
+
+/// The DNS names of the subjectAltName extension, or None if there is none.
+fn dns_alt_names(cert: &X509) -> Option<Vec<String>> {
+    let mut dns = Vec::new();
+    unsafe {
+        let names = ffi::X509_get_ext_d2i(cert.get_handle() as *mut c_void, ffi::NID_SUBJECT_ALT_NAME,
+                                          ptr::null_mut::<c_int>(), ptr::null_mut::<c_int>());
+        if names.is_null() {
+            return None;
+        }
+        for i in 0..ffi::sk_num(names) {
+            let name = ffi::sk_value(names, i) as *const ffi::GeneralName;
+            if (*name).kind != ffi::GEN_DNS {
+                continue;
+            }
+            let data = ffi::ASN1_STRING_data((*name).value) as *const c_uchar;
+            let len = ffi::ASN1_STRING_length((*name).value);
+            // A name with a NUL in it is an attack, not a hostname
+            match str::from_utf8(slice::from_raw_parts(data, len as usize)) {
+                Ok(name) if !name.contains('\0') => dns.push(name.to_owned()),
+                _ => ()
+            }
+        }
+        ffi::GENERAL_NAMES_free(names);
+    }
+    Some(dns)
+}
+
+fn name_matches(pattern: &str, hostname: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let hostname = hostname.to_ascii_lowercase();
+    if pattern.starts_with("*.") {
+        match hostname.find('.') {
+            Some(dot) => dot > 0 && &hostname[dot..] == &pattern[1..],
+            None => false
+        }
+    } else {
+        pattern == hostname
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().connect(":")
+}
+
+fn invalid(detail: String) -> IoError {
+    IoError::new(ErrorKind::InvalidInput, "Invalid TLS settings.", Some(detail))
+}
+
+fn rejected(detail: String) -> IoError {
+    IoError::new(ErrorKind::PermissionDenied, "Uplink certificate rejected.", Some(detail))
+}
+
+/// Converts a Result<U, SslError> into a Result<U>.
+fn ssl_to_io<U>(res: StdResult<U, SslError>) -> Result<U> {
+    match res {
+        Ok(x) => Ok(x),
+        Err(e) => Err(IoError::new(ErrorKind::Other, "An SSL error occurred.",
+                                   Some(e.description().to_owned()))),
+    }
+}
+
+fn err_to_io(err: Option<SslError>) -> Result<()> {
+    match err {
+        Some(e) => ssl_to_io(Err(e)),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connect_tls, name_matches};
+    use conf::TlsConfig;
+    use irc::Transport;
+
+    use openssl::ssl::{SslContext, SslMethod, SslStream};
+    use openssl::x509::X509FileType;
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::Path;
+    use std::thread;
+
+    static FINGERPRINT: &'static str =
+        "B4:A2:C0:FA:D8:51:3F:00:48:DD:FF:6D:44:21:69:18:09:E0:8B:65:57:7C:7A:F9:5E:E3:00:0F:D5:75:04:EB";
+
+    /// Accepts a single TLS client with one of the self-signed certificates in
+    /// testdata, and greets it: `link` names the server in its common name,
+    /// `san` only in its subjectAltName.
+    fn listener(cert: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+            ctx.set_certificate_file(&Path::new(&format!("src/irc/testdata/{}.pem", cert)), X509FileType::PEM);
+            ctx.set_private_key_file(&Path::new(&format!("src/irc/testdata/{}.key", cert)), X509FileType::PEM);
+            let (socket, _) = listener.accept().unwrap();
+            if let Ok(mut stream) = SslStream::new_server(&ctx, socket) {
+                let _ = stream.write_all(b"PASS :hello\r\n");
+                let _ = stream.flush();
+            }
+        });
+        port
+    }
+
+    fn connect(tls: TlsConfig) -> bool {
+        connect_to("link", tls)
+    }
+
+    fn connect_to(cert: &'static str, tls: TlsConfig) -> bool {
+        let port = listener(cert);
+        let socket = TcpStream::connect(&format!("127.0.0.1:{}", port)[..]).unwrap();
+        match connect_tls(socket, &tls, "hub.test.org") {
+            Ok(mut stream) => {
+                let mut greeting = [0; 13];
+                stream.read(&mut greeting).unwrap();
+                &greeting[..] == b"PASS :hello\r\n"
+            }
+            Err(_) => false
+        }
+    }
+
+    #[test]
+    fn pinned_fingerprint() {
+        assert!(connect(TlsConfig { fingerprint: Some(FINGERPRINT.to_string()), ..Default::default() }));
+        let wrong = FINGERPRINT.replace("B4:A2", "00:00");
+        assert!(!connect(TlsConfig { fingerprint: Some(wrong), ..Default::default() }));
+    }
+
+    #[test]
+    fn ca_verification() {
+        // Self-signed, so the certificate is its own CA
+        let ca = TlsConfig { ca_file: Some("src/irc/testdata/link.pem".to_string()),
+                             ..Default::default() };
+        assert!(connect(ca.clone()));
+        assert!(!connect(TlsConfig { hostname: Some("evil.test.org".to_string()), ..ca }));
+        assert!(!connect(TlsConfig::default()));
+    }
+
+    #[test]
+    fn pending_data() {
+        let port = listener("link");
+        let socket = TcpStream::connect(&format!("127.0.0.1:{}", port)[..]).unwrap();
+        let tls = TlsConfig { fingerprint: Some(FINGERPRINT.to_string()), ..Default::default() };
+        let mut stream = connect_tls(socket, &tls, "hub.test.org").ok().unwrap();
+        // The whole greeting came in one record: the rest is buffered, not on the socket
+        let mut start = [0; 5];
+        stream.read(&mut start).unwrap();
+        assert_eq!(Transport::pending(&stream), 8);
+    }
+
+    #[test]
+    fn subject_alt_names() {
+        let ca = TlsConfig { ca_file: Some("src/irc/testdata/san.pem".to_string()), ..Default::default() };
+        assert!(connect_to("san", ca.clone()));
+        assert!(connect_to("san", TlsConfig { hostname: Some("leaf.hubs.test.org".to_string()), ..ca.clone() }));
+        assert!(!connect_to("san", TlsConfig { hostname: Some("evil.test.org".to_string()), ..ca.clone() }));
+        // Only DNS names count, not the IP address entry
+        assert!(!connect_to("san", TlsConfig { hostname: Some("127.0.0.1".to_string()), ..ca }));
+    }
+
+    #[test]
+    fn hostnames() {
+        assert!(name_matches("hub.test.org", "HUB.test.org"));
+        assert!(name_matches("*.test.org", "hub.test.org"));
+        assert!(!name_matches("*.test.org", "test.org"));
+        assert!(!name_matches("*.test.org", "a.hub.test.org"));
+    }
+}
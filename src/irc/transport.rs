@@ -0,0 +1,190 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+
+#[cfg(feature = "ssl")] use openssl::ssl::SslStream;
+
+/// A byte stream to the uplink. `IrcStream` only ever talks to its uplink
+/// through this trait, so new kinds of links don't need any change there.
+///
+/// The main loop only reads once the file descriptor is readable, but reads
+/// should still time out (with `WouldBlock` or `TimedOut`) rather than block
+/// forever.
+pub trait Transport: Read + Write {
+    /// Describes the other end, for logs.
+    fn peer(&self) -> String;
+
+    /// File descriptor to poll for readability. Transports without one are
+    /// read from on every iteration of the main loop.
+    fn raw_fd(&self) -> Option<RawFd>;
+
+    /// Bytes already received and buffered, which polling the file descriptor
+    /// doesn't report: TLS decrypts whole records at once.
+    fn pending(&self) -> usize {
+        0
+    }
+}
+
+impl Transport for TcpStream {
+    fn peer(&self) -> String {
+        self.peer_addr().map(|a| a.to_string()).unwrap_or("unknown TCP peer".to_string())
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+#[cfg(feature = "ssl")]
+impl Transport for SslStream<TcpStream> {
+    fn peer(&self) -> String {
+        format!("{} (TLS)", self.get_ref().peer())
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.get_ref().raw_fd()
+    }
+
+    fn pending(&self) -> usize {
+        SslStream::pending(self)
+    }
+}
+
+impl Transport for UnixStream {
+    fn peer(&self) -> String {
+        self.peer_addr().ok().and_then(|a| a.as_pathname().map(|p| p.display().to_string()))
+                        .unwrap_or("unknown unix socket".to_string())
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+/// One end of an in-memory, single-threaded pipe. Reads return `WouldBlock`
+/// when no data is waiting, like a socket with a read timeout would, and EOF
+/// once the other end was dropped.
+pub struct Duplex {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<VecDeque<u8>>>
+}
+
+/// Creates a pair of connected `Duplex` ends.
+pub fn duplex() -> (Duplex, Duplex) {
+    let a = Rc::new(RefCell::new(VecDeque::new()));
+    let b = Rc::new(RefCell::new(VecDeque::new()));
+    (Duplex { incoming: a.clone(), outgoing: b.clone() }, Duplex { incoming: b, outgoing: a })
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            // Only our own handles left: the other end is gone
+            return if Rc::strong_count(&self.outgoing) == 1 {
+                Ok(0)
+            } else {
+                Err(Error::new(ErrorKind::WouldBlock, "No data in the pipe.", None))
+            };
+        }
+        let n = cmp::min(buf.len(), incoming.len());
+        for (dst, src) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.outgoing.borrow_mut().extend(buf.iter().cloned());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Duplex {
+    fn peer(&self) -> String {
+        "in-memory pipe".to_string()
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// Stands in for the link until the first one is established: reads and writes
+/// fail with `NotConnected`.
+pub struct Unlinked;
+
+impl Read for Unlinked {
+    fn read(&mut self, _: &mut [u8]) -> Result<usize> {
+        Err(Error::new(ErrorKind::NotConnected, "Not linked yet.", None))
+    }
+}
+
+impl Write for Unlinked {
+    fn write(&mut self, _: &[u8]) -> Result<usize> {
+        Err(Error::new(ErrorKind::NotConnected, "Not linked yet.", None))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Unlinked {
+    fn peer(&self) -> String {
+        "no uplink".to_string()
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn peer(&self) -> String {
+        (**self).peer()
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        (**self).raw_fd()
+    }
+
+    fn pending(&self) -> usize {
+        (**self).pending()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::duplex;
+    use std::io::{ErrorKind, Read, Write};
+
+    #[test]
+    fn pipe() {
+        let (mut us, mut uplink) = duplex();
+        let mut buf = [0; 64];
+        assert_eq!(us.read(&mut buf).err().map(|e| e.kind()), Some(ErrorKind::WouldBlock));
+
+        uplink.write_all(b"PING :Ping.MindForge.org\r\n").unwrap();
+        let n = us.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"PING :Ping.MindForge.org\r\n");
+
+        us.write_all(b"PONG :Ping.MindForge.org\r\n").unwrap();
+        let n = uplink.read(&mut buf[..4]).unwrap();
+        assert_eq!(&buf[..n], b"PONG");
+
+        drop(uplink);
+        assert_eq!(us.read(&mut buf).ok(), Some(0));
+    }
+}
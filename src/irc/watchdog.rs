@@ -0,0 +1,107 @@
+use std::cmp;
+
+/// What the link should do after checking the watchdog.
+#[derive(Debug, PartialEq)]
+pub enum WatchdogAction {
+    Nothing,
+    /// Time to send a PING to the uplink.
+    SendPing,
+    /// The uplink didn't answer our last PING in time; the link is dead.
+    Timeout
+}
+
+/// Keeps track of the PINGs we send to the uplink and of their replies.
+/// All times are in milliseconds, from a monotonic clock.
+pub struct Watchdog {
+    /// Interval between PINGs
+    freq: u64,
+    /// How long we wait for a PONG before giving up on the link
+    timeout: u64,
+    /// When the next PING is due
+    next_ping: u64,
+    /// When the PING we're waiting a reply for was sent
+    pending: Option<u64>,
+    /// Round trip time of the last PING
+    lag: Option<u64>
+}
+
+impl Watchdog {
+    pub fn new(freq_secs: u32, timeout_secs: u32, now: u64) -> Watchdog {
+        let freq = freq_secs as u64 * 1000;
+        Watchdog { freq: freq, timeout: timeout_secs as u64 * 1000, next_ping: now + freq,
+                   pending: None, lag: None }
+    }
+
+    /// Starts over, for a new link.
+    pub fn reset(&mut self, now: u64) {
+        self.next_ping = now + self.freq;
+        self.pending = None;
+        self.lag = None;
+    }
+
+    /// Changes the PING interval and timeout, e.g. after a configuration reload.
+    /// They apply from the next PING on.
+    pub fn set_period(&mut self, freq_secs: u32, timeout_secs: u32) {
+        self.freq = freq_secs as u64 * 1000;
+        self.timeout = timeout_secs as u64 * 1000;
+    }
+
+    pub fn check(&mut self, now: u64) -> WatchdogAction {
+        match self.pending {
+            Some(sent) if now - sent >= self.timeout => WatchdogAction::Timeout,
+            Some(_) => WatchdogAction::Nothing,
+            None if now >= self.next_ping => {
+                self.pending = Some(now);
+                WatchdogAction::SendPing
+            }
+            None => WatchdogAction::Nothing
+        }
+    }
+
+    /// When `check` will next have something to report.
+    pub fn next_deadline(&self) -> u64 {
+        match self.pending {
+            Some(sent) => sent + self.timeout,
+            None => self.next_ping
+        }
+    }
+
+    pub fn on_pong(&mut self, now: u64) {
+        if let Some(sent) = self.pending.take() {
+            self.lag = Some(now - sent);
+            self.next_ping = sent + self.freq;
+        }
+    }
+
+    /// The lag measured with the last PING, in milliseconds. While a PING is
+    /// outstanding for longer than that, the time waited so far is reported instead.
+    pub fn lag(&self, now: u64) -> Option<u64> {
+        match (self.lag, self.pending) {
+            (Some(lag), Some(sent)) => Some(cmp::max(lag, now - sent)),
+            (None, Some(sent)) => Some(now - sent),
+            (lag, None) => lag
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Watchdog, WatchdogAction};
+
+    #[test]
+    fn ping_pong() {
+        let mut wd = Watchdog::new(90, 60, 0);
+        assert_eq!(wd.check(89999), WatchdogAction::Nothing);
+        assert_eq!(wd.check(90000), WatchdogAction::SendPing);
+        assert_eq!(wd.check(90100), WatchdogAction::Nothing);
+        wd.on_pong(90250);
+        assert_eq!(wd.lag(90300), Some(250));
+        assert_eq!(wd.next_deadline(), 180000);
+        assert_eq!(wd.check(179999), WatchdogAction::Nothing);
+        assert_eq!(wd.check(180000), WatchdogAction::SendPing);
+        assert_eq!(wd.lag(181000), Some(1000));
+        assert_eq!(wd.next_deadline(), 240000);
+        assert_eq!(wd.check(239999), WatchdogAction::Nothing);
+        assert_eq!(wd.check(240000), WatchdogAction::Timeout);
+    }
+}
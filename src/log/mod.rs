@@ -0,0 +1,560 @@
+use cmd::{IrcMsg, MAX_LINE_LEN};
+use conf::{Config, LogConfig};
+
+use time;
+
+use std::ascii::AsciiExt;
+use std::borrow::{Cow, ToOwned};
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Error, ErrorKind, Result, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Logs a message: `log!(Warn, Link, "Reconnect failed: {}", e)`. The message
+/// isn't even formatted if no sink wants it.
+macro_rules! log {
+    ($level:ident, $category:ident, $($arg:tt)+) => (
+        if ::log::enabled(::log::Level::$level, ::log::Category::$category) {
+            ::log::log(::log::Level::$level, ::log::Category::$category, &format!($($arg)+))
+        }
+    )
+}
+
+macro_rules! error {
+    ($category:ident, $($arg:tt)+) => (log!(Error, $category, $($arg)+))
+}
+
+macro_rules! warn {
+    ($category:ident, $($arg:tt)+) => (log!(Warn, $category, $($arg)+))
+}
+
+macro_rules! info {
+    ($category:ident, $($arg:tt)+) => (log!(Info, $category, $($arg)+))
+}
+
+macro_rules! debug {
+    ($category:ident, $($arg:tt)+) => (log!(Debug, $category, $($arg)+))
+}
+
+macro_rules! trace {
+    ($category:ident, $($arg:tt)+) => (log!(Trace, $category, $($arg)+))
+}
+
+/// Lines the channel sink keeps while they can't be sent; older ones are dropped.
+static MAX_CHANNEL_BACKLOG: usize = 100;
+
+/// Syslog facility we log as (daemon).
+static SYSLOG_FACILITY: u8 = 3;
+
+/// How important a message is. Sinks have a threshold, and only get messages
+/// at least that important.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    /// Raw link traffic
+    Trace
+}
+
+/// What a message is about, so that sinks can pick what they are interested in.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Category {
+    /// Startup, shutdown and the admin socket
+    Core,
+    /// Connections to the uplink, and the traffic on them
+    Link,
+    /// Server-to-server protocol handling
+    Protocol,
+    /// The control bot
+    Bot,
+    /// Authentication failures, rejected certificates, admin commands
+    Security
+}
+
+impl Level {
+    pub fn parse(name: &str) -> Option<Level> {
+        match &name.to_ascii_lowercase()[..] {
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE"
+        }
+    }
+
+    /// Syslog severity.
+    fn severity(&self) -> u8 {
+        match *self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7
+        }
+    }
+}
+
+impl Category {
+    pub fn parse(name: &str) -> Option<Category> {
+        match &name.to_ascii_lowercase()[..] {
+            "core" => Some(Category::Core),
+            "link" => Some(Category::Link),
+            "protocol" => Some(Category::Protocol),
+            "bot" => Some(Category::Bot),
+            "security" => Some(Category::Security),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Category::Core => "core",
+            Category::Link => "link",
+            Category::Protocol => "protocol",
+            Category::Bot => "bot",
+            Category::Security => "security"
+        }
+    }
+}
+
+/// A message being logged.
+pub struct Record<'a> {
+    pub level: Level,
+    pub category: Category,
+    pub message: &'a str,
+    pub time: time::Tm
+}
+
+impl<'a> fmt::Display for Record<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{}] {}: {}",
+               time::strftime("%Y-%m-%d %H:%M:%S", &self.time).unwrap_or(String::new()),
+               self.level.name(), self.category.name(), self.message)
+    }
+}
+
+/// Somewhere log messages go.
+pub trait Sink {
+    fn write(&mut self, record: &Record) -> Result<()>;
+
+    /// Messages to send to the uplink on behalf of the sink, if it logs to IRC.
+    fn take_msgs(&mut self) -> Vec<IrcMsg> {
+        vec![]
+    }
+}
+
+struct Output {
+    level: Level,
+    /// Empty for every category
+    categories: Vec<Category>,
+    sink: Box<Sink>
+}
+
+impl Output {
+    fn wants(&self, level: Level, category: Category) -> bool {
+        level <= self.level && (self.categories.is_empty() || self.categories.contains(&category))
+    }
+}
+
+/// Dispatches log messages to the sinks that want them.
+pub struct Logger {
+    outputs: Vec<Output>
+}
+
+impl Default for Logger {
+    /// What is used until the configuration is loaded: informational messages
+    /// and above, to stderr.
+    fn default() -> Logger {
+        let mut logger = Logger::new();
+        logger.add(Level::Info, vec![], Box::new(StderrSink));
+        logger
+    }
+}
+
+impl Logger {
+    pub fn new() -> Logger {
+        Logger { outputs: Vec::new() }
+    }
+
+    /// Sets up the sinks of the `logging` configuration section.
+    pub fn from_config(conf: &Config) -> Result<Logger> {
+        let mut logger = Logger::new();
+        for output in conf.get_logging().iter() {
+            let level = try!(output.level().map_err(|e| invalid_output(output, e)));
+            let categories = try!(output.categories().map_err(|e| invalid_output(output, e)));
+            let sink = try!(open_sink(output, conf));
+            logger.add(level, categories, sink);
+        }
+        if let Some(level) = conf.get_log_level() {
+            logger.set_level(level);
+        }
+        Ok(logger)
+    }
+
+    /// Sends messages of `categories` (all of them if empty) at `level` or above to `sink`.
+    pub fn add(&mut self, level: Level, categories: Vec<Category>, sink: Box<Sink>) {
+        self.outputs.push(Output { level: level, categories: categories, sink: sink });
+    }
+
+    /// Makes every output log at `level` and above, e.g. from the command line.
+    pub fn set_level(&mut self, level: Level) {
+        for output in self.outputs.iter_mut() {
+            output.level = level;
+        }
+    }
+
+    pub fn enabled(&self, level: Level, category: Category) -> bool {
+        self.outputs.iter().any(|o| o.wants(level, category))
+    }
+
+    pub fn log(&mut self, level: Level, category: Category, message: &str) {
+        let record = Record { level: level, category: category, message: message, time: time::now() };
+        for output in self.outputs.iter_mut() {
+            if output.wants(level, category) {
+                if let Err(e) = output.sink.write(&record) {
+                    // Nowhere else to complain
+                    let _ = writeln!(&mut io::stderr(), "Failed to log: {} ({})", e, record);
+                }
+            }
+        }
+    }
+
+    pub fn take_msgs(&mut self) -> Vec<IrcMsg> {
+        let mut msgs = Vec::new();
+        for output in self.outputs.iter_mut() {
+            msgs.extend(output.sink.take_msgs().into_iter());
+        }
+        msgs
+    }
+}
+
+thread_local!(static LOGGER: RefCell<Logger> = RefCell::new(Logger::default()));
+
+/// Replaces the logger used by the logging macros.
+pub fn init(logger: Logger) {
+    LOGGER.with(|l| *l.borrow_mut() = logger);
+}
+
+pub fn enabled(level: Level, category: Category) -> bool {
+    LOGGER.with(|l| l.borrow().enabled(level, category))
+}
+
+/// Logs through the current logger; the macros are more convenient.
+pub fn log(level: Level, category: Category, message: &str) {
+    LOGGER.with(|l| l.borrow_mut().log(level, category, message));
+}
+
+/// Logs a raw line of link traffic, hiding passwords.
+pub fn traffic(incoming: bool, line: &str) {
+    if enabled(Level::Trace, Category::Link) {
+        let arrow = if incoming { "<<" } else { ">>" };
+        log(Level::Trace, Category::Link,
+            &format!("{} {}", arrow, redact(line.trim_right_matches(|c| c == '\r' || c == '\n'))));
+    }
+}
+
+/// Log lines for IRC sinks, to be sent to the uplink by the main loop.
+pub fn take_msgs() -> Vec<IrcMsg> {
+    LOGGER.with(|l| l.borrow_mut().take_msgs())
+}
+
+/// Hides the parameters of PASS lines.
+pub fn redact(line: &str) -> Cow<str> {
+    let mut rest = line;
+    // Skip tags and prefix
+    for &sigil in ['@', ':'].iter() {
+        if rest.starts_with(sigil) {
+            rest = match rest.find(' ') {
+                Some(pos) => rest[pos..].trim_left_matches(' '),
+                None => return Cow::Borrowed(line)
+            };
+        }
+    }
+    let command = rest.split(' ').next().unwrap_or("");
+    if command.eq_ignore_ascii_case("PASS") && rest.len() > command.len() {
+        let head = line.len() - rest.len() + command.len();
+        Cow::Owned(format!("{} <redacted>", &line[..head]))
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+fn open_sink(output: &LogConfig, conf: &Config) -> Result<Box<Sink>> {
+    Ok(match &output.sink[..] {
+        "stderr" => Box::new(StderrSink) as Box<Sink>,
+        "file" => {
+            let path = try!(output.path.as_ref().ok_or(
+                invalid_output(output, "a file sink needs a path".to_owned())));
+            Box::new(try!(FileSink::open(Path::new(path), output.max_size.unwrap_or(0),
+                                         output.keep.unwrap_or(5))))
+        }
+        "syslog" => {
+            let path = output.path.as_ref().map(|p| &p[..]).unwrap_or("/dev/log");
+            Box::new(try!(SyslogSink::connect(Path::new(path), "irctools")))
+        }
+        "channel" => {
+            let channel = try!(output.channel.as_ref().ok_or(
+                invalid_output(output, "a channel sink needs a channel".to_owned())));
+            Box::new(ChannelSink::new(conf.get_cbot_nick(), channel))
+        }
+        other => return Err(invalid_output(output, format!("unknown sink '{}'", other)))
+    })
+}
+
+fn invalid_output(output: &LogConfig, detail: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, "Invalid logging settings.",
+               Some(format!("{} sink: {}", output.sink, detail)))
+}
+
+pub struct StderrSink;
+
+impl Sink for StderrSink {
+    fn write(&mut self, record: &Record) -> Result<()> {
+        writeln!(&mut io::stderr(), "{}", record)
+    }
+}
+
+/// Appends to a file, which is rotated once it grows beyond `max_size` bytes:
+/// `irctools.log` becomes `irctools.log.1`, `irctools.log.1` becomes
+/// `irctools.log.2`, and so on up to `keep` old files.
+pub struct FileSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// 0 never rotates
+    max_size: u64,
+    keep: u32
+}
+
+impl FileSink {
+    pub fn open(path: &Path, max_size: u64, keep: u32) -> Result<FileSink> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        let size = try!(fs::metadata(path)).len();
+        Ok(FileSink { path: path.to_path_buf(), file: file, size: size, max_size: max_size,
+                      keep: keep })
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(&format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.keep > 0 {
+            for n in (1..self.keep).rev() {
+                // Missing files are fine
+                let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            try!(fs::rename(&self.path, self.rotated(1)));
+        }
+        self.file = try!(OpenOptions::new().create(true).write(true).truncate(true).open(&self.path));
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, record: &Record) -> Result<()> {
+        let line = format!("{}\n", record);
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            try!(self.rotate());
+        }
+        try!(self.file.write_all(line.as_bytes()));
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Sends RFC 3164 messages to the local syslog daemon.
+pub struct SyslogSink {
+    socket: UnixDatagram,
+    tag: String
+}
+
+impl SyslogSink {
+    pub fn connect(path: &Path, tag: &str) -> Result<SyslogSink> {
+        let socket = try!(UnixDatagram::unbound());
+        try!(socket.connect(path));
+        Ok(SyslogSink { socket: socket, tag: tag.to_owned() })
+    }
+}
+
+impl Sink for SyslogSink {
+    fn write(&mut self, record: &Record) -> Result<()> {
+        self.socket.send(syslog_line(record, &self.tag[..], process::id()).as_bytes()).map(|_| ())
+    }
+}
+
+/// The hostname is left out, syslogd fills it in for local messages.
+fn syslog_line(record: &Record, tag: &str, pid: u32) -> String {
+    format!("<{}>{} {}[{}]: {}: {}",
+            SYSLOG_FACILITY * 8 + record.level.severity(),
+            time::strftime("%b %e %H:%M:%S", &record.time).unwrap_or(String::new()),
+            tag, pid, record.category.name(), record.message)
+}
+
+/// Logs to an IRC channel, such as `#ServicesLog`, as the control bot. The
+/// control bot should be in that channel (see its `chans`). Raw traffic is
+/// never sent there, since sending the log lines would generate more of it.
+/// Lines are kept until the main loop takes them, once the bot is introduced.
+pub struct ChannelSink {
+    nick: String,
+    channel: String,
+    backlog: VecDeque<IrcMsg>
+}
+
+impl ChannelSink {
+    pub fn new(nick: &str, channel: &str) -> ChannelSink {
+        ChannelSink { nick: nick.to_owned(), channel: channel.to_owned(), backlog: VecDeque::new() }
+    }
+}
+
+impl Sink for ChannelSink {
+    fn write(&mut self, record: &Record) -> Result<()> {
+        if record.level == Level::Trace {
+            return Ok(());
+        }
+        // Control characters would break the line, or be sent as formatting
+        let text: String = format!("[{}] {}: {}", record.level.name(), record.category.name(), record.message)
+                               .chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+        // :<nick> PRIVMSG <channel> :<text>\r\n
+        let overhead = self.nick.len() + self.channel.len() + 14;
+        for part in split_text(&text[..], cmp::max(MAX_LINE_LEN.saturating_sub(overhead), 4)).into_iter() {
+            if self.backlog.len() >= MAX_CHANNEL_BACKLOG {
+                self.backlog.pop_front();
+            }
+            self.backlog.push_back(IrcMsg::new(Some(self.nick.clone()), "PRIVMSG",
+                                               vec![self.channel.clone(), part.to_owned()]));
+        }
+        Ok(())
+    }
+
+    fn take_msgs(&mut self) -> Vec<IrcMsg> {
+        self.backlog.drain(..).collect()
+    }
+}
+
+/// Cuts `text` in pieces of at most `max` bytes, without splitting characters.
+fn split_text(text: &str, max: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while rest.len() > max {
+        let mut end = max;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        parts.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    parts.push(rest);
+    parts
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Category, ChannelSink, FileSink, Level, Logger, Record, Sink, redact, syslog_line};
+    use cmd::MAX_LINE_LEN;
+
+    use time;
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    fn record(level: Level, message: &str) -> Record {
+        Record { level: level, category: Category::Link, message: message, time: time::now() }
+    }
+
+    #[test]
+    fn pass_redaction() {
+        assert_eq!(redact("PASS :rustp0w3r!"), "PASS <redacted>");
+        assert_eq!(redact(":Ping.MindForge.org pass secret"), ":Ping.MindForge.org pass <redacted>");
+        assert_eq!(redact("@time=1 :irc PASS :x"), "@time=1 :irc PASS <redacted>");
+        assert_eq!(redact("PRIVMSG #chan :PASS secret"), "PRIVMSG #chan :PASS secret");
+        assert_eq!(redact("PASSWORD x"), "PASSWORD x");
+    }
+
+    #[test]
+    fn filtering() {
+        let mut logger = Logger::new();
+        logger.add(Level::Info, vec![Category::Security], Box::new(ChannelSink::new("Tools", "#Log")));
+        logger.add(Level::Trace, vec![], Box::new(ChannelSink::new("Tools", "#Debug")));
+        assert!(logger.enabled(Level::Trace, Category::Link));
+
+        logger.log(Level::Warn, Category::Security, "Wrong link password");
+        logger.log(Level::Debug, Category::Security, "Not important");
+        logger.log(Level::Error, Category::Bot, "Not security");
+        logger.log(Level::Trace, Category::Link, "<< PING :x");
+
+        let msgs = logger.take_msgs();
+        let texts: Vec<(&str, &str)> = msgs.iter().map(|m| (&m.params[0][..], &m.params[1][..])).collect();
+        assert_eq!(texts, vec![("#Log", "[WARN] security: Wrong link password"),
+                               ("#Debug", "[WARN] security: Wrong link password"),
+                               ("#Debug", "[DEBUG] security: Not important"),
+                               ("#Debug", "[ERROR] bot: Not security")]);
+        assert!(logger.take_msgs().is_empty());
+    }
+
+    #[test]
+    fn long_channel_lines() {
+        let mut sink = ChannelSink::new("Tools", "#Log");
+        let message = format!("Dry run, not sending KILL Spammer :{}\r\nPRIVMSG #x :hi", (0..300).map(|_| "é").collect::<String>());
+        sink.write(&record(Level::Info, &message[..])).unwrap();
+
+        let msgs = sink.take_msgs();
+        assert_eq!(msgs.len(), 2);
+        for msg in msgs.iter() {
+            let line = msg.to_wire().unwrap();
+            assert!(line.len() <= MAX_LINE_LEN);
+        }
+        let text: String = msgs.iter().map(|m| &m.params[1][..]).collect();
+        assert!(text.starts_with("[INFO] link: Dry run"));
+        assert!(text.ends_with("  PRIVMSG #x :hi"));
+    }
+
+    #[test]
+    fn rotation() {
+        let path = env::temp_dir().join(format!("irctools-log-test-{}.log", ::std::process::id()));
+        let mut sink = FileSink::open(&path, 100, 2).unwrap();
+        for i in 0..4 {
+            sink.write(&record(Level::Info, &format!("message {} with some padding to fill the file", i))).unwrap();
+        }
+
+        let mut current = String::new();
+        File::open(&path).unwrap().read_to_string(&mut current).unwrap();
+        assert!(current.contains("message 3"));
+        assert!(!current.contains("message 2"));
+        for n in 1..3 {
+            let rotated = sink.rotated(n);
+            assert!(rotated.exists());
+            fs::remove_file(rotated).unwrap();
+        }
+        assert!(!sink.rotated(3).exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn syslog_format() {
+        let mut rec = record(Level::Warn, "Link lost");
+        rec.time = time::strptime("2015-03-07 09:05:01", "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!(syslog_line(&rec, "irctools", 42), "<28>Mar  7 09:05:01 irctools[42]: link: Link lost");
+    }
+}
@@ -1,82 +1,327 @@
 #![feature(io)]
 #![feature(core)]
+#![cfg_attr(test, feature(test))]
 
 extern crate "rustc-serialize" as rustc_serialize;
 extern crate encoding;
 #[cfg(feature = "ssl")]
 extern crate openssl;
 extern crate time;
+extern crate socket2;
+extern crate libc;
+extern crate getopts;
+extern crate toml;
+#[cfg(test)]
+extern crate quickcheck;
+#[cfg(test)]
+extern crate test;
 
+#[macro_use]
+mod log;
+mod admin;
+mod bot;
+mod cli;
+mod daemon;
+mod event;
 mod irc;
 mod cmd;
 mod conf;
 mod protocol;
+mod network;
+mod sched;
 
-use irc::IrcStream;
-use conf::Config;
-use std::io::Result;
+use admin::AdminSocket;
+use bot::ControlBot;
+use cli::Action;
+use daemon::PidFile;
+use event::Poll;
+use event::signal;
+use sched::Scheduler;
+use irc::{IrcStream, Backoff};
+use log::Logger;
+use conf::{Config, ConfigError};
+use std::env;
+use std::io::{ErrorKind, Result};
 use std::path::Path;
 use std::error::Error;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::process;
 
 use protocol::unreal::Unreal;
 use protocol::ServerProtocol;
 
 // TODO deal with case-sensitiveness?
-// TODO Disconnect / netsplit / reconnect and resync
 
 fn main() {
 
-    // TODO make cmd-line option to pass conf file path
     // TODO Better and more descriptive error handling
 
-    let config = Rc::new(RefCell::new(match load_config("tools.conf") {
+    let argv: Vec<String> = env::args().collect();
+    let args = match cli::parse(&argv[1..]) {
+        Ok(args) => args,
+        Err(e) => {
+            println!("{}\n{}", e, cli::usage(&argv[0]));
+            process::exit(2);
+        }
+    };
+    match args.action {
+        Action::Help => { println!("{}", cli::usage(&argv[0])); return () }
+        Action::Version => {
+            println!("irctools {}, protocol {}, features: {}", env!("CARGO_PKG_VERSION"),
+                     Unreal::protocol_version(), cli::features().connect(", "));
+            return ()
+        }
+        Action::Run | Action::CheckConfig => ()
+    }
+
+    let config = Rc::new(RefCell::new(match load_config(&args.config[..]) {
         Ok(cfg) => cfg,
-        // An ugly hack to get around the following warning:
-        // --
-        // use of deprecated item: use the Error trait's description method instead, #[warn(deprecated)] on by default
-        //--
-        // std::io::Error will go away at some point; when it does, make it e.description() again
-        Err(e) => { println!("ERROR loading conf: {}", (&e as &Error).description()); return () }
+        Err(errors) => {
+            // Every problem at once, so that they can all be fixed in one go
+            for e in errors.iter() {
+                if args.action == Action::CheckConfig {
+                    println!("{}: {}", args.config, e);
+                } else {
+                    error!(Core, "Invalid configuration: {}", e);
+                }
+            }
+            process::exit(1);
+        }
     }));
+    if args.action == Action::CheckConfig {
+        println!("{}: OK", args.config);
+        return ();
+    }
+    config.borrow_mut().set_dry_run(args.dry_run);
+    config.borrow_mut().set_log_level(args.log_level);
+
+    match Logger::from_config(&config.borrow()) {
+        Ok(logger) => log::init(logger),
+        Err(e) => { error!(Core, "Failed to set up logging: {}", e); process::exit(1) }
+    }
+    if args.daemon {
+        if let Err(e) = daemon::daemonize() {
+            error!(Core, "Failed to detach: {}", e);
+            process::exit(1);
+        }
+    }
+    // Only once detached, the PID changes
+    let pidfile = match args.pidfile {
+        Some(ref path) => match PidFile::create(&Path::new(path)) {
+            Ok(pidfile) => Some(pidfile),
+            Err(e) => { error!(Core, "Failed to create the PID file: {}", e); process::exit(1) }
+        },
+        None => None
+    };
+    let status = run(config, args.dry_run);
+    drop(pidfile);
+    process::exit(status);
+}
+
+/// Links to the network and serves until told to shut down. Returns the exit
+/// status rather than exiting, so that the PID file is removed.
+fn run(config: Rc<RefCell<Config>>, dry_run: bool) -> i32 {
+    if let Err(e) = signal::install() {
+        error!(Core, "Failed to install signal handlers: {}", e);
+        return 1;
+    }
+    if dry_run {
+        info!(Core, "Dry run: enforcement actions will be logged, not sent");
+    }
 
     // TODO Do not hardcode protocol handler
-    let ircstream = match IrcStream::new(config.clone(), Unreal::new(config.clone())) {
+    let scheduler = Rc::new(RefCell::new(Scheduler::default()));
+    let ircstream = match IrcStream::new(config.clone(),
+                                         Unreal::new(config.clone(), scheduler.clone()),
+                                         scheduler) {
         Ok(stream) => stream,
-        Err(_) => { println!("connection error"); return () }
-    }; 
+        // Bad charsets are reported here, before connecting
+        Err(e) => { error!(Core, "Failed to start: {}", e); return 1 }
+    };
+
+    let (min_delay, max_delay) = {
+        let conf = config.borrow();
+        (conf.get_reconnect_min(), conf.get_reconnect_max())
+    };
+    let mut backoff = Backoff::new(min_delay, max_delay);
+    let cbot = ControlBot::new(config.clone());
+    let mut admin = match config.borrow().get_admin_socket() {
+        Some(path) => match AdminSocket::bind(&Path::new(path)) {
+            Ok(admin) => Some(admin),
+            // Before linking, so that there is nothing to leave
+            Err(e) => { error!(Core, "Failed to open the admin socket: {}", e); return 1 }
+        },
+        None => None
+    };
+
+    // The first link is tried right away, and retried like any other
+    let mut wait = false;
+    loop {
+        loop {
+            if wait {
+                let delay = backoff.next_delay();
+                info!(Link, "Reconnecting in {} seconds", delay);
+                if idle(&ircstream, &cbot, &mut admin, delay as u64 * 1000) == Exit::Shutdown {
+                    info!(Core, "Shutting down");
+                    return 0;
+                }
+            }
+            wait = true;
+            match ircstream.reconnect() {
+                Ok(_) => break,
+                Err(e) => warn!(Link, "Failed to link: {}", (&e as &Error).description())
+            }
+        }
+
+        let linked_at = time::get_time().sec;
+        if enter_main_loop(&ircstream, &cbot, &mut admin) == Exit::Shutdown {
+            info!(Core, "Shutting down");
+            if let Err(e) = ircstream.shutdown("Shutting down") {
+                warn!(Link, "Failed to leave the network cleanly: {}", e);
+            }
+            return 0;
+        }
 
-    match ircstream.introduce() {
-        Ok(_) => (),
-        Err(_) => { println!("introduce() failed"); return () }
+        // Only start over from the minimum delay if the link was healthy for a while,
+        // so that an uplink rejecting us right away isn't hammered.
+        if time::get_time().sec - linked_at > max_delay as i64 {
+            backoff.reset();
+        }
+        // A relink to apply a new configuration is deliberate: no reason to wait
+        wait = !ircstream.is_relinking();
+        if !wait {
+            backoff.reset();
+        }
     }
+}
 
-    enter_main_loop(ircstream);
+fn load_config(file_path: &str) -> ::std::result::Result<Config, Vec<ConfigError>> {
+    Config::load_checked(&Path::new(file_path))
 }
 
-fn load_config(file_path: &str) -> Result<Config> {
-    Config::load(&Path::new(file_path))
+/// Why the main loop or `idle` returned.
+#[derive(PartialEq)]
+enum Exit {
+    LinkLost,
+    /// A SIGTERM or SIGINT was received
+    Shutdown,
+    /// `idle` waited as long as it was told to
+    Waited
 }
 
-fn enter_main_loop<T: ServerProtocol>(ircstream: IrcStream<T>) {
-    for message in ircstream.iter() {
-        match message {
-            Ok(unparsed_msg) => {
-                if let Ok(irc_msg) = unparsed_msg {
-                    match &irc_msg.command[..] {
-                        "PRIVMSG" => println!("Got a private! How exciting!"),
-                        _ => ()
-                    }
-                } else {
-                    println!("Invalid IRC Message");
-                    break;
-                }
-            }
-            Err(e) => {
-                println!("Connection reset by peer: {}", (&e as &Error).description());
-                break;
+/// Reloads the configuration on SIGHUP. Returns whether a shutdown was requested.
+fn handle_signals<T: ServerProtocol>(ircstream: &IrcStream<T>) -> bool {
+    signal::clear_wakeups();
+    if signal::take_reload() {
+        info!(Core, "Reloading the configuration");
+        if let Err(e) = ircstream.rehash(false) {
+            error!(Core, "Failed to reload the configuration: {}", e);
+        }
+    }
+    signal::take_terminate()
+}
+
+/// Processes messages until the link drops or we are told to shut down. Waits
+/// on the uplink and the admin socket at once, waking up when the ping watchdog
+/// needs to run.
+fn enter_main_loop<T: ServerProtocol>(ircstream: &IrcStream<T>, cbot: &ControlBot,
+                                      admin: &mut Option<AdminSocket>) -> Exit {
+    let mut poll = Poll::new();
+    loop {
+        if handle_signals(ircstream) {
+            return Exit::Shutdown;
+        }
+        poll.clear();
+        poll.register(signal::raw_fd());
+        let uplink = poll.register(ircstream.raw_fd());
+        if let Some(ref mut admin) = *admin {
+            admin.register(&mut poll);
+        }
+        if let Err(e) = poll.wait(ircstream.next_timeout_ms()) {
+            error!(Core, "poll() failed: {}", (&e as &Error).description());
+            return Exit::LinkLost;
+        }
+
+        // Data TLS buffered doesn't make the socket readable
+        if poll.is_ready(uplink) || ircstream.has_pending() {
+            if let Err(e) = process_uplink(ircstream, cbot) {
+                warn!(Link, "Connection reset by peer: {}", (&e as &Error).description());
+                return Exit::LinkLost;
             }
-        }        
+        }
+        if let Err(e) = ircstream.run_timers() {
+            warn!(Link, "Link lost: {}", (&e as &Error).description());
+            return Exit::LinkLost;
+        }
+        send_log_lines(ircstream);
+        if let Some(ref mut admin) = *admin {
+            admin.process(&poll, |cmd| run_admin_command(cmd, cbot, ircstream));
+        }
+    }
+}
+
+fn run_admin_command<T: ServerProtocol>(cmd: &str, cbot: &ControlBot, ircstream: &IrcStream<T>)
+                                        -> String {
+    info!(Security, "Admin socket command: {}", cmd);
+    cbot.run_command(cmd, ircstream)
+}
+
+/// Sends what was logged to IRC channels since the last call. Held back until
+/// the control bot is introduced, since the uplink would reject lines from a nick
+/// it doesn't know, and while the send queue is congested, so that logging about
+/// it doesn't make it worse.
+fn send_log_lines<T: ServerProtocol>(ircstream: &IrcStream<T>) {
+    if !ircstream.is_synced() || ircstream.is_congested() {
+        return;
+    }
+    let msgs = log::take_msgs();
+    if msgs.is_empty() {
+        return;
+    }
+    // Failures show up on the next read or timer run
+    let _ = ircstream.send_msgs(&msgs[..]);
+}
+
+/// Reads from the uplink and handles every complete message received.
+fn process_uplink<T: ServerProtocol>(ircstream: &IrcStream<T>, cbot: &ControlBot) -> Result<()> {
+    try!(ircstream.read_available());
+    let mut replies = Vec::new();
+    while try!(ircstream.next_msg(|msg| replies = cbot.handle(msg, ircstream))) {
+        match ircstream.send_msgs(&replies[..]) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => warn!(Link, "{}", e),
+            Err(e) => return Err(e)
+        }
+        replies.clear();
+    }
+    Ok(())
+}
+
+/// Waits for `ms` milliseconds while there is no link, still answering the admin
+/// socket and signals.
+fn idle<T: ServerProtocol>(ircstream: &IrcStream<T>, cbot: &ControlBot,
+                           admin: &mut Option<AdminSocket>, ms: u64) -> Exit {
+    let until = time::precise_time_ns() / 1000000 + ms;
+    let mut poll = Poll::new();
+    loop {
+        if handle_signals(ircstream) {
+            return Exit::Shutdown;
+        }
+        let now = time::precise_time_ns() / 1000000;
+        if now >= until {
+            return Exit::Waited;
+        }
+        poll.clear();
+        poll.register(signal::raw_fd());
+        if let Some(ref mut admin) = *admin {
+            admin.register(&mut poll);
+        }
+        if poll.wait(until - now).is_err() {
+            return Exit::Waited;
+        }
+        if let Some(ref mut admin) = *admin {
+            admin.process(&poll, |cmd| run_admin_command(cmd, cbot, ircstream));
+        }
     }
 }
@@ -0,0 +1,546 @@
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::net::IpAddr;
+use std::borrow::ToOwned;
+use std::cmp::Ordering;
+
+use cmd::prefix::Prefix;
+
+/// A server linked to the network.
+pub struct Server {
+    pub name: String,
+    /// Server ID, for protocols that use them.
+    pub sid: Option<String>,
+    pub hopcount: u32,
+    /// The server this one is linked to. `None` for ourselves.
+    pub uplink: Option<String>,
+    pub description: String,
+    /// Whether we have seen EOS (End Of Synch) for this server.
+    pub synced: bool
+}
+
+/// A user connected to the network.
+pub struct User {
+    pub nick: String,
+    /// User ID, for protocols that use them.
+    pub uid: Option<String>,
+    pub ident: String,
+    pub host: String,
+    pub vhost: Option<String>,
+    pub gecos: String,
+    /// User modes, without the leading `+`.
+    pub umodes: String,
+    pub ip: Option<IpAddr>,
+    /// Name of the server the user is connected to.
+    pub server: String,
+    /// Nick timestamp
+    pub timestamp: i64,
+    /// Channels this user is on (normalized names).
+    pub channels: HashSet<String>
+}
+
+pub struct Topic {
+    pub text: String,
+    pub setter: String,
+    pub timestamp: i64
+}
+
+/// A channel and everything we know about it.
+pub struct Channel {
+    pub name: String,
+    /// Creation timestamp
+    pub timestamp: i64,
+    /// Simple and parameterized channel modes (`k`, `l`, ...). Modes without
+    /// a parameter map to `None`.
+    pub modes: BTreeMap<char, Option<String>>,
+    pub bans: Vec<String>,
+    pub excepts: Vec<String>,
+    pub invex: Vec<String>,
+    pub topic: Option<Topic>,
+    /// Normalized nick -> status modes held on this channel (e.g. `"ov"`).
+    pub members: HashMap<String, String>
+}
+
+/// A single mode change, as parsed by the protocol handler.
+/// The protocol module knows which modes take parameters; the network model
+/// only applies them.
+#[derive(Debug, PartialEq)]
+pub struct ModeChange {
+    pub adding: bool,
+    pub mode: char,
+    pub arg: Option<String>
+}
+
+/// Status modes a user can hold on a channel, highest first.
+pub static STATUS_MODES: &'static str = "qaohv";
+
+/// What a message prefix refers to.
+pub enum Origin<'a> {
+    Server(&'a Server),
+    User(&'a User)
+}
+
+/// The state of the whole network as seen by us.
+#[derive(Default)]
+pub struct Network {
+    servers: HashMap<String, Server>,
+    users: HashMap<String, User>,
+    channels: HashMap<String, Channel>
+}
+
+/// Normalizes a nick or channel name using the RFC 1459 case mapping,
+/// where `[]\~` are the lowercase versions of `{}|^`.
+pub fn irc_lower(name: &str) -> String {
+    name.chars().map(|c| match c {
+        'A'...'Z' => ((c as u8) + 32) as char,
+        '[' => '{',
+        ']' => '}',
+        '\\' => '|',
+        '~' => '^',
+        _ => c
+    }).collect()
+}
+
+impl Network {
+    pub fn new() -> Network {
+        Network { servers: HashMap::new(), users: HashMap::new(), channels: HashMap::new() }
+    }
+
+    /// Forgets everything we know about the network.
+    pub fn clear(&mut self) {
+        self.servers.clear();
+        self.users.clear();
+        self.channels.clear();
+    }
+
+    pub fn server(&self, name: &str) -> Option<&Server> {
+        self.servers.get(&irc_lower(name))
+    }
+
+    pub fn user(&self, nick: &str) -> Option<&User> {
+        self.users.get(&irc_lower(nick))
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&Channel> {
+        self.channels.get(&irc_lower(name))
+    }
+
+    pub fn servers(&self) -> Vec<&Server> {
+        self.servers.values().collect()
+    }
+
+    pub fn users(&self) -> Vec<&User> {
+        self.users.values().collect()
+    }
+
+    pub fn channels(&self) -> Vec<&Channel> {
+        self.channels.values().collect()
+    }
+
+    /// Finds the server or user a message came from.
+    pub fn resolve(&self, prefix: &Prefix) -> Option<Origin> {
+        match *prefix {
+            Prefix::Server(name) => self.server(name).map(Origin::Server),
+            Prefix::Nick(nick) | Prefix::User { nick, .. } => self.user(nick).map(Origin::User),
+            Prefix::Sid(sid) => self.servers.values()
+                .find(|s| s.sid.as_ref().map_or(false, |id| &id[..] == sid))
+                .map(Origin::Server),
+            Prefix::Uid(uid) => self.users.values()
+                .find(|u| u.uid.as_ref().map_or(false, |id| &id[..] == uid))
+                .map(Origin::User)
+        }
+    }
+
+    /// Returns every user connected to `server`.
+    pub fn users_on(&self, server: &str) -> Vec<&User> {
+        let key = irc_lower(server);
+        self.users.values().filter(|u| irc_lower(&u.server[..]) == key).collect()
+    }
+
+    pub fn add_server(&mut self, name: &str, hopcount: u32, uplink: Option<&str>, desc: &str) {
+        self.servers.insert(irc_lower(name),
+                            Server { name: name.to_owned(),
+                                     sid: None,
+                                     hopcount: hopcount,
+                                     uplink: uplink.map(|u| u.to_owned()),
+                                     description: desc.to_owned(),
+                                     synced: false });
+    }
+
+    pub fn set_server_synced(&mut self, name: &str) {
+        if let Some(server) = self.servers.get_mut(&irc_lower(name)) {
+            server.synced = true;
+        }
+    }
+
+    /// Removes a server, every server behind it, and all of their users.
+    pub fn remove_server(&mut self, name: &str) {
+        let key = irc_lower(name);
+        let server = match self.servers.remove(&key) {
+            Some(server) => server,
+            None => return
+        };
+
+        let leaves: Vec<String> = self.servers.values()
+            .filter(|s| s.uplink.as_ref().map_or(false, |u| irc_lower(&u[..]) == key))
+            .map(|s| s.name.clone())
+            .collect();
+        for leaf in leaves.iter() {
+            self.remove_server(&leaf[..]);
+        }
+
+        let gone: Vec<String> = self.users_on(&server.name[..]).iter()
+            .map(|u| u.nick.clone())
+            .collect();
+        for nick in gone.iter() {
+            self.remove_user(&nick[..]);
+        }
+    }
+
+    /// Adds a user. A collision with a user who already has the nick is resolved
+    /// as in `rename_user`, and the losers are described in the error.
+    pub fn add_user(&mut self, user: User) -> Result<(), String> {
+        let key = irc_lower(&user.nick[..]);
+        if let Some(existing_ts) = self.users.get(&key).map(|u| u.timestamp) {
+            let collision = format!("Nick collision: {} introduced (TS {}), which was in use (TS {})",
+                                    user.nick, user.timestamp, existing_ts);
+            match existing_ts.cmp(&user.timestamp) {
+                Ordering::Less => (),
+                Ordering::Greater => {
+                    self.remove_user(&user.nick[..]);
+                    self.users.insert(key, user);
+                }
+                Ordering::Equal => self.remove_user(&user.nick[..])
+            }
+            return Err(collision);
+        }
+        self.users.insert(key, user);
+        Ok(())
+    }
+
+    /// Renames a user. If another user has the new nick, the collision is
+    /// resolved as Unreal does: the older nick timestamp wins, and both lose on a
+    /// tie. The losers are removed, and described in the error.
+    pub fn rename_user(&mut self, old: &str, new: &str, ts: i64) -> Result<(), String> {
+        let old_key = irc_lower(old);
+        let new_key = irc_lower(new);
+        if !self.users.contains_key(&old_key) {
+            return Ok(());
+        }
+        if old_key != new_key {
+            if let Some(existing_ts) = self.users.get(&new_key).map(|u| u.timestamp) {
+                let collision = format!("Nick collision: {} renamed to {} (TS {}), which was in use (TS {})",
+                                        old, new, ts, existing_ts);
+                match existing_ts.cmp(&ts) {
+                    Ordering::Less => self.remove_user(old),
+                    Ordering::Greater => {
+                        self.remove_user(new);
+                        self.do_rename(&old_key[..], new, ts);
+                    }
+                    Ordering::Equal => {
+                        self.remove_user(new);
+                        self.remove_user(old);
+                    }
+                }
+                return Err(collision);
+            }
+        }
+        self.do_rename(&old_key[..], new, ts);
+        Ok(())
+    }
+
+    fn do_rename(&mut self, old_key: &str, new: &str, ts: i64) {
+        let new_key = irc_lower(new);
+        let mut user = match self.users.remove(old_key) {
+            Some(user) => user,
+            None => return
+        };
+        user.nick = new.to_owned();
+        user.timestamp = ts;
+
+        for chan in user.channels.iter() {
+            if let Some(channel) = self.channels.get_mut(chan) {
+                if let Some(status) = channel.members.remove(old_key) {
+                    channel.members.insert(new_key.clone(), status);
+                }
+            }
+        }
+        self.users.insert(new_key, user);
+    }
+
+    /// Removes a user from the network (QUIT, KILL, SQUIT).
+    pub fn remove_user(&mut self, nick: &str) {
+        let key = irc_lower(nick);
+        if let Some(user) = self.users.remove(&key) {
+            for chan in user.channels.iter() {
+                self.drop_member(&chan[..], &key[..]);
+            }
+        }
+    }
+
+    pub fn set_umodes(&mut self, nick: &str, modes: &str) {
+        if let Some(user) = self.users.get_mut(&irc_lower(nick)) {
+            let mut adding = true;
+            for c in modes.chars() {
+                match c {
+                    '+' => adding = true,
+                    '-' => adding = false,
+                    _ if adding => {
+                        if !user.umodes.contains(c) {
+                            user.umodes.push(c);
+                        }
+                    }
+                    _ => user.umodes = user.umodes.chars().filter(|&m| m != c).collect()
+                }
+            }
+        }
+    }
+
+    pub fn set_vhost(&mut self, nick: &str, vhost: &str) {
+        if let Some(user) = self.users.get_mut(&irc_lower(nick)) {
+            user.vhost = Some(vhost.to_owned());
+        }
+    }
+
+    pub fn set_gecos(&mut self, nick: &str, gecos: &str) {
+        if let Some(user) = self.users.get_mut(&irc_lower(nick)) {
+            user.gecos = gecos.to_owned();
+        }
+    }
+
+    /// Makes sure `name` exists, creating it with timestamp `ts` if needed.
+    pub fn create_channel(&mut self, name: &str, ts: i64) {
+        let key = irc_lower(name);
+        if !self.channels.contains_key(&key) {
+            self.channels.insert(key, Channel { name: name.to_owned(),
+                                                timestamp: ts,
+                                                modes: BTreeMap::new(),
+                                                bans: Vec::new(),
+                                                excepts: Vec::new(),
+                                                invex: Vec::new(),
+                                                topic: None,
+                                                members: HashMap::new() });
+        }
+    }
+
+    /// Adds `nick` to `chan` with the given status modes. Creates the
+    /// channel if it doesn't exist yet.
+    pub fn join(&mut self, chan: &str, nick: &str, status: &str, ts: i64) {
+        let nick_key = irc_lower(nick);
+        let chan_key = irc_lower(chan);
+        match self.users.get_mut(&nick_key) {
+            Some(user) => { user.channels.insert(chan_key.clone()); },
+            None => return
+        }
+        self.create_channel(chan, ts);
+        if let Some(channel) = self.channels.get_mut(&chan_key) {
+            channel.members.insert(nick_key, status.to_owned());
+        }
+    }
+
+    pub fn part(&mut self, chan: &str, nick: &str) {
+        let nick_key = irc_lower(nick);
+        let chan_key = irc_lower(chan);
+        if let Some(user) = self.users.get_mut(&nick_key) {
+            user.channels.remove(&chan_key);
+        }
+        self.drop_member(&chan_key[..], &nick_key[..]);
+    }
+
+    pub fn set_topic(&mut self, chan: &str, text: &str, setter: &str, ts: i64) {
+        if let Some(channel) = self.channels.get_mut(&irc_lower(chan)) {
+            channel.topic = if text.len() == 0 {
+                None
+            } else {
+                Some(Topic { text: text.to_owned(), setter: setter.to_owned(), timestamp: ts })
+            };
+        }
+    }
+
+    /// Wipes modes, lists and member statuses of a channel. Used when a
+    /// channel with an older timestamp is received during a burst.
+    pub fn reset_channel(&mut self, chan: &str, ts: i64) {
+        if let Some(channel) = self.channels.get_mut(&irc_lower(chan)) {
+            channel.timestamp = ts;
+            channel.modes.clear();
+            channel.bans.clear();
+            channel.excepts.clear();
+            channel.invex.clear();
+            for (_, status) in channel.members.iter_mut() {
+                status.clear();
+            }
+        }
+    }
+
+    pub fn apply_channel_modes(&mut self, chan: &str, changes: &[ModeChange]) {
+        let channel = match self.channels.get_mut(&irc_lower(chan)) {
+            Some(channel) => channel,
+            None => return
+        };
+
+        for change in changes.iter() {
+            let arg = change.arg.as_ref().map(|a| &a[..]);
+            match (change.mode, arg) {
+                ('b', Some(mask)) => update_list(&mut channel.bans, mask, change.adding),
+                ('e', Some(mask)) => update_list(&mut channel.excepts, mask, change.adding),
+                ('I', Some(mask)) => update_list(&mut channel.invex, mask, change.adding),
+                (m, Some(nick)) if STATUS_MODES.contains(m) => {
+                    if let Some(status) = channel.members.get_mut(&irc_lower(nick)) {
+                        if change.adding && !status.contains(m) {
+                            status.push(m);
+                        } else if !change.adding {
+                            *status = status.chars().filter(|&c| c != m).collect();
+                        }
+                    }
+                }
+                (m, _) => {
+                    if change.adding {
+                        channel.modes.insert(m, change.arg.clone());
+                    } else {
+                        channel.modes.remove(&m);
+                    }
+                }
+            }
+        }
+    }
+
+    fn drop_member(&mut self, chan_key: &str, nick_key: &str) {
+        let empty = match self.channels.get_mut(chan_key) {
+            Some(channel) => {
+                channel.members.remove(nick_key);
+                channel.members.is_empty() && !channel.modes.contains_key(&'P')
+            }
+            None => false
+        };
+        if empty {
+            self.channels.remove(chan_key);
+        }
+    }
+}
+
+fn update_list(list: &mut Vec<String>, mask: &str, adding: bool) {
+    if adding {
+        if !list.iter().any(|m| &m[..] == mask) {
+            list.push(mask.to_owned());
+        }
+    } else {
+        list.retain(|m| &m[..] != mask);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Network, User, Origin, ModeChange, irc_lower};
+    use cmd::prefix::Prefix;
+    use std::collections::HashSet;
+
+    fn user(nick: &str, server: &str) -> User {
+        User { nick: nick.to_string(), uid: None, ident: "ident".to_string(), host: "host".to_string(),
+               vhost: None, gecos: "gecos".to_string(), umodes: "i".to_string(), ip: None,
+               server: server.to_string(), timestamp: 0, channels: HashSet::new() }
+    }
+
+    #[test]
+    fn squit_removes_users_and_leaves() {
+        let mut net = Network::new();
+        net.add_server("hub.example.com", 1, Some("services.example.com"), "hub");
+        net.add_server("leaf.example.com", 2, Some("hub.example.com"), "leaf");
+        net.add_user(user("Alice", "leaf.example.com")).unwrap();
+        net.add_user(user("Bob", "hub.example.com")).unwrap();
+        net.join("#Test", "Alice", "o", 100);
+        net.join("#test", "bob", "", 100);
+
+        net.remove_server("HUB.example.com");
+
+        assert!(net.server("leaf.example.com").is_none());
+        assert!(net.user("alice").is_none());
+        assert!(net.user("bob").is_none());
+        assert!(net.channel("#test").is_none());
+    }
+
+    #[test]
+    fn modes_and_renames() {
+        let mut net = Network::new();
+        net.add_server("hub.example.com", 1, None, "hub");
+        net.add_user(user("Alice", "hub.example.com")).unwrap();
+        net.join("#test", "Alice", "", 100);
+        net.apply_channel_modes("#TEST", &[
+            ModeChange { adding: true, mode: 'o', arg: Some("alice".to_string()) },
+            ModeChange { adding: true, mode: 'k', arg: Some("key".to_string()) },
+            ModeChange { adding: true, mode: 'b', arg: Some("*!*@evil".to_string()) }]);
+        net.rename_user("Alice", "Alice[away]", 200).unwrap();
+
+        let chan = net.channel("#test").unwrap();
+        assert_eq!(chan.members.get(&irc_lower("ALICE{AWAY}")).map(|s| &s[..]), Some("o"));
+        assert_eq!(chan.modes.get(&'k'), Some(&Some("key".to_string())));
+        assert_eq!(chan.bans, vec!["*!*@evil".to_string()]);
+    }
+
+    #[test]
+    fn burst_collisions() {
+        let mut net = Network::new();
+        net.add_server("hub.example.com", 1, None, "hub");
+        net.add_server("leaf.example.com", 2, Some("hub.example.com"), "leaf");
+        net.add_user(User { timestamp: 100, ..user("Alice", "hub.example.com") }).unwrap();
+        net.join("#test", "Alice", "o", 100);
+
+        // A newer Alice from the other side of the network loses
+        assert!(net.add_user(User { timestamp: 200, ..user("alice", "leaf.example.com") }).is_err());
+        assert_eq!(net.user("Alice").map(|u| &u.server[..]), Some("hub.example.com"));
+        assert!(net.channel("#test").unwrap().members.contains_key("alice"));
+
+        // An older one wins, and the one we knew leaves its channels
+        assert!(net.add_user(User { timestamp: 50, ..user("ALICE", "leaf.example.com") }).is_err());
+        assert_eq!(net.user("alice").map(|u| &u.server[..]), Some("leaf.example.com"));
+        assert!(net.channel("#test").is_none());
+
+        // A tie: both lose
+        assert!(net.add_user(User { timestamp: 50, ..user("Alice", "hub.example.com") }).is_err());
+        assert!(net.user("alice").is_none());
+        assert!(net.users().is_empty());
+    }
+
+    #[test]
+    fn nick_collisions() {
+        let mut net = Network::new();
+        net.add_server("hub.example.com", 1, None, "hub");
+        for &(nick, ts) in [("Alice", 100), ("Bob", 50), ("Carol", 300), ("Dave", 400)].iter() {
+            net.add_user(User { timestamp: ts, ..user(nick, "hub.example.com") }).unwrap();
+            net.join("#test", nick, "", 100);
+        }
+
+        // Alice's nick is older: Bob loses, memberships included
+        assert!(net.rename_user("Bob", "alice", 150).is_err());
+        assert!(net.user("bob").is_none());
+        assert_eq!(net.user("alice").map(|u| u.timestamp), Some(100));
+        assert!(!net.channel("#test").unwrap().members.contains_key("bob"));
+
+        // The nick change is older than Carol's nick: Carol loses
+        assert!(net.rename_user("Alice", "Carol", 200).is_err());
+        assert!(net.user("alice").is_none());
+        assert_eq!(net.user("carol").map(|u| u.timestamp), Some(200));
+        assert_eq!(net.channel("#test").unwrap().members.len(), 2);
+
+        // A tie: both lose
+        assert!(net.rename_user("Carol", "Dave", 400).is_err());
+        assert!(net.user("carol").is_none() && net.user("dave").is_none());
+        assert!(net.channel("#test").is_none());
+    }
+
+    #[test]
+    fn resolve() {
+        let mut net = Network::new();
+        net.add_server("hub.example.com", 1, None, "hub");
+        net.add_user(user("Alice", "hub.example.com")).unwrap();
+
+        match net.resolve(&Prefix::parse("alice!alice@host")) {
+            Some(Origin::User(u)) => assert_eq!(&u.nick[..], "Alice"),
+            _ => panic!("Alice not found")
+        }
+        match net.resolve(&Prefix::parse("HUB.example.com")) {
+            Some(Origin::Server(s)) => assert_eq!(&s.name[..], "hub.example.com"),
+            _ => panic!("hub.example.com not found")
+        }
+        assert!(net.resolve(&Prefix::parse("Bob")).is_none());
+        assert!(net.resolve(&Prefix::parse("001ABCDEF")).is_none());
+    }
+}
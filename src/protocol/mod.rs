@@ -1,8 +1,11 @@
 
 pub mod unreal;
 
-use cmd::IrcMsg;
-use conf::Config;
+use cmd::{IrcMsg, IrcMsgRef};
+use cmd::command::Command;
+use conf::{Config, ConfigChanges};
+use network::Network;
+use sched::Scheduler;
 
 use std::fmt::{Display, Formatter};
 use std::fmt::Result as FmtResult;
@@ -46,47 +49,88 @@ pub trait ServerProtocol {
 
     //type IRCd;
 
-    fn new(config: Rc<RefCell<Config>>) -> Self;
+    /// Creates a handler. Timers it needs (expiries, periodic checks...) go
+    /// through `scheduler`, which is run from the main loop.
+    fn new(config: Rc<RefCell<Config>>, scheduler: Rc<RefCell<Scheduler>>) -> Self;
 
-    fn introduce_msg(&self) -> String;
+    /// The protocol version spoken, as sent to the uplink (e.g. U2311).
+    fn protocol_version() -> &'static str;
 
-    fn introduce_client_msg(&self, ctype: IrcClientType,
-                            nick: &str, ident: &str, host: &str, gecos: &str) -> String;
+    /// The network state maintained by this handler.
+    fn network(&self) -> Rc<RefCell<Network>>;
+
+    /// Forgets the current link: negotiated capabilities, sync status and
+    /// network state. Called before relinking.
+    fn reset(&mut self);
+
+    /// Whether the burst is over and our clients were introduced, so that they
+    /// may send messages.
+    fn is_synced(&self) -> bool;
+
+    fn introduce_msg(&self) -> Vec<IrcMsg>;
 
-    fn handle(&mut self, msg: &IrcMsg) -> Result<Option<String>, ProtocolError> {
-        match &msg.command[..] {
-            "PING" => self.handle_ping(msg),
-            "PASS" => self.handle_pass(msg),
-            _ => self.handle_generic(msg)
+    /// A PING to the uplink, used to check that the link is alive and to measure lag.
+    fn ping_msg(&self) -> IrcMsg;
+
+    fn introduce_client_msg(&self, ctype: IrcClientType,
+                            nick: &str, ident: &str, host: &str, gecos: &str) -> IrcMsg;
+
+    /// Leaves the network cleanly: our clients quit, then we split from the uplink.
+    fn quit_msgs(&self, reason: &str) -> Vec<IrcMsg>;
+
+    /// Applies a reloaded configuration to the clients we introduced: channels
+    /// joined or parted, host and gecos changes. The new configuration is
+    /// already in place.
+    fn rehash_msgs(&self, changes: &ConfigChanges) -> Vec<IrcMsg>;
+
+    /// Handles a message from the uplink. It borrows from the line it was
+    /// parsed from: nothing is allocated for messages that are only looked at.
+    fn handle(&mut self, msg: &IrcMsgRef) -> Result<Vec<IrcMsg>, ProtocolError> {
+        let source = msg.source;
+        let cmd = match Command::from_ref(msg) {
+            Ok(cmd) => cmd,
+            // A malformed PASS or SERVER from the uplink means the link can't be established
+            Err(e) => return Err(match (source, msg.command) {
+                (None, "PASS") | (None, "SERVER") => ProtocolError { kind: ProtoErrorKind::Fatal, ..e },
+                _ => e
+            })
+        };
+        match cmd {
+            Command::Ping { origin, target } => self.handle_ping(origin, target),
+            Command::Pass { password } => self.handle_pass(password),
+            Command::Server { name, hopcount, description } =>
+                self.handle_server(source, name, hopcount, description),
+            cmd => self.handle_generic(source, cmd)
         }
     }
 
-    fn handle_pass(&self, msg: &IrcMsg) -> Result<Option<String>, ProtocolError>;
+    fn handle_pass(&self, password: &str) -> Result<Vec<IrcMsg>, ProtocolError>;
 
     // TODO
     // When Rust supports struct inheritance, move handle_ping back here
-    fn handle_ping(&self, msg: &IrcMsg) -> Result<Option<String>, ProtocolError>;
+    fn handle_ping(&self, origin: &str, target: Option<&str>) -> Result<Vec<IrcMsg>, ProtocolError>;
 
-    fn handle_server(&self, msg: &IrcMsg) -> Result<Option<String>, ProtocolError>;
+    fn handle_server(&self, source: Option<&str>, name: &str, hopcount: u32, description: &str)
+                     -> Result<Vec<IrcMsg>, ProtocolError>;
 
     #[allow(unused_variables)]
-    fn handle_generic(&mut self, msg: &IrcMsg) ->
-        Result<Option<String>, ProtocolError> {
-        Ok(None)
+    fn handle_generic(&mut self, source: Option<&str>, cmd: Command) ->
+        Result<Vec<IrcMsg>, ProtocolError> {
+        Ok(vec![])
     }
 }
 
 impl ProtocolError {
-    fn new(errtype: ProtoErrorKind, descr: &'static str, details: Option<String>) -> ProtocolError {
+    pub fn new(errtype: ProtoErrorKind, descr: &'static str, details: Option<String>) -> ProtocolError {
         ProtocolError { kind: errtype, desc: descr, detail: details }
     }
 }
 
 impl Display for ProtocolError {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        write!(f, "[PROTOCOL ERROR] ({:?}): {} ({})",
-               self.kind,
+        write!(f, "{} ({:?}: {})",
                self.desc,
+               self.kind,
                self.detail.as_ref().map_or("no details", |d| &d[..]))
     }
 }
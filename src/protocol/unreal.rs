@@ -1,13 +1,21 @@
 use std::default::Default;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::borrow::ToOwned;
 
 use protocol::ServerProtocol;
-use conf::Config;
+use conf::{Config, ConfigChanges};
 use cmd::IrcMsg;
+use cmd::command::{Command, List};
 use protocol::{ProtoErrorKind, ProtocolError};
 use protocol::IrcClientType;
+use cmd::prefix::Prefix;
+use network::{Network, User, Origin, ModeChange, irc_lower};
+use sched::Scheduler;
 
+use rustc_serialize::base64::FromBase64;
 use time;
 
 /// This module targets Unreal protocol version 2311 (Unreal 3.2.10)
@@ -18,13 +26,28 @@ use time;
 
 static PROTOVERSION: &'static str = "U2311";
 static COMPILEFLAGS: &'static str = "Ooe";
+static PROTOCTL_TOKENS: [&'static str; 9] = ["VHP", "UMODE2", "VL", "SJOIN", "SJOIN2", "SJ3",
+                                              "TKLEXT", "NICKv2", "NICKIP"];
 static DEF_USR_MODES: &'static str = "+i";
 static DEF_SERVICE_MODES: &'static str = "+ioSq";
 
+/// Channel modes that always take a parameter (lists and CHANMODES types A and B).
+static CHMODES_PARAM: &'static str = "beIkfL";
+/// Channel modes that take a parameter only when set.
+static CHMODES_PARAM_SET: &'static str = "lj";
+/// Channel status modes (owner, admin, op, halfop, voice).
+static CHMODES_STATUS: &'static str = "qaohv";
+/// SJOIN member prefixes, mapped to the corresponding status mode.
+static SJOIN_PREFIXES: [(char, char); 5] = [('*', 'q'), ('~', 'a'), ('@', 'o'), ('%', 'h'), ('+', 'v')];
+
 #[derive(Default)]
 pub struct Unreal {
     /// Configuration
     conf: Rc<RefCell<Config>>,
+    /// Network state, built from the burst
+    network: Rc<RefCell<Network>>,
+    /// Timers shared with the rest of the program
+    scheduler: Rc<RefCell<Scheduler>>,
     /// Are we synced?
     synced: bool,
     /// When introducing a user, send his cloaked host as if it were a vhost.
@@ -52,28 +75,99 @@ impl ServerProtocol for Unreal {
 
     //type IRCd = Unreal;
 
-    fn new(config: Rc<RefCell<Config>>) -> Self {
-        Unreal { conf: config.clone(), synced: false, ..Default::default() }
+    fn new(config: Rc<RefCell<Config>>, scheduler: Rc<RefCell<Scheduler>>) -> Self {
+        Unreal { conf: config.clone(),
+                 network: Rc::new(RefCell::new(Network::new())),
+                 scheduler: scheduler,
+                 synced: false,
+                 ..Default::default() }
+    }
+
+    fn protocol_version() -> &'static str {
+        PROTOVERSION
+    }
+
+    fn network(&self) -> Rc<RefCell<Network>> {
+        self.network.clone()
+    }
+
+    fn reset(&mut self) {
+        // Keep the same Network instance, others may be holding on to it
+        self.network.borrow_mut().clear();
+        *self = Unreal { conf: self.conf.clone(),
+                         network: self.network.clone(),
+                         scheduler: self.scheduler.clone(),
+                         synced: false,
+                         ..Default::default() };
+    }
+
+    fn is_synced(&self) -> bool {
+        self.synced
     }
 
     /// Generates the introduce msg to an Unreal uplink.
-    fn introduce_msg(&self) -> String {
+    fn introduce_msg(&self) -> Vec<IrcMsg> {
+        let conf = self.conf.borrow();
+        vec![IrcMsg::new(None, "PASS", vec![conf.get_link_passwd().to_owned()]),
+             IrcMsg::new(None, "PROTOCTL", PROTOCTL_TOKENS.iter().map(|t| t.to_string()).collect()),
+             IrcMsg::new(None, "SERVER", vec![conf.get_server_name().to_owned(),
+                                              "1".to_owned(),
+                                              format!("{}-{}-{} {}", PROTOVERSION, COMPILEFLAGS,
+                                                      conf.get_numeric(), conf.get_description())])]
+    }
+
+    fn ping_msg(&self) -> IrcMsg {
         let conf = self.conf.borrow();
-        format!(concat!("PASS :{}\r\n",
-                        "PROTOCTL VHP UMODE2 VL SJOIN SJOIN2 SJ3 TKLEXT NICKv2 NICKIP\r\n",
-                        "SERVER {} 1 :{}-{}-{} {}\r\n"),
-                conf.get_link_passwd(), conf.get_server_name(), PROTOVERSION, COMPILEFLAGS,
-                conf.get_numeric(), conf.get_description())
+        IrcMsg::new(None, "PING", vec![conf.get_server_name().to_owned(),
+                                       conf.get_uplink_name().to_owned()])
+    }
+
+    fn quit_msgs(&self, reason: &str) -> Vec<IrcMsg> {
+        let conf = self.conf.borrow();
+        let mut msgs: Vec<IrcMsg> = self.network.borrow().users_on(conf.get_server_name()).iter()
+            .map(|u| IrcMsg::new(Some(u.nick.clone()), "QUIT", vec![reason.to_owned()]))
+            .collect();
+        msgs.push(IrcMsg::new(None, "SQUIT", vec![conf.get_server_name().to_owned(), reason.to_owned()]));
+        msgs
+    }
+
+    fn rehash_msgs(&self, changes: &ConfigChanges) -> Vec<IrcMsg> {
+        // Before EOS the control bot isn't there yet; it is introduced as configured
+        if !self.synced {
+            return vec![];
+        }
+        let conf = self.conf.borrow();
+        let nick = conf.get_cbot_nick();
+        let mut network = self.network.borrow_mut();
+        let mut msgs = Vec::new();
+        if let Some(ref host) = changes.host {
+            msgs.push(IrcMsg::new(Some(nick.to_owned()), "SETHOST", vec![host.clone()]));
+            network.set_vhost(nick, &host[..]);
+        }
+        if let Some(ref gecos) = changes.gecos {
+            msgs.push(IrcMsg::new(Some(nick.to_owned()), "SETNAME", vec![gecos.clone()]));
+            network.set_gecos(nick, &gecos[..]);
+        }
+        for chan in changes.parted.iter() {
+            msgs.push(IrcMsg::new(Some(nick.to_owned()), "PART", vec![chan.clone()]));
+            network.part(&chan[..], nick);
+        }
+        for chan in changes.joined.iter() {
+            msgs.push(IrcMsg::new(Some(nick.to_owned()), "JOIN", vec![chan.clone()]));
+            network.join(&chan[..], nick, "", time::get_time().sec);
+        }
+        msgs
     }
 
     /// Generates a client introduce msg
     fn introduce_client_msg(&self, ctype: IrcClientType,
-                            nick: &str, ident: &str, host: &str, gecos: &str) -> String {
+                            nick: &str, ident: &str, host: &str, gecos: &str) -> IrcMsg {
 
         let conf = self.conf.borrow();
 
-        let mut msg = format!("NICK {} 1 {} {} {} {} 0", nick, time::get_time().sec, ident, host,
-                              conf.get_server_name());
+        let mut params = vec![nick.to_owned(), "1".to_owned(), time::get_time().sec.to_string(),
+                              ident.to_owned(), host.to_owned(),
+                              conf.get_server_name().to_owned(), "0".to_owned()];
         // TODO What if NICKv2 is not supported? We need to send modes anyway...
         // Same for NICKIP
         if self.nickv2 {
@@ -81,60 +175,52 @@ impl ServerProtocol for Unreal {
                 IrcClientType::Regular => DEF_USR_MODES,
                 IrcClientType::Service => DEF_SERVICE_MODES
             };
-            msg.push_str(&format!(" {} {}", umodes, host)[..]);
+            params.push(umodes.to_owned());
+            params.push(host.to_owned());
             if self.nickip {
                 // TODO Do not hardcode IP
-                msg.push_str(" fwAAAQ==");
+                params.push("fwAAAQ==".to_owned());
             }
         }
 
-        msg.push_str(&format!(" :{}", gecos)[..]);
+        params.push(gecos.to_owned());
 
-        msg
+        IrcMsg::new(None, "NICK", params)
     }
 
-    fn handle_pass(&self, msg: &IrcMsg) -> Result<Option<String>, ProtocolError> {
+    fn handle_pass(&self, password: &str) -> Result<Vec<IrcMsg>, ProtocolError> {
         if self.synced {
             Err(ProtocolError::new(ProtoErrorKind::InvalidContext,
                                    "Got PASS on an already-established link",
                                    None))
-        } else if msg.params.len() == 0 {
-            Err(ProtocolError::new(ProtoErrorKind::Fatal,
-                                   "Empty PASS command",
-                                   None))
-        } else if &msg.params[0][..] != self.conf.borrow().get_passwd_receive() {
-            Err(ProtocolError::new(ProtoErrorKind::Fatal,
-                                   "Wrong password received",
-                                   Some(format!("PASS :{}", &msg.params[0][..]))))
+        } else if password != self.conf.borrow().get_passwd_receive() {
+            warn!(Security, "Wrong link password received from {}", self.conf.borrow().get_uplink_name());
+            Err(ProtocolError::new(ProtoErrorKind::Fatal, "Wrong password received", None))
         } else {
-            Ok(None)
+            Ok(vec![])
         }
     }
 
-    fn handle_ping(&self, msg: &IrcMsg) -> Result<Option<String>, ProtocolError> {
+    fn handle_ping(&self, origin: &str, target: Option<&str>) -> Result<Vec<IrcMsg>, ProtocolError> {
         let conf = self.conf.borrow();
-        if msg.params.len() < 1 {
-            return Err(ProtocolError::new(ProtoErrorKind::MissingParameter,
-                                          "No parameters found; expected at least 1.",
-                                          Some(format!("PING with no parameters"))));
-        }
-        if msg.params.len() >= 2 && &msg.params[1][..] != conf.get_server_name() {
-            return Err(ProtocolError::new(ProtoErrorKind::InvalidParameter,
-                                          "Request to act as a hub",
-                                          Some(format!("PING {} :{}",
-                                                       &msg.params[0][..], 
-                                                       &msg.params[1][..]))));
-        }
-        if msg.params[0] != conf.get_uplink_name() {
-            Ok(Some(format!("PONG {} :{}\r\n", conf.get_server_name(), &msg.params[0][..])))
+        if let Some(target) = target {
+            if target != conf.get_server_name() {
+                return Err(ProtocolError::new(ProtoErrorKind::InvalidParameter,
+                                              "Request to act as a hub",
+                                              Some(format!("PING {} :{}", origin, target))));
+            }
+        }
+        if origin != conf.get_uplink_name() {
+            Ok(vec![IrcMsg::new(None, "PONG", vec![conf.get_server_name().to_owned(),
+                                                   origin.to_owned()])])
         } else {
-            Ok(Some(format!("PONG :{}\r\n", conf.get_server_name())))
+            Ok(vec![IrcMsg::new(None, "PONG", vec![conf.get_server_name().to_owned()])])
         }
     }
 
 
-    fn handle_server(&self, msg: &IrcMsg) ->
-        Result<Option<String>, ProtocolError> {
+    fn handle_server(&self, source: Option<&str>, name: &str, hopcount: u32, description: &str) ->
+        Result<Vec<IrcMsg>, ProtocolError> {
             /* Unreal uses empty prefixes to introduce the uplink, and non-empty prefixes to
              * introduce servers with hopcount > 1
              * SERVER Ping.MindForge.org 1 :U2311-Fhin6XeOoEm-191 Ping? Pong!
@@ -142,60 +228,182 @@ impl ServerProtocol for Unreal {
              * :Ping.MindForge.org SERVER SanFrancisco.MindForge.org 2 :Oh, California!
              * :SanFrancisco.MindForge.org EOS
              */
-            if msg.source.is_some() {
-                // We don't care about other servers :)
-                return Ok(None);
+            if source.is_some() {
+                let uplink = match self.source_server(source) {
+                    Some(uplink) => uplink,
+                    None => return Err(ProtocolError::new(ProtoErrorKind::InvalidParameter,
+                                                          "SERVER introduced by a user",
+                                                          Some(format!("{} introduced {}",
+                                                                       source.unwrap(), name))))
+                };
+                self.network.borrow_mut().add_server(name, hopcount, Some(&uplink[..]), description);
+                return Ok(vec![]);
             }
 
-            if msg.params.len() < 3 {
-                return Err(ProtocolError::new(ProtoErrorKind::Fatal,
-                                              "Invalid SERVER message (missing parameters)",
-                                              None));
-            }
-
-            if &msg.params[0][..] != self.conf.borrow().get_uplink_name() {
+            if name != self.conf.borrow().get_uplink_name() {
                 return Err(ProtocolError::new(ProtoErrorKind::Fatal,
                                               "Wrong uplink server name",
                                               Some(format!("Got {}, expected {}",
-                                                           &msg.params[0][..],
+                                                           name,
                                                            self.conf.borrow().get_uplink_name()))));
             }
 
-            if !&msg.params[2][..].starts_with(PROTOVERSION) {
+            if !description.starts_with(PROTOVERSION) {
                 return Err(ProtocolError::new(ProtoErrorKind::ProtocolVMismatch,
                                               "Different protocol version",
                                               Some(format!("Uplink implements {}, we implement {}",
-                                                           &msg.params[2][..], PROTOVERSION))));
+                                                           description, PROTOVERSION))));
             }
 
-            Ok(None)
+            let conf = self.conf.borrow();
+            let mut network = self.network.borrow_mut();
+            network.add_server(conf.get_server_name(), 0, None, conf.get_description());
+            network.add_server(name, hopcount, Some(conf.get_server_name()), description);
+
+            Ok(vec![])
         }
 
-    fn handle_generic(&mut self, msg: &IrcMsg) ->
-        Result<Option<String>, ProtocolError> {
-            match &msg.command[..] {
-                "PROTOCTL" => self.handle_protoctl(msg),
-                "EOS" => self.handle_eos(msg),
-                _ => Ok(None)
+    fn handle_generic(&mut self, source: Option<&str>, cmd: Command) ->
+        Result<Vec<IrcMsg>, ProtocolError> {
+            match cmd {
+                Command::Protoctl { tokens } => self.handle_protoctl(tokens),
+                Command::Eos => self.handle_eos(source),
+                Command::Nick { nick, timestamp, ident, host, server, umodes, vhost, ip, gecos, .. } => {
+                    let user = User { nick: nick.to_owned(),
+                                      uid: None,
+                                      ident: ident.to_owned(),
+                                      host: host.to_owned(),
+                                      vhost: vhost.and_then(|v| if v == "*" { None } else { Some(v.to_owned()) }),
+                                      gecos: gecos.to_owned(),
+                                      umodes: umodes.unwrap_or("").trim_left_matches('+').to_owned(),
+                                      ip: ip.and_then(decode_nickip),
+                                      server: server.to_owned(),
+                                      timestamp: timestamp,
+                                      channels: HashSet::new() };
+                    if let Err(e) = self.network.borrow_mut().add_user(user) {
+                        warn!(Protocol, "{}", e);
+                    }
+                    Ok(vec![])
+                }
+                Command::NickChange { nick, timestamp } => {
+                    let old = try!(self.source_nick(source, "NICK"));
+                    let ts = timestamp.unwrap_or(time::get_time().sec);
+                    if let Err(e) = self.network.borrow_mut().rename_user(&old[..], nick, ts) {
+                        warn!(Protocol, "{}", e);
+                    }
+                    Ok(vec![])
+                }
+                Command::SJoin { timestamp, channel, modes, mode_args, members } =>
+                    self.handle_sjoin(timestamp, channel, modes, mode_args, members),
+                Command::Join { channels } => {
+                    let nick = try!(self.source_nick(source, "JOIN"));
+                    self.handle_join(&nick[..], channels)
+                }
+                Command::Mode { target, modes, args } => {
+                    let mut network = self.network.borrow_mut();
+                    if target.starts_with("#") {
+                        let changes = parse_chan_modes(modes, args);
+                        network.apply_channel_modes(target, &changes[..]);
+                    } else {
+                        network.set_umodes(target, modes);
+                    }
+                    Ok(vec![])
+                }
+                Command::UMode2 { modes } => {
+                    let nick = try!(self.source_nick(source, "UMODE2"));
+                    self.network.borrow_mut().set_umodes(&nick[..], modes);
+                    Ok(vec![])
+                }
+                Command::Quit { .. } => {
+                    let nick = try!(self.source_nick(source, "QUIT"));
+                    self.network.borrow_mut().remove_user(&nick[..]);
+                    Ok(vec![])
+                }
+                Command::Kill { target, .. } => {
+                    self.network.borrow_mut().remove_user(target);
+                    Ok(vec![])
+                }
+                Command::Part { channels, .. } => {
+                    let nick = try!(self.source_nick(source, "PART"));
+                    let mut network = self.network.borrow_mut();
+                    for chan in channels.iter() {
+                        network.part(chan, &nick[..]);
+                    }
+                    Ok(vec![])
+                }
+                Command::Kick { channel, targets, .. } => {
+                    let mut network = self.network.borrow_mut();
+                    for nick in targets.iter() {
+                        network.part(channel, nick);
+                    }
+                    Ok(vec![])
+                }
+                Command::Topic { channel, setter, timestamp, topic } => {
+                    let setter = setter.or(source).unwrap_or("");
+                    let ts = timestamp.unwrap_or(time::get_time().sec);
+                    self.network.borrow_mut().set_topic(channel, topic, setter, ts);
+                    Ok(vec![])
+                }
+                Command::SQuit { server, .. } => {
+                    self.network.borrow_mut().remove_server(server);
+                    Ok(vec![])
+                }
+                Command::SetHost { host } => {
+                    let nick = try!(self.source_nick(source, "SETHOST"));
+                    self.network.borrow_mut().set_vhost(&nick[..], host);
+                    Ok(vec![])
+                }
+                Command::ChgHost { nick, host } => {
+                    self.network.borrow_mut().set_vhost(nick, host);
+                    Ok(vec![])
+                }
+                _ => Ok(vec![])
             }
         }
 }
 
 impl Unreal {
-    fn handle_protoctl(&mut self, msg: &IrcMsg) ->
-        Result<Option<String>, ProtocolError> {
+    /// Resolves the current nick of the user a message came from.
+    fn source_nick(&self, source: Option<&str>, cmd: &str) -> Result<String, ProtocolError> {
+        let prefix = match source.map(Prefix::parse) {
+            Some(prefix) => prefix,
+            None => return Err(ProtocolError::new(ProtoErrorKind::MissingParameter,
+                                                  "Expected a user prefix",
+                                                  Some(format!("{} without a source", cmd))))
+        };
+        if let Some(nick) = prefix.nick() {
+            return Ok(nick.to_owned());
+        }
+        match self.network.borrow().resolve(&prefix) {
+            Some(Origin::User(user)) => Ok(user.nick.clone()),
+            _ => Err(ProtocolError::new(ProtoErrorKind::InvalidParameter,
+                                        "Expected a user prefix",
+                                        Some(format!("{} from {}", cmd, prefix))))
+        }
+    }
+
+    /// Resolves the name of the server a message came from. Messages without
+    /// a prefix come from our uplink.
+    fn source_server(&self, source: Option<&str>) -> Option<String> {
+        match source.map(Prefix::parse) {
+            None => Some(self.conf.borrow().get_uplink_name().to_owned()),
+            Some(Prefix::Server(name)) => Some(name.to_owned()),
+            Some(prefix) => match self.network.borrow().resolve(&prefix) {
+                Some(Origin::Server(server)) => Some(server.name.clone()),
+                _ => None
+            }
+        }
+    }
+
+    fn handle_protoctl(&mut self, tokens: &[&str]) ->
+        Result<Vec<IrcMsg>, ProtocolError> {
             if self.synced {
                 return Err(ProtocolError::new(ProtoErrorKind::InvalidContext,
                                               "Got PROTOCTL on an already-established link",
                                               None));
             }
-            if msg.params.len() == 0 {
-                return Err(ProtocolError::new(ProtoErrorKind::MissingParameter,
-                                              "Empty PROTOCTL command",
-                                              None));
-            }
-            for token in msg.params.iter() {
-                match &token[..] {
+            for token in tokens.iter() {
+                match *token {
                     "VHP" => self.vhp = true,
                     "UMODE2" => self.umode2 = true,
                     "VL" => self.vl = true,
@@ -208,14 +416,21 @@ impl Unreal {
                     _ => ()
                 }
             }
-            Ok(None)
+            Ok(vec![])
     }
 
-    fn handle_eos(&mut self, msg: &IrcMsg) ->
-        Result<Option<String>, ProtocolError> {
+    fn handle_eos(&mut self, source: Option<&str>) ->
+        Result<Vec<IrcMsg>, ProtocolError> {
+        let origin = match self.source_server(source) {
+            Some(origin) => origin,
+            None => return Err(ProtocolError::new(ProtoErrorKind::InvalidParameter,
+                                                  "Got EOS from a user",
+                                                  source.map(|s| s.to_owned())))
+        };
         let conf = self.conf.borrow();
         let uname = conf.get_uplink_name();
-        if msg.source.as_ref().map_or(uname, |p| &p[..]) == uname {
+        self.network.borrow_mut().set_server_synced(&origin[..]);
+        if irc_lower(&origin[..]) == irc_lower(uname) {
             if self.synced {
                 Err(ProtocolError::new(ProtoErrorKind::InvalidContext,
                                        "GOT EOS on an already-established link",
@@ -223,22 +438,175 @@ impl Unreal {
             } else {
                 self.synced = true;
                 // TODO Some sort of OnSync()
-                let mut cbot_intro = self.introduce_client_msg(IrcClientType::Service,
-                                                               conf.get_cbot_nick(),
-                                                               conf.get_cbot_ident(),
-                                                               conf.get_cbot_host(),
-                                                               conf.get_cbot_gecos());
+                let mut replies = Vec::new();
+                let mut network = self.network.borrow_mut();
+                network.set_server_synced(conf.get_server_name());
+                for bot in conf.get_bots().iter() {
+                    replies.push(self.introduce_client_msg(IrcClientType::Service, &bot.nick[..],
+                                                           &bot.ident[..], &bot.host[..], &bot.gecos[..]));
+                    // Ours is the newer nick: a user who already has it keeps it
+                    let added = network.add_user(User { nick: bot.nick.clone(),
+                                                        uid: None,
+                                                        ident: bot.ident.clone(),
+                                                        host: bot.host.clone(),
+                                                        vhost: None,
+                                                        gecos: bot.gecos.clone(),
+                                                        umodes: DEF_SERVICE_MODES[1..].to_owned(),
+                                                        ip: None,
+                                                        server: conf.get_server_name().to_owned(),
+                                                        timestamp: time::get_time().sec,
+                                                        channels: HashSet::new() });
+                    if let Err(e) = added {
+                        warn!(Protocol, "{}", e);
+                    }
 
-                cbot_intro.push_str("\r\n");
-
-                for chan in conf.get_cbot_chans() {
-                    cbot_intro.push_str(&format!(":{} JOIN {}\r\n", conf.get_cbot_nick(), chan))
+                    for chan in bot.chans.iter() {
+                        replies.push(IrcMsg::new(Some(bot.nick.clone()), "JOIN", vec![chan.clone()]));
+                        network.join(&chan[..], &bot.nick[..], "", time::get_time().sec);
+                    }
                 }
 
-                Ok(Some(format!("{}EOS\r\n", cbot_intro)))
+                replies.push(IrcMsg::new(None, "EOS", vec![]));
+                Ok(replies)
             }
         } else {
-            Ok(None)
+            Ok(vec![])
+        }
+    }
+
+    /// :Ping.MindForge.org SJOIN 1424879430 #Services +nt :@Alice +Bob &*!*@evil.com
+    fn handle_sjoin(&mut self, ts: i64, chan: &str, modes: Option<&str>,
+                    mode_args: &[&str], members: List) ->
+        Result<Vec<IrcMsg>, ProtocolError> {
+            let mut network = self.network.borrow_mut();
+
+            network.create_channel(chan, ts);
+            let ours = network.channel(chan).map_or(ts, |c| c.timestamp);
+            // SJ3 timestamp rules: the oldest channel wins; if ours is newer, our modes are
+            // lost; if theirs is newer, their modes and statuses are ignored.
+            if ts < ours {
+                network.reset_channel(chan, ts);
+            }
+            let keep_theirs = ts <= ours;
+
+            let mut changes = match modes {
+                Some(modes) if keep_theirs => parse_chan_modes(modes, mode_args),
+                _ => Vec::new()
+            };
+
+            for token in members.iter() {
+                let list = match token.char_at(0) {
+                    '&' => Some('b'),
+                    '"' => Some('e'),
+                    '\'' => Some('I'),
+                    _ => None
+                };
+                if let Some(mode) = list {
+                    if keep_theirs {
+                        changes.push(ModeChange { adding: true, mode: mode,
+                                                  arg: Some(token[1..].to_owned()) });
+                    }
+                    continue;
+                }
+
+                let mut status = String::new();
+                let mut nick = token;
+                while let Some(&(_, mode)) = SJOIN_PREFIXES.iter().find(
+                    |&&(prefix, _)| nick.starts_with(prefix)) {
+                    status.push(mode);
+                    nick = &nick[1..];
+                }
+                network.join(chan, nick, if keep_theirs { &status[..] } else { "" }, ts);
+            }
+
+            network.apply_channel_modes(chan, &changes[..]);
+            Ok(vec![])
         }
+
+    /// :Alice JOIN #chan1,#chan2
+    fn handle_join(&mut self, nick: &str, channels: List) ->
+        Result<Vec<IrcMsg>, ProtocolError> {
+            let mut network = self.network.borrow_mut();
+            if channels.as_str() == "0" {
+                let chans: Vec<String> = network.user(nick).map_or(Vec::new(),
+                    |u| u.channels.iter().cloned().collect());
+                for chan in chans.iter() {
+                    network.part(&chan[..], nick);
+                }
+            } else {
+                for chan in channels.iter() {
+                    network.join(chan, nick, "", time::get_time().sec);
+                }
+            }
+            Ok(vec![])
+        }
+}
+
+/// Splits a channel mode string and its arguments into individual changes.
+fn parse_chan_modes(modes: &str, args: &[&str]) -> Vec<ModeChange> {
+    let mut changes = Vec::new();
+    let mut args = args.iter();
+    let mut adding = true;
+
+    for mode in modes.chars() {
+        match mode {
+            '+' => adding = true,
+            '-' => adding = false,
+            _ => {
+                let takes_arg = CHMODES_PARAM.contains(mode) || CHMODES_STATUS.contains(mode) ||
+                    (adding && CHMODES_PARAM_SET.contains(mode));
+                let arg = if takes_arg {
+                    match args.next() {
+                        Some(arg) => Some(arg.to_string()),
+                        None => continue
+                    }
+                } else {
+                    None
+                };
+                changes.push(ModeChange { adding: adding, mode: mode, arg: arg });
+            }
+        }
+    }
+
+    changes
+}
+
+/// NICKIP sends the base64 encoding of the user's address in network byte order,
+/// or `*` if it is unknown.
+fn decode_nickip(ip: &str) -> Option<IpAddr> {
+    match ip.from_base64() {
+        Ok(ref b) if b.len() == 4 => Some(IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3]))),
+        Ok(ref b) if b.len() == 16 => {
+            let seg = |i: usize| ((b[2*i] as u16) << 8) | b[2*i + 1] as u16;
+            Some(IpAddr::V6(Ipv6Addr::new(seg(0), seg(1), seg(2), seg(3),
+                                          seg(4), seg(5), seg(6), seg(7))))
+        }
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_chan_modes;
+    use super::decode_nickip;
+    use network::ModeChange;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn chan_modes() {
+        let changes = parse_chan_modes("+ontl-kb", &["Alice", "10", "key", "*!*@evil"]);
+        assert_eq!(changes, vec![
+            ModeChange { adding: true, mode: 'o', arg: Some("Alice".to_string()) },
+            ModeChange { adding: true, mode: 'n', arg: None },
+            ModeChange { adding: true, mode: 't', arg: None },
+            ModeChange { adding: true, mode: 'l', arg: Some("10".to_string()) },
+            ModeChange { adding: false, mode: 'k', arg: Some("key".to_string()) },
+            ModeChange { adding: false, mode: 'b', arg: Some("*!*@evil".to_string()) }]);
+    }
+
+    #[test]
+    fn nickip() {
+        assert_eq!(decode_nickip("fwAAAQ=="), Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))));
+        assert_eq!(decode_nickip("*"), None);
     }
 }
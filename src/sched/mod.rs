@@ -0,0 +1,259 @@
+use cmd::IrcMsg;
+
+use time;
+
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::rc::Rc;
+
+/// A source of monotonic time, in milliseconds.
+pub trait Clock {
+    fn now_ms(&self) -> u64;
+}
+
+/// The real clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        time::precise_time_ns() / 1000000
+    }
+}
+
+/// A clock that only moves when told to, for tests. Clones share the same time.
+#[derive(Clone)]
+pub struct FakeClock {
+    now: Rc<Cell<u64>>
+}
+
+impl FakeClock {
+    pub fn new(now: u64) -> FakeClock {
+        FakeClock { now: Rc::new(Cell::new(now)) }
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now.set(self.now.get() + ms);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now_ms(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+/// Returned when scheduling a timer; cancels it. Dropping the handle doesn't.
+#[derive(Clone)]
+pub struct TimerHandle {
+    cancelled: Rc<Cell<bool>>
+}
+
+impl TimerHandle {
+    pub fn cancel(&self) {
+        self.cancelled.set(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.get()
+    }
+}
+
+pub type Callback = Box<FnMut() -> Vec<IrcMsg>>;
+
+struct Timer {
+    deadline: u64,
+    /// Scheduling order, so that timers due at the same time run in that order
+    seq: u64,
+    /// Set for repeating timers
+    period: Option<u64>,
+    cancelled: Rc<Cell<bool>>,
+    callback: Callback
+}
+
+// BinaryHeap is a max-heap: the earliest deadline has to compare greatest.
+impl Ord for Timer {
+    fn cmp(&self, other: &Timer) -> Ordering {
+        (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Timer) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Timer) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for Timer {}
+
+/// One-shot and repeating timers, run from the main loop. Callbacks return
+/// the messages to send to the uplink.
+///
+/// The scheduler is shared as an `Rc<RefCell<Scheduler>>`; `run_due` doesn't
+/// keep it borrowed while callbacks run, so they can schedule more timers.
+pub struct Scheduler {
+    clock: Box<Clock>,
+    timers: BinaryHeap<Timer>,
+    next_seq: u64
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new(Box::new(SystemClock))
+    }
+}
+
+impl Scheduler {
+    pub fn new(clock: Box<Clock>) -> Scheduler {
+        Scheduler { clock: clock, timers: BinaryHeap::new(), next_seq: 0 }
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.clock.now_ms()
+    }
+
+    /// Runs `callback` once, `delay_ms` milliseconds from now.
+    pub fn after(&mut self, delay_ms: u64, callback: Callback) -> TimerHandle {
+        let deadline = self.now_ms() + delay_ms;
+        self.add(deadline, None, callback)
+    }
+
+    /// Runs `callback` every `period_ms` milliseconds, starting one period from now.
+    pub fn every(&mut self, period_ms: u64, callback: Callback) -> TimerHandle {
+        assert!(period_ms > 0);
+        let deadline = self.now_ms() + period_ms;
+        self.add(deadline, Some(period_ms), callback)
+    }
+
+    fn add(&mut self, deadline: u64, period: Option<u64>, callback: Callback) -> TimerHandle {
+        let cancelled = Rc::new(Cell::new(false));
+        self.next_seq += 1;
+        self.timers.push(Timer { deadline: deadline, seq: self.next_seq, period: period,
+                                 cancelled: cancelled.clone(), callback: callback });
+        TimerHandle { cancelled: cancelled }
+    }
+
+    /// Milliseconds until the next timer is due, if there is one.
+    pub fn next_timeout_ms(&mut self) -> Option<u64> {
+        self.drop_cancelled();
+        let now = self.now_ms();
+        self.timers.peek().map(|t| if t.deadline > now { t.deadline - now } else { 0 })
+    }
+
+    /// Forgets every timer, e.g. when the link they were about is gone.
+    pub fn clear(&mut self) {
+        self.timers.clear();
+    }
+
+    fn drop_cancelled(&mut self) {
+        while self.timers.peek().map_or(false, |t| t.cancelled.get()) {
+            self.timers.pop();
+        }
+    }
+
+    fn pop_due(&mut self) -> Option<Timer> {
+        self.drop_cancelled();
+        let now = self.now_ms();
+        if self.timers.peek().map_or(false, |t| t.deadline <= now) {
+            self.timers.pop()
+        } else {
+            None
+        }
+    }
+}
+
+/// Runs the timers that are due and collects the messages they produced. A
+/// repeating timer that fell behind runs once, then resumes its period from now.
+pub fn run_due(scheduler: &RefCell<Scheduler>) -> Vec<IrcMsg> {
+    let mut msgs = Vec::new();
+    let mut repeating = Vec::new();
+    loop {
+        let mut timer = match scheduler.borrow_mut().pop_due() {
+            Some(timer) => timer,
+            None => break
+        };
+        msgs.extend((timer.callback)().into_iter());
+        if let Some(period) = timer.period {
+            if !timer.cancelled.get() {
+                repeating.push((timer, period));
+            }
+        }
+    }
+
+    // Put back afterwards, so that a zero wait doesn't make us loop forever
+    let mut sched = scheduler.borrow_mut();
+    let now = sched.now_ms();
+    for (mut timer, period) in repeating.into_iter() {
+        timer.deadline = if timer.deadline + period > now {
+            timer.deadline + period
+        } else {
+            now + period
+        };
+        sched.timers.push(timer);
+    }
+    msgs
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Scheduler, FakeClock, run_due};
+    use cmd::IrcMsg;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn notice(text: &str) -> Vec<IrcMsg> {
+        vec![IrcMsg::new(None, "NOTICE", vec!["#Services".to_string(), text.to_string()])]
+    }
+
+    fn texts(msgs: Vec<IrcMsg>) -> Vec<String> {
+        msgs.into_iter().map(|m| m.params[1].clone()).collect()
+    }
+
+    #[test]
+    fn one_shot_and_repeating() {
+        let clock = FakeClock::new(1000);
+        let sched = RefCell::new(Scheduler::new(Box::new(clock.clone())));
+        sched.borrow_mut().after(500, Box::new(|| notice("once")));
+        let stats = sched.borrow_mut().every(200, Box::new(|| notice("stats")));
+        assert_eq!(sched.borrow_mut().next_timeout_ms(), Some(200));
+
+        assert!(run_due(&sched).is_empty());
+        clock.advance(200);
+        assert_eq!(texts(run_due(&sched)), vec!["stats"]);
+        clock.advance(300);
+        assert_eq!(texts(run_due(&sched)), vec!["stats", "once"]);
+
+        // Far behind: runs once, then every 200ms from now on
+        clock.advance(1000);
+        assert_eq!(texts(run_due(&sched)), vec!["stats"]);
+        assert_eq!(sched.borrow_mut().next_timeout_ms(), Some(200));
+
+        stats.cancel();
+        clock.advance(200);
+        assert!(run_due(&sched).is_empty());
+        assert_eq!(sched.borrow_mut().next_timeout_ms(), None);
+    }
+
+    #[test]
+    fn reschedule_from_callback() {
+        let clock = FakeClock::new(0);
+        let sched = Rc::new(RefCell::new(Scheduler::new(Box::new(clock.clone()))));
+        let inner = sched.clone();
+        sched.borrow_mut().after(10, Box::new(move || {
+            inner.borrow_mut().after(10, Box::new(|| notice("again")));
+            notice("first")
+        }));
+
+        clock.advance(10);
+        assert_eq!(texts(run_due(&sched)), vec!["first"]);
+        clock.advance(10);
+        assert_eq!(texts(run_due(&sched)), vec!["again"]);
+    }
+}